@@ -4,7 +4,7 @@
 # The install.sh and uninstall.sh scripts are provided as alternative methods for scenarios where manual installation or uninstallation might be necessary.
 
 # This script is used to clean up and remove all components related to the chmod-bpf tool on macOS.
-# It will remove the launch daemon, the script files, and the group created for BPF device management.
+# It will remove the launch daemon, the installed binary, and the group created for BPF device management.
 
 # Path to the LaunchDaemon plist file for the chmod-bpf service
 CHMOD_BPF_PLIST="/Library/LaunchDaemons/com.foctal.chmod-bpf.plist"
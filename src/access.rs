@@ -0,0 +1,713 @@
+use anyhow::{Result, anyhow, bail};
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use tracing::debug;
+use uzers::User;
+
+pub const READ: u32 = 0o4;
+pub const WRITE: u32 = 0o2;
+
+/// Identity whose access is evaluated: the resolved operator, not
+/// necessarily the process running the check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary group ids, including the primary group.
+    pub groups: Vec<u32>,
+}
+
+impl Credentials {
+    pub fn of_user(user: &User) -> Self {
+        let mut groups: Vec<u32> = user
+            .groups()
+            .unwrap_or_default()
+            .iter()
+            .map(|group| group.gid())
+            .collect();
+        if !groups.contains(&user.primary_group_id()) {
+            groups.push(user.primary_group_id());
+        }
+        Self {
+            uid: user.uid(),
+            gid: user.primary_group_id(),
+            groups,
+        }
+    }
+
+    fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+}
+
+/// Qualifier of a POSIX ACL entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclTag {
+    UserObj,
+    User(u32),
+    GroupObj,
+    Group(u32),
+    Mask,
+    Other,
+}
+
+/// One POSIX ACL entry with its `rwx` bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    pub perms: u32,
+}
+
+/// Decides whether `credentials` may access a file with the given owner,
+/// group, mode and optional ACL, following the POSIX.1e algorithm.
+///
+/// Root is granted read and write. As in the kernel, once the user matches
+/// the owning group or a named group entry, the group class decides and the
+/// "other" bits are not consulted.
+pub fn evaluate(
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    acl: Option<&[AclEntry]>,
+    credentials: &Credentials,
+    wanted: u32,
+) -> bool {
+    if credentials.uid == 0 {
+        return wanted & !(READ | WRITE) == 0 || mode & 0o111 != 0;
+    }
+    let grants = |perms: u32| perms & wanted == wanted;
+    if credentials.uid == uid {
+        return grants((mode >> 6) & 0o7);
+    }
+
+    let Some(acl) = acl.filter(|acl| acl.len() > 3) else {
+        if credentials.in_group(gid) {
+            return grants((mode >> 3) & 0o7);
+        }
+        return grants(mode & 0o7);
+    };
+
+    // With an extended ACL the group bits of the mode hold the mask.
+    let mask = acl
+        .iter()
+        .find(|entry| entry.tag == AclTag::Mask)
+        .map_or(0o7, |entry| entry.perms);
+    if let Some(entry) = acl
+        .iter()
+        .find(|entry| entry.tag == AclTag::User(credentials.uid))
+    {
+        return grants(entry.perms & mask);
+    }
+    let mut matched_group = false;
+    for entry in acl {
+        let member = match entry.tag {
+            AclTag::GroupObj => credentials.in_group(gid),
+            AclTag::Group(group) => credentials.in_group(group),
+            _ => false,
+        };
+        if member {
+            if grants(entry.perms & mask) {
+                return true;
+            }
+            matched_group = true;
+        }
+    }
+    if matched_group {
+        return false;
+    }
+    acl.iter()
+        .find(|entry| entry.tag == AclTag::Other)
+        .is_some_and(|entry| grants(entry.perms))
+}
+
+/// Decodes the `system.posix_acl_access` extended attribute used by Linux.
+pub fn parse_posix_acl_xattr(data: &[u8]) -> Result<Vec<AclEntry>> {
+    const VERSION: u32 = 2;
+    if data.len() < 4 || !(data.len() - 4).is_multiple_of(8) {
+        bail!("Malformed POSIX ACL of {} bytes", data.len());
+    }
+    let version = u32::from_le_bytes(data[0..4].try_into()?);
+    if version != VERSION {
+        bail!("Unsupported POSIX ACL version {version}");
+    }
+    data[4..]
+        .chunks_exact(8)
+        .map(|chunk| {
+            let tag = u16::from_le_bytes([chunk[0], chunk[1]]);
+            let perms = u32::from(u16::from_le_bytes([chunk[2], chunk[3]]) & 0o7);
+            let id = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+            let tag = match tag {
+                0x01 => AclTag::UserObj,
+                0x02 => AclTag::User(id),
+                0x04 => AclTag::GroupObj,
+                0x08 => AclTag::Group(id),
+                0x10 => AclTag::Mask,
+                0x20 => AclTag::Other,
+                other => bail!("Unknown POSIX ACL tag {other:#x}"),
+            };
+            Ok(AclEntry { tag, perms })
+        })
+        .collect()
+}
+
+/// Reads the access ACL of a path. Returns `None` when the path has no
+/// extended ACL or the platform does not expose POSIX ACLs.
+#[cfg(target_os = "linux")]
+pub fn read_acl(path: &Path) -> Result<Option<Vec<AclEntry>>> {
+    const NAME: &[u8] = b"system.posix_acl_access\0";
+    let c_path = c_path(path)?;
+    let mut buffer = vec![0u8; 4 + 8 * 32];
+    loop {
+        // SAFETY: both strings are NUL-terminated and the buffer length is passed.
+        let size = unsafe {
+            libc::getxattr(
+                c_path.as_ptr(),
+                NAME.as_ptr().cast(),
+                buffer.as_mut_ptr().cast(),
+                buffer.len(),
+            )
+        };
+        if size >= 0 {
+            buffer.truncate(size as usize);
+            return parse_posix_acl_xattr(&buffer).map(Some);
+        }
+        let error = io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::ENODATA) | Some(libc::ENOTSUP) => return Ok(None),
+            Some(libc::ERANGE) => buffer.resize(buffer.len() * 2, 0),
+            _ => {
+                return Err(anyhow!(
+                    "Failed to read the ACL of {}: {error}",
+                    path.display()
+                ));
+            }
+        }
+    }
+}
+
+/// macOS extended ACLs are not POSIX.1e ACLs; the kernel method accounts for
+/// them instead.
+#[cfg(not(target_os = "linux"))]
+pub fn read_acl(_path: &Path) -> Result<Option<Vec<AclEntry>>> {
+    Ok(None)
+}
+
+/// Checks whether `credentials` may access `path`.
+///
+/// When they are the credentials of this process the kernel is asked with
+/// faccessat(2). With `impersonate`, a root process temporarily switches to
+/// the given credentials to ask the kernel on their behalf. Otherwise, or if
+/// switching fails, the verdict is computed from the mode bits and ACL.
+pub fn check_access(
+    path: &Path,
+    credentials: &Credentials,
+    wanted: u32,
+    impersonate: bool,
+) -> Result<bool> {
+    let current = current_credentials();
+    if credentials.uid == current.uid {
+        return kernel_access(path, wanted);
+    }
+    if impersonate && current.uid == 0 {
+        match with_credentials(credentials, || kernel_access(path, wanted)) {
+            Ok(verdict) => return Ok(verdict),
+            Err(error) => debug!(?error, "Impersonation failed; evaluating mode bits and ACL"),
+        }
+    }
+    let metadata = std::fs::metadata(path)
+        .map_err(|error| anyhow!("Failed to read metadata for {}: {error}", path.display()))?;
+    let acl = read_acl(path)?;
+    Ok(evaluate(
+        metadata.uid(),
+        metadata.gid(),
+        metadata.mode(),
+        acl.as_deref(),
+        credentials,
+        wanted,
+    ))
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| anyhow!("{} contains a NUL byte", path.display()))
+}
+
+/// Effective credentials of this process.
+fn current_credentials() -> Credentials {
+    // SAFETY: these calls only read process credentials.
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    Credentials {
+        uid,
+        gid,
+        groups: supplementary_groups().unwrap_or_default(),
+    }
+}
+
+/// Supplementary groups of this process, as reported by getgroups(2).
+pub fn supplementary_groups() -> Result<Vec<u32>> {
+    // SAFETY: a zero-sized call only returns the group count.
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    if count < 0 {
+        return Err(anyhow!("getgroups failed: {}", io::Error::last_os_error()));
+    }
+    let mut groups = vec![0 as libc::gid_t; count as usize];
+    // SAFETY: the buffer holds `count` entries.
+    let count = unsafe { libc::getgroups(count, groups.as_mut_ptr()) };
+    if count < 0 {
+        return Err(anyhow!("getgroups failed: {}", io::Error::last_os_error()));
+    }
+    groups.truncate(count as usize);
+    Ok(groups)
+}
+
+/// Asks the kernel whether the effective ids of this process may access
+/// `path`, which accounts for ACLs, root and file-system flags.
+fn kernel_access(path: &Path, wanted: u32) -> Result<bool> {
+    let c_path = c_path(path)?;
+    let mut mode = 0;
+    if wanted & READ != 0 {
+        mode |= libc::R_OK;
+    }
+    if wanted & WRITE != 0 {
+        mode |= libc::W_OK;
+    }
+    // SAFETY: the path is NUL-terminated.
+    let result =
+        unsafe { libc::faccessat(libc::AT_FDCWD, c_path.as_ptr(), mode, libc::AT_EACCESS) };
+    if result == 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EACCES) | Some(libc::EPERM) | Some(libc::EROFS) => Ok(false),
+        _ => Err(anyhow!(
+            "Failed to check access to {}: {error}",
+            path.display()
+        )),
+    }
+}
+
+/// Runs `check` with the effective uid, gid and supplementary groups switched
+/// to `credentials`, restoring root's afterwards.
+fn with_credentials<T>(credentials: &Credentials, check: impl FnOnce() -> Result<T>) -> Result<T> {
+    let saved = current_credentials();
+    let groups: Vec<libc::gid_t> = credentials.groups.clone();
+    // SAFETY: the group list outlives the call; the ids come from the user database.
+    unsafe {
+        if libc::setgroups(groups.len() as _, groups.as_ptr()) != 0 {
+            bail!("setgroups failed: {}", io::Error::last_os_error());
+        }
+        if libc::setegid(credentials.gid) != 0 {
+            let error = io::Error::last_os_error();
+            libc::setgroups(saved.groups.len() as _, saved.groups.as_ptr());
+            bail!("setegid failed: {error}");
+        }
+        if libc::seteuid(credentials.uid) != 0 {
+            let error = io::Error::last_os_error();
+            libc::setegid(saved.gid);
+            libc::setgroups(saved.groups.len() as _, saved.groups.as_ptr());
+            bail!("seteuid failed: {error}");
+        }
+    }
+    let result = check();
+    // SAFETY: the saved credentials were those of this process.
+    let restored = unsafe {
+        libc::seteuid(saved.uid) == 0
+            && libc::setegid(saved.gid) == 0
+            && libc::setgroups(saved.groups.len() as _, saved.groups.as_ptr()) == 0
+    };
+    if !restored {
+        bail!(
+            "Failed to restore the process credentials: {}",
+            io::Error::last_os_error()
+        );
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    const RW: u32 = READ | WRITE;
+
+    fn user(uid: u32, groups: &[u32]) -> Credentials {
+        Credentials {
+            uid,
+            gid: 20,
+            groups: groups.to_vec(),
+        }
+    }
+
+    fn acl(entries: &[(AclTag, u32)]) -> Vec<AclEntry> {
+        entries
+            .iter()
+            .map(|&(tag, perms)| AclEntry { tag, perms })
+            .collect()
+    }
+
+    #[test]
+    fn evaluate_mode_bits() {
+        let owner = user(501, &[20]);
+        let member = user(502, &[20, 80]);
+        let stranger = user(503, &[20]);
+        let cases = [
+            // credentials, file mode, wanted, expected
+            (&owner, 0o600, RW, true),
+            (&owner, 0o400, RW, false),
+            // The owner class decides even when the group or other would grant.
+            (&owner, 0o066, RW, false),
+            (&member, 0o060, RW, true),
+            (&member, 0o040, READ, true),
+            (&member, 0o040, RW, false),
+            // A group member does not fall through to the other bits.
+            (&member, 0o606, RW, false),
+            (&stranger, 0o606, RW, true),
+            (&stranger, 0o660, RW, false),
+            (&stranger, 0o604, READ, true),
+        ];
+        for (credentials, mode, wanted, expected) in cases {
+            assert_eq!(
+                evaluate(501, 80, mode, None, credentials, wanted),
+                expected,
+                "uid {} on {mode:o} wanting {wanted:o}",
+                credentials.uid
+            );
+        }
+    }
+
+    #[test]
+    fn evaluate_grants_root_read_and_write() {
+        let root = user(0, &[0]);
+        assert!(evaluate(501, 80, 0o000, None, &root, RW));
+        assert!(!evaluate(501, 80, 0o666, None, &root, 0o1));
+        assert!(evaluate(501, 80, 0o744, None, &root, 0o1));
+    }
+
+    #[test]
+    fn evaluate_named_entries_are_limited_by_the_mask() {
+        let named = user(600, &[20]);
+        let entries = acl(&[
+            (AclTag::UserObj, 0o6),
+            (AclTag::User(600), 0o6),
+            (AclTag::GroupObj, 0o0),
+            (AclTag::Mask, 0o4),
+            (AclTag::Other, 0o0),
+        ]);
+        assert!(evaluate(501, 80, 0o640, Some(&entries), &named, READ));
+        assert!(!evaluate(501, 80, 0o640, Some(&entries), &named, RW));
+
+        let member = user(602, &[20, 90]);
+        let entries = acl(&[
+            (AclTag::UserObj, 0o6),
+            (AclTag::GroupObj, 0o0),
+            (AclTag::Group(90), 0o6),
+            (AclTag::Mask, 0o6),
+            (AclTag::Other, 0o0),
+        ]);
+        assert!(evaluate(501, 80, 0o660, Some(&entries), &member, RW));
+        let masked = acl(&[
+            (AclTag::UserObj, 0o6),
+            (AclTag::GroupObj, 0o0),
+            (AclTag::Group(90), 0o6),
+            (AclTag::Mask, 0o4),
+            (AclTag::Other, 0o6),
+        ]);
+        // The matched group entry denies and "other" is not consulted.
+        assert!(!evaluate(501, 80, 0o646, Some(&masked), &member, RW));
+        assert!(evaluate(
+            501,
+            80,
+            0o646,
+            Some(&masked),
+            &user(603, &[20]),
+            RW
+        ));
+    }
+
+    #[test]
+    fn evaluate_a_minimal_acl_uses_the_mode() {
+        let member = user(502, &[80]);
+        let entries = acl(&[
+            (AclTag::UserObj, 0o6),
+            (AclTag::GroupObj, 0o6),
+            (AclTag::Other, 0o0),
+        ]);
+        assert!(evaluate(501, 80, 0o660, Some(&entries), &member, RW));
+        assert!(!evaluate(501, 80, 0o600, Some(&entries), &member, RW));
+    }
+
+    fn xattr(version: u32, entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut data = version.to_le_bytes().to_vec();
+        for &(tag, perms, id) in entries {
+            data.extend(tag.to_le_bytes());
+            data.extend(perms.to_le_bytes());
+            data.extend(id.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn parse_posix_acl_xattr_decodes_every_tag() {
+        let undefined = u32::MAX;
+        let data = xattr(
+            2,
+            &[
+                (0x01, 0o6, undefined),
+                (0x02, 0o7, 501),
+                (0x04, 0o4, undefined),
+                (0x08, 0o6, 80),
+                (0x10, 0o6, undefined),
+                (0x20, 0o10, undefined),
+            ],
+        );
+        assert_eq!(
+            parse_posix_acl_xattr(&data).unwrap(),
+            acl(&[
+                (AclTag::UserObj, 0o6),
+                (AclTag::User(501), 0o7),
+                (AclTag::GroupObj, 0o4),
+                (AclTag::Group(80), 0o6),
+                (AclTag::Mask, 0o6),
+                // Bits above rwx are dropped.
+                (AclTag::Other, 0o0),
+            ])
+        );
+        assert_eq!(parse_posix_acl_xattr(&xattr(2, &[])).unwrap(), []);
+    }
+
+    #[test]
+    fn parse_posix_acl_xattr_rejects_malformed_data() {
+        let mut truncated = xattr(2, &[(0x01, 0o6, 0)]);
+        truncated.pop();
+        for data in [
+            Vec::new(),
+            vec![2, 0],
+            truncated,
+            xattr(1, &[(0x01, 0o6, 0)]),
+            xattr(2, &[(0x40, 0o6, 0)]),
+        ] {
+            assert!(
+                parse_posix_acl_xattr(&data).is_err(),
+                "{data:?} was accepted"
+            );
+        }
+    }
+
+    fn file_with_mode(dir: &TempDir, name: &str, mode: u32) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, "").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    /// Someone who is neither the owner nor in the group of files created by
+    /// this process.
+    fn stranger() -> Credentials {
+        Credentials {
+            uid: 4242,
+            gid: 4243,
+            groups: vec![4243],
+        }
+    }
+
+    /// A non-owner in the group of files created by this process.
+    fn group_member() -> Credentials {
+        let mut credentials = stranger();
+        credentials.groups.push(current_credentials().gid);
+        credentials
+    }
+
+    #[test]
+    fn owner_access_is_asked_of_the_kernel() {
+        let dir = TempDir::new();
+        let current = current_credentials();
+        let root = current.uid == 0;
+        let cases = [(0o600, true), (0o400, root), (0o066, root), (0o000, root)];
+        for (mode, expected) in cases {
+            let path = file_with_mode(&dir, &format!("{mode:o}"), mode);
+            assert_eq!(kernel_access(&path, RW).unwrap(), expected, "{mode:o}");
+            assert_eq!(
+                check_access(&path, &current, RW, false).unwrap(),
+                expected,
+                "{mode:o}"
+            );
+        }
+        assert!(kernel_access(&dir.join("missing"), READ).is_err());
+    }
+
+    #[test]
+    fn group_and_other_access_of_real_files() {
+        let dir = TempDir::new();
+        let cases = [
+            // credentials, file mode, expected
+            (group_member(), 0o060, true),
+            (group_member(), 0o040, false),
+            // A group member does not fall through to the other bits.
+            (group_member(), 0o606, false),
+            (stranger(), 0o606, true),
+            (stranger(), 0o660, false),
+            (stranger(), 0o604, false),
+        ];
+        for (index, (credentials, mode, expected)) in cases.into_iter().enumerate() {
+            let path = file_with_mode(&dir, &index.to_string(), mode);
+            assert_eq!(
+                check_access(&path, &credentials, RW, false).unwrap(),
+                expected,
+                "{credentials:?} on {mode:o}"
+            );
+        }
+        assert!(check_access(&dir.join("missing"), &stranger(), RW, false).is_err());
+    }
+
+    /// Writes `system.posix_acl_access` the way setfacl(1) does. Returns
+    /// `false` when the file system does not support POSIX ACLs.
+    #[cfg(target_os = "linux")]
+    fn set_acl(path: &Path, entries: &[(u16, u16, u32)]) -> bool {
+        let data = xattr(2, entries);
+        let c_path = c_path(path).unwrap();
+        // SAFETY: the name and path are NUL-terminated and the length is passed.
+        let result = unsafe {
+            libc::setxattr(
+                c_path.as_ptr(),
+                c"system.posix_acl_access".as_ptr(),
+                data.as_ptr().cast(),
+                data.len(),
+                0,
+            )
+        };
+        if result == 0 {
+            return true;
+        }
+        let error = io::Error::last_os_error();
+        assert_eq!(
+            error.raw_os_error(),
+            Some(libc::ENOTSUP),
+            "setxattr: {error}"
+        );
+        false
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn acl_entries_of_real_files() {
+        const UNDEFINED: u32 = u32::MAX;
+        let dir = TempDir::new();
+        let plain = file_with_mode(&dir, "plain", 0o600);
+        assert_eq!(read_acl(&plain).unwrap(), None);
+
+        let named_user = file_with_mode(&dir, "named-user", 0o600);
+        let entries = [
+            (0x01, 0o6, UNDEFINED),
+            (0x02, 0o6, 4242),
+            (0x04, 0o0, UNDEFINED),
+            (0x10, 0o6, UNDEFINED),
+            (0x20, 0o0, UNDEFINED),
+        ];
+        if !set_acl(&named_user, &entries) {
+            eprintln!(
+                "skipping: {} does not support POSIX ACLs",
+                dir.path().display()
+            );
+            return;
+        }
+        assert_eq!(
+            read_acl(&named_user).unwrap().unwrap(),
+            acl(&[
+                (AclTag::UserObj, 0o6),
+                (AclTag::User(4242), 0o6),
+                (AclTag::GroupObj, 0o0),
+                (AclTag::Mask, 0o6),
+                (AclTag::Other, 0o0),
+            ])
+        );
+        assert!(check_access(&named_user, &stranger(), RW, false).unwrap());
+
+        // The mask limits the named entry to read.
+        let masked = file_with_mode(&dir, "masked", 0o600);
+        let mut masked_entries = entries;
+        masked_entries[3].1 = 0o4;
+        assert!(set_acl(&masked, &masked_entries));
+        assert!(!check_access(&masked, &stranger(), RW, false).unwrap());
+        assert!(check_access(&masked, &stranger(), READ, false).unwrap());
+
+        // A named group entry grants its members, and only them.
+        let named_group = file_with_mode(&dir, "named-group", 0o600);
+        assert!(set_acl(
+            &named_group,
+            &[
+                (0x01, 0o6, UNDEFINED),
+                (0x04, 0o0, UNDEFINED),
+                (0x08, 0o6, 4243),
+                (0x10, 0o6, UNDEFINED),
+                (0x20, 0o0, UNDEFINED),
+            ],
+        ));
+        assert!(check_access(&named_group, &stranger(), RW, false).unwrap());
+        let outsider = Credentials {
+            uid: 4244,
+            gid: 4245,
+            groups: vec![4245],
+        };
+        assert!(!check_access(&named_group, &outsider, RW, false).unwrap());
+    }
+
+    /// Runs `check` in a forked child: switching credentials affects every
+    /// thread of the test runner.
+    fn in_child(check: impl FnOnce() -> bool) -> bool {
+        // SAFETY: the child only runs `check` and exits without returning.
+        unsafe {
+            match libc::fork() {
+                -1 => panic!("fork failed: {}", io::Error::last_os_error()),
+                0 => {
+                    let code = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(check)) {
+                        Ok(true) => 0,
+                        Ok(false) => 1,
+                        Err(_) => 2,
+                    };
+                    libc::_exit(code)
+                }
+                pid => {
+                    let mut status = 0;
+                    assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+                    assert!(libc::WIFEXITED(status), "child status {status}");
+                    match libc::WEXITSTATUS(status) {
+                        0 => true,
+                        1 => false,
+                        code => panic!("child failed with {code}"),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn impersonation_asks_the_kernel_as_the_user() {
+        if current_credentials().uid != 0 {
+            eprintln!("skipping: impersonation needs root");
+            return;
+        }
+        // The mode bits of the file grant access, but only root can reach it.
+        let dir = TempDir::new();
+        let private = dir.join("private");
+        std::fs::create_dir(&private).unwrap();
+        std::fs::set_permissions(&private, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let path = private.join("device");
+        std::fs::write(&path, "").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o606)).unwrap();
+
+        assert!(check_access(&path, &stranger(), RW, false).unwrap());
+        assert!(!in_child(|| {
+            let verdict = check_access(&path, &stranger(), RW, true).unwrap();
+            // The credentials of root are restored afterwards.
+            assert_eq!(current_credentials().uid, 0);
+            verdict
+        }));
+    }
+}
@@ -0,0 +1,810 @@
+use anyhow::{Result, anyhow};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    bpf, daemon, permission, user,
+    user::{GidRange, GroupMembers, GroupMembership},
+};
+
+/// Ownership and `st_mode` of a path, including the file type bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+}
+
+/// Privileged system operations used by the handlers.
+///
+/// Every group, daemon, file-permission and device action goes through this
+/// trait so the install, uninstall and check flows do not depend on macOS.
+pub trait SystemBackend {
+    /// Fails unless the process runs with elevated privileges.
+    fn require_root(&self) -> Result<()>;
+    /// Name of the operator on whose behalf the tool runs.
+    fn current_user_name(&self) -> Option<String>;
+
+    fn group_exists(&self, group_name: &str) -> bool;
+    fn group_gid(&self, group_name: &str) -> Option<u32>;
+    /// Name of the group that owns `gid`, if any.
+    fn group_name_by_gid(&self, gid: u32) -> Option<String>;
+    /// Name of the user that owns `uid`, if any.
+    fn user_name_by_uid(&self, uid: u32) -> Option<String>;
+    fn current_user_membership(&self, group_name: &str) -> GroupMembership;
+    /// Lowest gid in `range` that no group uses.
+    fn free_gid(&self, range: GidRange) -> Result<u32>;
+    fn create_group(&self, group_name: &str, group_real_name: &str, gid: u32) -> Result<()>;
+    fn delete_group(&self, group_name: &str) -> Result<()>;
+    fn add_user_to_group(&self, user_name: &str, group_name: &str) -> Result<()>;
+    fn add_group_to_group(&self, group: &str, target_group: &str) -> Result<()>;
+    fn user_is_member(&self, user_name: &str, group_name: &str) -> bool;
+    fn user_exists(&self, user_name: &str) -> bool;
+    /// Users and nested groups listed directly in the group record.
+    fn group_members(&self, group_name: &str) -> Result<GroupMembers>;
+    fn group_is_member(&self, group: &str, target_group: &str) -> Result<bool>;
+    fn remove_user_from_group(&self, user_name: &str, group_name: &str) -> Result<()>;
+    fn remove_group_from_group(&self, group: &str, target_group: &str) -> Result<()>;
+
+    fn bootout_daemon(&self, plist_path: &str) -> Result<()>;
+    fn bootstrap_daemon(&self, plist_path: &str) -> Result<()>;
+    /// Output of `launchctl print system/<label>`, or `None` when the job is
+    /// not loaded.
+    fn launchctl_print(&self, label: &str) -> Result<Option<String>>;
+
+    fn path_exists(&self, path: &str) -> bool;
+    /// Ownership and mode of a path, following symbolic links.
+    fn file_metadata(&self, path: &Path) -> Result<FileMetadata>;
+    /// Ownership and mode of a path without following a final symbolic link,
+    /// or `None` when nothing exists there.
+    fn symlink_metadata(&self, path: &Path) -> Result<Option<FileMetadata>>;
+    /// Names of the entries in a directory, sorted.
+    fn list_dir(&self, path: &Path) -> Result<Vec<String>>;
+    fn create_dir_all(&self, path: &str) -> Result<()>;
+    /// Reads a text file, returning `None` when it is not present.
+    fn read_file(&self, path: &str) -> Result<Option<Vec<u8>>>;
+    fn write_file(&self, path: &str, contents: &[u8]) -> Result<()>;
+    /// Removes a file, returning `false` when it was not present.
+    fn remove_file(&self, path: &str) -> Result<bool>;
+    /// Removes a directory tree, returning `false` when it was not present.
+    fn remove_dir_all(&self, path: &str) -> Result<bool>;
+    /// Removes an empty directory, returning `false` when it was not present.
+    fn remove_dir(&self, path: &str) -> Result<bool>;
+    /// Applies a symbolic mode such as `u=rw,g=r,o=r` to a path.
+    fn set_mode(&self, path: &str, mode: &str, recursive: bool) -> Result<()>;
+    /// Changes ownership of a path to an `owner:group` pair.
+    fn set_owner(&self, path: &str, owner: &str, recursive: bool) -> Result<()>;
+
+    /// Contents of the running chmod-bpf binary, installed for the daemon.
+    fn current_executable(&self) -> Result<Vec<u8>>;
+
+    fn bpf_device_paths(&self) -> Result<Vec<PathBuf>>;
+    /// Whether the resolved user can read and write `path`; see
+    /// `access::check_access` for `impersonate`.
+    fn has_read_write_access(&self, path: &Path, impersonate: bool) -> Result<bool>;
+    /// Reads a kernel parameter such as `debug.bpf_maxdevices`.
+    fn read_sysctl(&self, name: &str) -> Result<String>;
+    /// Opens `/dev/bpf<index>`, which makes the kernel create the node.
+    fn open_bpf_device(&self, index: u32) -> Result<()>;
+}
+
+/// Backend that talks to the real macOS system.
+pub struct MacOsBackend;
+
+impl SystemBackend for MacOsBackend {
+    fn require_root(&self) -> Result<()> {
+        user::require_root()
+    }
+
+    fn current_user_name(&self) -> Option<String> {
+        user::get_real_current_user().map(|user| user.name().to_string_lossy().into_owned())
+    }
+
+    fn group_exists(&self, group_name: &str) -> bool {
+        user::group_exists(group_name)
+    }
+
+    fn group_gid(&self, group_name: &str) -> Option<u32> {
+        user::get_group_gid(group_name)
+    }
+
+    fn group_name_by_gid(&self, gid: u32) -> Option<String> {
+        user::get_group_name(gid)
+    }
+
+    fn user_name_by_uid(&self, uid: u32) -> Option<String> {
+        user::get_user_name(uid)
+    }
+
+    fn current_user_membership(&self, group_name: &str) -> GroupMembership {
+        user::current_user_group_membership(group_name)
+    }
+
+    fn free_gid(&self, range: GidRange) -> Result<u32> {
+        user::get_free_gid(range)
+    }
+
+    fn create_group(&self, group_name: &str, group_real_name: &str, gid: u32) -> Result<()> {
+        user::create_group(group_name, group_real_name, gid)
+    }
+
+    fn delete_group(&self, group_name: &str) -> Result<()> {
+        user::delete_group(group_name)
+    }
+
+    fn add_user_to_group(&self, user_name: &str, group_name: &str) -> Result<()> {
+        user::add_user_to_group(user_name, group_name)
+    }
+
+    fn add_group_to_group(&self, group: &str, target_group: &str) -> Result<()> {
+        user::add_group_to_group(group, target_group)
+    }
+
+    fn user_is_member(&self, user_name: &str, group_name: &str) -> bool {
+        user::user_in_group(user_name, group_name)
+    }
+
+    fn user_exists(&self, user_name: &str) -> bool {
+        user::user_exists(user_name)
+    }
+
+    fn group_members(&self, group_name: &str) -> Result<GroupMembers> {
+        user::group_members(group_name)
+    }
+
+    fn group_is_member(&self, group: &str, target_group: &str) -> Result<bool> {
+        user::group_in_group(group, target_group)
+    }
+
+    fn remove_user_from_group(&self, user_name: &str, group_name: &str) -> Result<()> {
+        user::remove_user_from_group(user_name, group_name)
+    }
+
+    fn remove_group_from_group(&self, group: &str, target_group: &str) -> Result<()> {
+        user::remove_group_from_group(group, target_group)
+    }
+
+    fn bootout_daemon(&self, plist_path: &str) -> Result<()> {
+        daemon::bootout_daemon(plist_path)
+    }
+
+    fn bootstrap_daemon(&self, plist_path: &str) -> Result<()> {
+        daemon::bootstrap_daemon(plist_path)
+    }
+
+    fn launchctl_print(&self, label: &str) -> Result<Option<String>> {
+        daemon::launchctl_print(label)
+    }
+
+    fn path_exists(&self, path: &str) -> bool {
+        Path::new(path).exists()
+    }
+
+    fn file_metadata(&self, path: &Path) -> Result<FileMetadata> {
+        let metadata = std::fs::metadata(path)
+            .map_err(|error| anyhow!("Failed to read metadata for {}: {error}", path.display()))?;
+        Ok(FileMetadata {
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mode: metadata.mode(),
+        })
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> Result<Option<FileMetadata>> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) => Ok(Some(FileMetadata {
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                mode: metadata.mode(),
+            })),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(anyhow!(
+                "Failed to read metadata for {}: {error}",
+                path.display()
+            )),
+        }
+    }
+
+    fn list_dir(&self, path: &Path) -> Result<Vec<String>> {
+        let entries = std::fs::read_dir(path)
+            .map_err(|error| anyhow!("Failed to list {}: {error}", path.display()))?;
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry =
+                entry.map_err(|error| anyhow!("Failed to list {}: {error}", path.display()))?;
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn create_dir_all(&self, path: &str) -> Result<()> {
+        std::fs::create_dir_all(path)
+            .map_err(|error| anyhow!("Failed to create directory at {path}: {error}"))
+    }
+
+    fn read_file(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        match std::fs::read(path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(anyhow!("Failed to read {path}: {error}")),
+        }
+    }
+
+    fn write_file(&self, path: &str, contents: &[u8]) -> Result<()> {
+        std::fs::write(path, contents).map_err(|error| anyhow!("Failed to write {path}: {error}"))
+    }
+
+    fn remove_file(&self, path: &str) -> Result<bool> {
+        match std::fs::remove_file(path) {
+            Ok(_) => Ok(true),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(error) => Err(anyhow!("Failed to delete {path}: {error}")),
+        }
+    }
+
+    fn remove_dir_all(&self, path: &str) -> Result<bool> {
+        match std::fs::remove_dir_all(path) {
+            Ok(_) => Ok(true),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(error) => Err(anyhow!("Failed to delete directory at {path}: {error}")),
+        }
+    }
+
+    fn remove_dir(&self, path: &str) -> Result<bool> {
+        match std::fs::remove_dir(path) {
+            Ok(_) => Ok(true),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(error) => Err(anyhow!("Failed to delete directory at {path}: {error}")),
+        }
+    }
+
+    fn set_mode(&self, path: &str, mode: &str, recursive: bool) -> Result<()> {
+        permission::set_mode(path, mode, recursive)
+    }
+
+    fn set_owner(&self, path: &str, owner: &str, recursive: bool) -> Result<()> {
+        permission::set_owner(path, owner, recursive)
+    }
+
+    fn current_executable(&self) -> Result<Vec<u8>> {
+        let path = std::env::current_exe()
+            .map_err(|error| anyhow!("Failed to locate the chmod-bpf binary: {error}"))?;
+        std::fs::read(&path).map_err(|error| anyhow!("Failed to read {}: {error}", path.display()))
+    }
+
+    fn bpf_device_paths(&self) -> Result<Vec<PathBuf>> {
+        bpf::collect_bpf_device_paths()
+    }
+
+    fn has_read_write_access(&self, path: &Path, impersonate: bool) -> Result<bool> {
+        bpf::check_current_user_read_write_permissions(&path.to_string_lossy(), impersonate)
+    }
+
+    fn read_sysctl(&self, name: &str) -> Result<String> {
+        bpf::read_sysctl(name)
+    }
+
+    fn open_bpf_device(&self, index: u32) -> Result<()> {
+        bpf::open_bpf_device(index)
+    }
+}
+
+#[cfg(test)]
+pub use memory::{MemoryBackend, MemoryDevice, MemoryGroup, Step};
+
+/// In-memory fake of the system, for exercising the handler flows off-macOS.
+#[cfg(test)]
+mod memory {
+    use anyhow::{Result, anyhow, bail};
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::path::{Path, PathBuf};
+
+    use super::{FileMetadata, SystemBackend};
+    use crate::{
+        bpf, plist, user,
+        user::{GidRange, GroupMembers, GroupMembership},
+    };
+
+    /// Individual steps of the fake backend that can be made to fail.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum Step {
+        CreateGroup,
+        DeleteGroup,
+        AddUserToGroup,
+        AddGroupToGroup,
+        RemoveUserFromGroup,
+        RemoveGroupFromGroup,
+        BootoutDaemon,
+        BootstrapDaemon,
+        CreateDir,
+        WriteFile,
+        RemoveFile,
+        RemoveDir,
+        SetPermissions,
+        SetOwner,
+    }
+
+    impl Step {
+        pub const ALL: [Step; 14] = [
+            Step::CreateGroup,
+            Step::DeleteGroup,
+            Step::AddUserToGroup,
+            Step::AddGroupToGroup,
+            Step::RemoveUserFromGroup,
+            Step::RemoveGroupFromGroup,
+            Step::BootoutDaemon,
+            Step::BootstrapDaemon,
+            Step::CreateDir,
+            Step::WriteFile,
+            Step::RemoveFile,
+            Step::RemoveDir,
+            Step::SetPermissions,
+            Step::SetOwner,
+        ];
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct MemoryGroup {
+        pub gid: u32,
+        pub users: BTreeSet<String>,
+        pub groups: BTreeSet<String>,
+    }
+
+    /// A simulated device node.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MemoryDevice {
+        pub metadata: FileMetadata,
+        /// Whether the current user can read and write the device.
+        pub accessible: bool,
+    }
+
+    impl Default for MemoryDevice {
+        fn default() -> Self {
+            Self {
+                metadata: FileMetadata {
+                    uid: 0,
+                    gid: 0,
+                    mode: 0o020600,
+                },
+                accessible: false,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct MemoryState {
+        pub groups: BTreeMap<String, MemoryGroup>,
+        /// User accounts besides the operator.
+        pub users: BTreeSet<String>,
+        pub dirs: BTreeSet<String>,
+        pub files: BTreeMap<String, Vec<u8>>,
+        pub loaded_daemons: BTreeSet<String>,
+        pub devices: BTreeMap<PathBuf, MemoryDevice>,
+        /// Kernel parameters by name, e.g. `debug.bpf_maxdevices`.
+        pub sysctls: BTreeMap<String, String>,
+        /// Owner and mode of files and directories that are not root's
+        /// defaults, e.g. symbolic links or world-writable directories.
+        pub metadata: BTreeMap<PathBuf, FileMetadata>,
+    }
+
+    /// In-memory backend that simulates the system, for exercising the handler
+    /// flows off-macOS.
+    #[derive(Debug, Default)]
+    pub struct MemoryBackend {
+        pub is_root: bool,
+        pub user_name: Option<String>,
+        /// Step that fails when it is reached, for failure injection.
+        pub fail_at: Option<Step>,
+        /// Whether the login session predates group changes.
+        pub stale_session: bool,
+        pub executable: Vec<u8>,
+        pub state: RefCell<MemoryState>,
+    }
+
+    impl MemoryBackend {
+        /// A `sudo` session on behalf of `user_name`.
+        pub fn root(user_name: &str) -> Self {
+            Self {
+                is_root: true,
+                user_name: Some(user_name.to_string()),
+                executable: b"chmod-bpf binary".to_vec(),
+                ..Self::default()
+            }
+        }
+
+        fn step(&self, step: Step) -> Result<()> {
+            if self.fail_at == Some(step) {
+                bail!("Injected failure at {step:?}");
+            }
+            Ok(())
+        }
+
+        fn require_path(&self, path: &str) -> Result<()> {
+            if self.path_exists(path) {
+                Ok(())
+            } else {
+                Err(anyhow!("{path} does not exist"))
+            }
+        }
+    }
+
+    impl SystemBackend for MemoryBackend {
+        fn require_root(&self) -> Result<()> {
+            if self.is_root {
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "This command must be executed with elevated privileges (sudo)."
+                ))
+            }
+        }
+
+        fn current_user_name(&self) -> Option<String> {
+            self.user_name.clone()
+        }
+
+        fn group_exists(&self, group_name: &str) -> bool {
+            self.state.borrow().groups.contains_key(group_name)
+        }
+
+        fn group_gid(&self, group_name: &str) -> Option<u32> {
+            self.state
+                .borrow()
+                .groups
+                .get(group_name)
+                .map(|group| group.gid)
+        }
+
+        fn group_name_by_gid(&self, gid: u32) -> Option<String> {
+            self.state
+                .borrow()
+                .groups
+                .iter()
+                .find(|(_, group)| group.gid == gid)
+                .map(|(name, _)| name.clone())
+        }
+
+        fn user_name_by_uid(&self, uid: u32) -> Option<String> {
+            (uid == 0).then(|| "root".to_string())
+        }
+
+        fn current_user_membership(&self, group_name: &str) -> GroupMembership {
+            if !self.group_exists(group_name) {
+                return GroupMembership::GroupMissing;
+            }
+            let configured = self
+                .user_name
+                .as_deref()
+                .is_some_and(|user_name| self.user_is_member(user_name, group_name));
+            GroupMembership::from_states(configured, Some(configured && !self.stale_session))
+        }
+
+        fn free_gid(&self, range: GidRange) -> Result<u32> {
+            let used: Vec<u32> = self
+                .state
+                .borrow()
+                .groups
+                .values()
+                .map(|group| group.gid)
+                .collect();
+            user::allocate_gid(&used, range)
+                .ok_or_else(|| anyhow!("No free gid in the range {range}"))
+        }
+
+        fn create_group(&self, group_name: &str, _group_real_name: &str, gid: u32) -> Result<()> {
+            self.step(Step::CreateGroup)?;
+            let mut state = self.state.borrow_mut();
+            if state.groups.contains_key(group_name) {
+                bail!("Group {group_name} already exists");
+            }
+            state.groups.insert(
+                group_name.to_string(),
+                MemoryGroup {
+                    gid,
+                    ..Default::default()
+                },
+            );
+            Ok(())
+        }
+
+        fn delete_group(&self, group_name: &str) -> Result<()> {
+            self.step(Step::DeleteGroup)?;
+            match self.state.borrow_mut().groups.remove(group_name) {
+                Some(_) => Ok(()),
+                None => Err(anyhow!("Group {group_name} does not exist")),
+            }
+        }
+
+        fn add_user_to_group(&self, user_name: &str, group_name: &str) -> Result<()> {
+            self.step(Step::AddUserToGroup)?;
+            let mut state = self.state.borrow_mut();
+            let group = state
+                .groups
+                .get_mut(group_name)
+                .ok_or_else(|| anyhow!("Group {group_name} does not exist"))?;
+            group.users.insert(user_name.to_string());
+            Ok(())
+        }
+
+        fn add_group_to_group(&self, group: &str, target_group: &str) -> Result<()> {
+            self.step(Step::AddGroupToGroup)?;
+            let mut state = self.state.borrow_mut();
+            let target = state
+                .groups
+                .get_mut(target_group)
+                .ok_or_else(|| anyhow!("Group {target_group} does not exist"))?;
+            target.groups.insert(group.to_string());
+            Ok(())
+        }
+
+        fn user_is_member(&self, user_name: &str, group_name: &str) -> bool {
+            self.state
+                .borrow()
+                .groups
+                .get(group_name)
+                .is_some_and(|group| group.users.contains(user_name))
+        }
+
+        fn user_exists(&self, user_name: &str) -> bool {
+            self.user_name.as_deref() == Some(user_name)
+                || self.state.borrow().users.contains(user_name)
+        }
+
+        fn group_members(&self, group_name: &str) -> Result<GroupMembers> {
+            let state = self.state.borrow();
+            let group = state
+                .groups
+                .get(group_name)
+                .ok_or_else(|| anyhow!("Group {group_name} does not exist"))?;
+            Ok(GroupMembers {
+                users: group.users.iter().cloned().collect(),
+                groups: group.groups.iter().cloned().collect(),
+            })
+        }
+
+        fn group_is_member(&self, group: &str, target_group: &str) -> Result<bool> {
+            self.state
+                .borrow()
+                .groups
+                .get(target_group)
+                .map(|target| target.groups.contains(group))
+                .ok_or_else(|| anyhow!("Group {target_group} does not exist"))
+        }
+
+        fn remove_user_from_group(&self, user_name: &str, group_name: &str) -> Result<()> {
+            self.step(Step::RemoveUserFromGroup)?;
+            let mut state = self.state.borrow_mut();
+            let group = state
+                .groups
+                .get_mut(group_name)
+                .ok_or_else(|| anyhow!("Group {group_name} does not exist"))?;
+            group.users.remove(user_name);
+            Ok(())
+        }
+
+        fn remove_group_from_group(&self, group: &str, target_group: &str) -> Result<()> {
+            self.step(Step::RemoveGroupFromGroup)?;
+            let mut state = self.state.borrow_mut();
+            let target = state
+                .groups
+                .get_mut(target_group)
+                .ok_or_else(|| anyhow!("Group {target_group} does not exist"))?;
+            target.groups.remove(group);
+            Ok(())
+        }
+
+        fn bootout_daemon(&self, plist_path: &str) -> Result<()> {
+            self.step(Step::BootoutDaemon)?;
+            if self.state.borrow_mut().loaded_daemons.remove(plist_path) {
+                Ok(())
+            } else {
+                Err(anyhow!("{plist_path} is not loaded"))
+            }
+        }
+
+        fn bootstrap_daemon(&self, plist_path: &str) -> Result<()> {
+            self.step(Step::BootstrapDaemon)?;
+            self.require_path(plist_path)?;
+            self.state
+                .borrow_mut()
+                .loaded_daemons
+                .insert(plist_path.to_string());
+            Ok(())
+        }
+
+        fn launchctl_print(&self, label: &str) -> Result<Option<String>> {
+            let plist_path = format!("{}/{label}.plist", plist::LAUNCH_DAEMONS_DIR);
+            if !self.state.borrow().loaded_daemons.contains(&plist_path) {
+                return Ok(None);
+            }
+            Ok(Some(format!(
+                "system/{label} = {{\n\tpath = {plist_path}\n\tstate = not running\n\truns = 1\n\tlast exit code = 0\n}}\n"
+            )))
+        }
+
+        fn path_exists(&self, path: &str) -> bool {
+            let state = self.state.borrow();
+            state.files.contains_key(path)
+                || state.dirs.contains(path)
+                || state.devices.contains_key(Path::new(path))
+        }
+
+        fn file_metadata(&self, path: &Path) -> Result<FileMetadata> {
+            let state = self.state.borrow();
+            if let Some(device) = state.devices.get(path) {
+                return Ok(device.metadata);
+            }
+            if let Some(metadata) = state.metadata.get(path) {
+                return Ok(*metadata);
+            }
+            let path_str = path.to_string_lossy();
+            let mode = if state.dirs.contains(path_str.as_ref()) {
+                0o040755
+            } else if state.files.contains_key(path_str.as_ref()) {
+                0o100644
+            } else {
+                bail!("Failed to read metadata for {path_str}");
+            };
+            Ok(FileMetadata {
+                uid: 0,
+                gid: 0,
+                mode,
+            })
+        }
+
+        fn symlink_metadata(&self, path: &Path) -> Result<Option<FileMetadata>> {
+            let state = self.state.borrow();
+            if let Some(device) = state.devices.get(path) {
+                return Ok(Some(device.metadata));
+            }
+            if let Some(metadata) = state.metadata.get(path) {
+                return Ok(Some(*metadata));
+            }
+            let known = state
+                .dirs
+                .iter()
+                .chain(state.files.keys())
+                .map(|known| Path::new(known.as_str()));
+            let mut mode = None;
+            for known in known {
+                if known == path {
+                    mode = Some(
+                        if state.files.contains_key(known.to_string_lossy().as_ref()) {
+                            0o100644
+                        } else {
+                            0o040755
+                        },
+                    );
+                    break;
+                }
+                if known.starts_with(path) {
+                    mode = Some(0o040755);
+                }
+            }
+            Ok(mode.map(|mode| FileMetadata {
+                uid: 0,
+                gid: 0,
+                mode,
+            }))
+        }
+
+        fn list_dir(&self, path: &Path) -> Result<Vec<String>> {
+            let state = self.state.borrow();
+            let names: BTreeSet<String> = state
+                .dirs
+                .iter()
+                .chain(state.files.keys())
+                .filter_map(|known| {
+                    Path::new(known.as_str())
+                        .strip_prefix(path)
+                        .ok()?
+                        .components()
+                        .next()
+                        .map(|name| name.as_os_str().to_string_lossy().into_owned())
+                })
+                .collect();
+            Ok(names.into_iter().collect())
+        }
+
+        fn create_dir_all(&self, path: &str) -> Result<()> {
+            self.step(Step::CreateDir)?;
+            self.state.borrow_mut().dirs.insert(path.to_string());
+            Ok(())
+        }
+
+        fn read_file(&self, path: &str) -> Result<Option<Vec<u8>>> {
+            Ok(self.state.borrow().files.get(path).cloned())
+        }
+
+        fn write_file(&self, path: &str, contents: &[u8]) -> Result<()> {
+            self.step(Step::WriteFile)?;
+            self.state
+                .borrow_mut()
+                .files
+                .insert(path.to_string(), contents.to_vec());
+            Ok(())
+        }
+
+        fn remove_file(&self, path: &str) -> Result<bool> {
+            self.step(Step::RemoveFile)?;
+            Ok(self.state.borrow_mut().files.remove(path).is_some())
+        }
+
+        fn remove_dir_all(&self, path: &str) -> Result<bool> {
+            self.step(Step::RemoveDir)?;
+            let mut state = self.state.borrow_mut();
+            state.files.retain(|file, _| !file.starts_with(path));
+            Ok(state.dirs.remove(path))
+        }
+
+        fn remove_dir(&self, path: &str) -> Result<bool> {
+            self.step(Step::RemoveDir)?;
+            let mut state = self.state.borrow_mut();
+            let dir = Path::new(path);
+            let has_entries = state
+                .dirs
+                .iter()
+                .chain(state.files.keys())
+                .map(|known| Path::new(known.as_str()))
+                .any(|known| known != dir && known.starts_with(dir));
+            if has_entries {
+                bail!("Failed to delete directory at {path}: Directory not empty");
+            }
+            let before = state.dirs.len();
+            state.dirs.retain(|known| Path::new(known.as_str()) != dir);
+            Ok(state.dirs.len() < before)
+        }
+
+        fn set_mode(&self, path: &str, _mode: &str, _recursive: bool) -> Result<()> {
+            self.step(Step::SetPermissions)?;
+            self.require_path(path)
+        }
+
+        fn set_owner(&self, path: &str, _owner: &str, _recursive: bool) -> Result<()> {
+            self.step(Step::SetOwner)?;
+            self.require_path(path)
+        }
+
+        fn current_executable(&self) -> Result<Vec<u8>> {
+            Ok(self.executable.clone())
+        }
+
+        fn bpf_device_paths(&self) -> Result<Vec<PathBuf>> {
+            Ok(self.state.borrow().devices.keys().cloned().collect())
+        }
+
+        fn has_read_write_access(&self, path: &Path, _impersonate: bool) -> Result<bool> {
+            self.state
+                .borrow()
+                .devices
+                .get(path)
+                .map(|device| device.accessible)
+                .ok_or_else(|| anyhow!("Failed to read metadata for {}", path.display()))
+        }
+
+        fn read_sysctl(&self, name: &str) -> Result<String> {
+            self.state
+                .borrow()
+                .sysctls
+                .get(name)
+                .cloned()
+                .ok_or_else(|| anyhow!("Unknown sysctl {name}"))
+        }
+
+        fn open_bpf_device(&self, index: u32) -> Result<()> {
+            // Without the sysctl the kernel limit is unknown, not zero.
+            let max_devices = bpf::max_devices(self).ok();
+            let mut state = self.state.borrow_mut();
+            if max_devices.is_some_and(|max_devices| index >= max_devices) {
+                bail!("/dev/bpf{index} exceeds the device limit");
+            }
+            state
+                .devices
+                .entry(PathBuf::from(format!("/dev/bpf{index}")))
+                .or_default();
+            Ok(())
+        }
+    }
+}
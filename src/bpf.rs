@@ -1,12 +1,24 @@
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs;
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::access::{self, Credentials};
+use crate::backend::SystemBackend;
+use crate::permission;
 
 pub const BPF_GROUP: &str = "access_bpf";
 pub const BPF_GROUP_NAME: &str = "BPF Device ACL";
+/// Number of BPF devices to pre-create, clamped to `debug.bpf_maxdevices`.
+pub const FORCE_CREATE_BPF_MAX: u32 = 256;
+/// Mode change applied to every BPF device so group members can capture.
+pub const BPF_DEVICE_MODE: &str = "g+rw";
 
-fn collect_bpf_device_paths() -> Result<Vec<PathBuf>> {
+pub fn collect_bpf_device_paths() -> Result<Vec<PathBuf>> {
     let mut paths = Vec::new();
     for entry in fs::read_dir("/dev").context("Failed to scan /dev for BPF devices")? {
         let entry = entry?;
@@ -18,38 +30,34 @@ fn collect_bpf_device_paths() -> Result<Vec<PathBuf>> {
     Ok(paths)
 }
 
-/// Check if the current user has read/write permissions for the file.
-pub fn check_current_user_read_write_permissions(file_path: &str) -> Result<bool> {
-    let metadata = fs::metadata(Path::new(file_path))
-        .with_context(|| format!("Failed to read metadata for {file_path}"))?;
-    let permissions = metadata.permissions();
-
-    let mode = permissions.mode();
-    if let Some(user) = crate::user::get_real_current_user() {
-        if user.uid() == metadata.uid() {
-            return Ok(mode & 0o600 == 0o600);
-        }
-        if let Some(groups) = user.groups() {
-            for group in groups {
-                if group.gid() == metadata.gid() {
-                    return Ok(mode & 0o060 == 0o060);
-                }
-            }
-        }
-    }
-    Ok(mode & 0o006 == 0o006)
+/// Check if the resolved user (`SUDO_USER` when set) can read and write the
+/// file. With `impersonate`, a root process asks the kernel as that user, so
+/// every ACL is accounted for; otherwise mode bits and POSIX ACLs decide.
+pub fn check_current_user_read_write_permissions(
+    file_path: &str,
+    impersonate: bool,
+) -> Result<bool> {
+    let user = crate::user::get_real_current_user()
+        .ok_or_else(|| anyhow!("Unable to resolve the current user"))?;
+    access::check_access(
+        Path::new(file_path),
+        &Credentials::of_user(&user),
+        access::READ | access::WRITE,
+        impersonate,
+    )
 }
 
 /// Checks if the group has read/write permissions for the BPF devices.
-pub fn check_all_bpf_device_permissions() -> Result<()> {
-    let devices = collect_bpf_device_paths()?;
+pub fn check_all_bpf_device_permissions(backend: &dyn SystemBackend) -> Result<()> {
+    let devices = backend.bpf_device_paths()?;
     if devices.is_empty() {
         bail!("No BPF device nodes were found under /dev");
     }
 
     for entry in devices {
         let path_str = entry.to_string_lossy();
-        let has_permissions = check_current_user_read_write_permissions(&path_str)
+        let has_permissions = backend
+            .has_read_write_access(&entry, false)
             .with_context(|| format!("Failed to evaluate permissions for {path_str}"))?;
         if !has_permissions {
             bail!("Missing read/write permissions for {path_str}");
@@ -57,3 +65,361 @@ pub fn check_all_bpf_device_permissions() -> Result<()> {
     }
     Ok(())
 }
+
+/// File type of a `/dev/bpf*` node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKind {
+    CharacterDevice,
+    BlockDevice,
+    Other,
+}
+
+impl DeviceKind {
+    fn from_mode(mode: u32) -> Self {
+        match mode & 0o170000 {
+            0o020000 => DeviceKind::CharacterDevice,
+            0o060000 => DeviceKind::BlockDevice,
+            _ => DeviceKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for DeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DeviceKind::CharacterDevice => "char",
+            DeviceKind::BlockDevice => "block",
+            DeviceKind::Other => "other",
+        })
+    }
+}
+
+/// Why the resolved user can or cannot use a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceVerdict {
+    Accessible,
+    /// The device is not owned by the BPF group.
+    WrongGroup,
+    /// The BPF group lacks read/write permission on the device.
+    WrongMode,
+    /// Ownership and mode are right, but the user is not in the group.
+    NotInGroup,
+    /// The path is not a character device.
+    NotADevice,
+    /// The device could not be inspected.
+    Unreadable,
+}
+
+impl fmt::Display for DeviceVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DeviceVerdict::Accessible => "accessible",
+            DeviceVerdict::WrongGroup => "wrong group",
+            DeviceVerdict::WrongMode => "wrong mode",
+            DeviceVerdict::NotInGroup => "not in group",
+            DeviceVerdict::NotADevice => "not a device",
+            DeviceVerdict::Unreadable => "unreadable",
+        })
+    }
+}
+
+/// Ownership, mode and access verdict of one BPF device.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceStatus {
+    pub path: String,
+    pub kind: Option<DeviceKind>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    /// `ls -l` style mode, e.g. `crw-rw----`.
+    pub mode: Option<String>,
+    pub verdict: DeviceVerdict,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Counts of device verdicts, displayed as e.g. `254/256 accessible, 2 wrong group`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DeviceSummary {
+    pub total: usize,
+    pub accessible: usize,
+    /// Number of inaccessible devices per verdict.
+    pub problems: BTreeMap<DeviceVerdict, usize>,
+}
+
+impl DeviceSummary {
+    pub fn from_devices(devices: &[DeviceStatus]) -> Self {
+        let mut summary = DeviceSummary {
+            total: devices.len(),
+            ..Default::default()
+        };
+        for device in devices {
+            if device.verdict == DeviceVerdict::Accessible {
+                summary.accessible += 1;
+            } else {
+                *summary.problems.entry(device.verdict).or_default() += 1;
+            }
+        }
+        summary
+    }
+
+    pub fn all_accessible(&self) -> bool {
+        self.total > 0 && self.accessible == self.total
+    }
+}
+
+impl fmt::Display for DeviceSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{} accessible", self.accessible, self.total)?;
+        for (verdict, count) in &self.problems {
+            write!(f, ", {count} {verdict}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Inspects every BPF device and decides whether the resolved user can use
+/// it, and if not, why. `impersonate` is passed on to the access check.
+pub fn audit_bpf_devices(
+    backend: &dyn SystemBackend,
+    group: &str,
+    impersonate: bool,
+) -> Result<Vec<DeviceStatus>> {
+    let group_gid = backend.group_gid(group);
+    let mut devices = Vec::new();
+    for path in backend.bpf_device_paths()? {
+        let path_str = path.to_string_lossy().into_owned();
+        let metadata = match backend.file_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                devices.push(DeviceStatus {
+                    path: path_str,
+                    kind: None,
+                    owner: None,
+                    group: None,
+                    mode: None,
+                    verdict: DeviceVerdict::Unreadable,
+                    error: Some(format!("{error:#}")),
+                });
+                continue;
+            }
+        };
+        let kind = DeviceKind::from_mode(metadata.mode);
+        let (verdict, error) = match backend.has_read_write_access(&path, impersonate) {
+            Ok(true) => (DeviceVerdict::Accessible, None),
+            Ok(false) if kind != DeviceKind::CharacterDevice => (DeviceVerdict::NotADevice, None),
+            Ok(false) if group_gid != Some(metadata.gid) => (DeviceVerdict::WrongGroup, None),
+            Ok(false) if metadata.mode & 0o060 != 0o060 => (DeviceVerdict::WrongMode, None),
+            Ok(false) => (DeviceVerdict::NotInGroup, None),
+            Err(error) => (DeviceVerdict::Unreadable, Some(format!("{error:#}"))),
+        };
+        devices.push(DeviceStatus {
+            path: path_str,
+            kind: Some(kind),
+            owner: Some(
+                backend
+                    .user_name_by_uid(metadata.uid)
+                    .unwrap_or_else(|| metadata.uid.to_string()),
+            ),
+            group: Some(
+                backend
+                    .group_name_by_gid(metadata.gid)
+                    .unwrap_or_else(|| metadata.gid.to_string()),
+            ),
+            mode: Some(permission::mode_string(metadata.mode)),
+            verdict,
+            error,
+        });
+    }
+    devices.sort_by_key(|device| device_sort_key(&device.path));
+    Ok(devices)
+}
+
+/// Orders `/dev/bpf2` before `/dev/bpf10`.
+fn device_sort_key(path: &str) -> (u32, String) {
+    let index = path
+        .trim_start_matches("/dev/bpf")
+        .parse()
+        .unwrap_or(u32::MAX);
+    (index, path.to_string())
+}
+
+/// Kernel limit on the number of BPF devices.
+pub const BPF_MAX_DEVICES_SYSCTL: &str = "debug.bpf_maxdevices";
+
+/// Reads a sysctl value with `sysctl -n`.
+pub fn read_sysctl(name: &str) -> Result<String> {
+    let output = Command::new("sysctl")
+        .arg("-n")
+        .arg(name)
+        .output()
+        .with_context(|| format!("Failed to query sysctl for {name}"))?;
+    if !output.status.success() {
+        bail!(
+            "sysctl exited with status {} while reading {name}",
+            output.status
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Reads the kernel limit on BPF devices.
+pub fn max_devices(backend: &dyn SystemBackend) -> Result<u32> {
+    let value = backend.read_sysctl(BPF_MAX_DEVICES_SYSCTL)?;
+    value
+        .parse()
+        .with_context(|| format!("Unexpected {BPF_MAX_DEVICES_SYSCTL} value {value:?}"))
+}
+
+/// Existing BPF devices compared with how many `apply` aims to create.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceCount {
+    pub existing: usize,
+    /// Configured device count, clamped to the kernel limit when known.
+    pub target: u32,
+    /// `debug.bpf_maxdevices`, when it could be read.
+    pub kernel_max: Option<u32>,
+}
+
+impl DeviceCount {
+    pub fn new(existing: usize, configured: u32, kernel_max: Option<u32>) -> Self {
+        Self {
+            existing,
+            target: kernel_max.map_or(configured, |max| configured.min(max)),
+            kernel_max,
+        }
+    }
+
+    /// Whether the pre-creation target has been reached.
+    pub fn target_reached(&self) -> bool {
+        self.existing >= self.target as usize
+    }
+}
+
+/// Opens the BPF device for reading, which forces the kernel to create it.
+pub fn open_bpf_device(index: u32) -> Result<()> {
+    let path = format!("/dev/bpf{index}");
+    fs::File::open(&path)
+        .map(|_| ())
+        .with_context(|| format!("Failed to open {path}"))
+}
+
+/// Pre-creates BPF devices and grants the group read/write access to them.
+/// This is what the launch daemon runs at boot.
+pub fn apply_device_permissions(
+    backend: &dyn SystemBackend,
+    group: &str,
+    count: u32,
+) -> Result<()> {
+    let count = match max_devices(backend) {
+        Ok(max_devices) => count.min(max_devices),
+        Err(error) => {
+            warn!(
+                ?error,
+                "Could not read {BPF_MAX_DEVICES_SYSCTL}; creating the configured {count} device(s)"
+            );
+            count
+        }
+    };
+    if count > 0 {
+        info!(
+            "Forcing creation and setting permissions for /dev/bpf0-{}",
+            count - 1
+        );
+    }
+    for index in 0..count {
+        // Busy devices fail to open but still exist.
+        if let Err(error) = backend.open_bpf_device(index) {
+            debug!(?error, "Could not open BPF device {index}");
+        }
+    }
+
+    let owner = format!(":{group}");
+    let mut failures = Vec::new();
+    for device in backend.bpf_device_paths()? {
+        let path = device.to_string_lossy();
+        if let Err(error) = backend
+            .set_owner(&path, &owner, false)
+            .and_then(|_| backend.set_mode(&path, BPF_DEVICE_MODE, false))
+        {
+            failures.push(format!("{error:#}"));
+        }
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Failed to update {} BPF device(s):\n  {}",
+            failures.len(),
+            failures.join("\n  ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{MemoryBackend, MemoryGroup};
+
+    fn backend_with_group() -> MemoryBackend {
+        let backend = MemoryBackend::root("alice");
+        backend.state.borrow_mut().groups.insert(
+            BPF_GROUP.to_string(),
+            MemoryGroup {
+                gid: 600,
+                ..Default::default()
+            },
+        );
+        backend
+    }
+
+    fn device_paths(backend: &MemoryBackend) -> Vec<PathBuf> {
+        backend.bpf_device_paths().unwrap()
+    }
+
+    fn bpf_paths(count: u32) -> Vec<PathBuf> {
+        (0..count)
+            .map(|index| PathBuf::from(format!("/dev/bpf{index}")))
+            .collect()
+    }
+
+    #[test]
+    fn apply_clamps_the_device_count_to_the_kernel_limit() {
+        let backend = backend_with_group();
+        backend
+            .state
+            .borrow_mut()
+            .sysctls
+            .insert(BPF_MAX_DEVICES_SYSCTL.to_string(), "2".to_string());
+        apply_device_permissions(&backend, BPF_GROUP, 4).unwrap();
+        assert_eq!(device_paths(&backend), bpf_paths(2));
+    }
+
+    #[test]
+    fn apply_falls_back_to_the_configured_count_without_the_sysctl() {
+        let backend = backend_with_group();
+        apply_device_permissions(&backend, BPF_GROUP, 3).unwrap();
+        assert_eq!(device_paths(&backend), bpf_paths(3));
+    }
+
+    #[test]
+    fn device_count_target_is_clamped_to_the_kernel_limit() {
+        let cases = [
+            // existing, configured, kernel max, target, reached
+            (4, 256, Some(256), 256, false),
+            (4, 256, Some(8), 8, false),
+            (8, 256, Some(8), 8, true),
+            (12, 8, Some(256), 8, true),
+            (0, 8, None, 8, false),
+            (8, 8, None, 8, true),
+            (0, 8, Some(0), 0, true),
+        ];
+        for (existing, configured, kernel_max, target, reached) in cases {
+            let count = DeviceCount::new(existing, configured, kernel_max);
+            assert_eq!(count.target, target, "{count:?}");
+            assert_eq!(count.kernel_max, kernel_max);
+            assert_eq!(count.target_reached(), reached, "{count:?}");
+        }
+    }
+}
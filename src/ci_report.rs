@@ -0,0 +1,484 @@
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::{
+    drift::DriftState,
+    output::escape_xml,
+    report::{CheckReport, Section, Status},
+    security::Severity,
+};
+
+/// File format of a CI report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportKind {
+    Junit,
+    Sarif,
+}
+
+/// Where `check --report KIND=PATH` writes a report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportTarget {
+    pub kind: ReportKind,
+    pub path: PathBuf,
+}
+
+impl FromStr for ReportTarget {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (kind, path) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected KIND=PATH, got {value:?}"))?;
+        let kind = match kind {
+            "junit" => ReportKind::Junit,
+            "sarif" => ReportKind::Sarif,
+            other => {
+                return Err(format!(
+                    "unknown report kind {other:?} (use junit or sarif)"
+                ));
+            }
+        };
+        if path.is_empty() {
+            return Err("the report path is empty".to_string());
+        }
+        Ok(Self {
+            kind,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+impl fmt::Display for ReportKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ReportKind::Junit => "JUnit",
+            ReportKind::Sarif => "SARIF",
+        })
+    }
+}
+
+impl ReportTarget {
+    /// Renders the report and writes it to the target path.
+    pub fn write(&self, report: &CheckReport, strict: bool) -> Result<()> {
+        let contents = match self.kind {
+            ReportKind::Junit => render_junit(report, strict),
+            ReportKind::Sarif => render_sarif(report)?,
+        };
+        std::fs::write(&self.path, contents).with_context(|| {
+            format!(
+                "Failed to write the {} report to {}",
+                self.kind,
+                self.path.display()
+            )
+        })
+    }
+}
+
+/// Stable identifier of a section, e.g. `device-count`.
+fn rule_id(title: &str) -> String {
+    title.to_lowercase().replace(' ', "-")
+}
+
+/// Messages followed by the remediation hints, one per line.
+fn section_details(section: &Section) -> String {
+    section
+        .messages
+        .iter()
+        .cloned()
+        .chain(section.hints.iter().map(|hint| format!("Hint: {hint}")))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// One JUnit test case per section. Errors fail their test case, and so do
+/// warnings with `strict`, matching the exit status of `check`.
+pub fn render_junit(report: &CheckReport, strict: bool) -> String {
+    let threshold = if strict {
+        Status::Warning
+    } else {
+        Status::Error
+    };
+    let sections = report.sections();
+    let failures = sections
+        .iter()
+        .filter(|(_, section)| section.status >= threshold)
+        .count();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuites name=\"chmod-bpf\" tests=\"{tests}\" failures=\"{failures}\">\n  <testsuite name=\"chmod-bpf check\" tests=\"{tests}\" failures=\"{failures}\" errors=\"0\" skipped=\"0\" timestamp=\"{timestamp}\">\n",
+        tests = sections.len(),
+        timestamp = escape_xml(&report.checked_at),
+    ));
+    for (title, section) in sections {
+        xml.push_str(&format!(
+            "    <testcase classname=\"chmod-bpf.check\" name=\"{}\">\n",
+            escape_xml(title)
+        ));
+        let details = escape_xml(&section_details(section));
+        if section.status >= threshold {
+            let kind = match section.status {
+                Status::Error => "error",
+                _ => "warning",
+            };
+            xml.push_str(&format!(
+                "      <failure type=\"{kind}\" message=\"{}\">{details}</failure>\n",
+                escape_xml(section.messages.first().map_or("", String::as_str))
+            ));
+        } else {
+            xml.push_str(&format!("      <system-out>{details}</system-out>\n"));
+        }
+        xml.push_str("    </testcase>\n");
+    }
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+    xml
+}
+
+/// `file://` URI of an absolute path, percent-encoding everything but
+/// unreserved characters and `/`.
+fn file_uri(path: &str) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(byte as char)
+            }
+            byte => uri.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    uri
+}
+
+fn sarif_locations<'a>(paths: impl IntoIterator<Item = &'a str>) -> Vec<serde_json::Value> {
+    paths
+        .into_iter()
+        .map(|path| {
+            json!({
+                "physicalLocation": { "artifactLocation": { "uri": file_uri(path) } },
+            })
+        })
+        .collect()
+}
+
+fn sarif_level(status: Status) -> &'static str {
+    match status {
+        Status::Error => "error",
+        _ => "warning",
+    }
+}
+
+/// A SARIF 2.1.0 log with one rule per section and one result per section
+/// that is not ok. Security findings are reported one result each, at the
+/// path they concern; the drift result points at the assets that drifted.
+pub fn render_sarif(report: &CheckReport) -> Result<String> {
+    let sections = report.sections();
+    let rules: Vec<_> = sections
+        .iter()
+        .map(|(title, _)| {
+            json!({
+                "id": rule_id(title),
+                "name": title,
+                "shortDescription": { "text": format!("{title} check") },
+            })
+        })
+        .collect();
+    let security_findings = report.security_findings.as_deref().unwrap_or_default();
+    let mut results = Vec::new();
+    for (title, section) in &sections {
+        if section.status == Status::Ok {
+            continue;
+        }
+        match *title {
+            "Security" if !security_findings.is_empty() => {
+                for finding in security_findings {
+                    results.push(json!({
+                        "ruleId": rule_id(title),
+                        "level": match finding.severity {
+                            Severity::Critical | Severity::High => "error",
+                            Severity::Medium => "warning",
+                            Severity::Low => "note",
+                        },
+                        "message": { "text": format!("[{}] {}", finding.severity, finding.issue) },
+                        "locations": sarif_locations([finding.path.as_str()]),
+                    }));
+                }
+            }
+            "Drift" => {
+                let drifted = report
+                    .drift_files
+                    .iter()
+                    .filter(|file| file.state != DriftState::Matches)
+                    .map(|file| file.path.as_str());
+                results.push(json!({
+                    "ruleId": rule_id(title),
+                    "level": sarif_level(section.status),
+                    "message": { "text": section_details(section) },
+                    "locations": sarif_locations(drifted),
+                }));
+            }
+            _ => results.push(json!({
+                "ruleId": rule_id(title),
+                "level": sarif_level(section.status),
+                "message": { "text": section_details(section) },
+            })),
+        }
+    }
+
+    let log = json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "chmod-bpf",
+                    "version": report.version,
+                    "informationUri": env!("CARGO_PKG_REPOSITORY"),
+                    "rules": rules,
+                }
+            },
+            "invocations": [{
+                "executionSuccessful": true,
+                "endTimeUtc": chrono::DateTime::parse_from_rfc3339(&report.checked_at)
+                    .map(|time| time.to_utc().to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+                    .unwrap_or_default(),
+            }],
+            "results": results,
+        }],
+    });
+    serde_json::to_string_pretty(&log).context("Failed to serialize the SARIF report")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use crate::config::Config;
+    use crate::drift::FileDrift;
+    use crate::report::CheckOptions;
+    use crate::security::SecurityFinding;
+
+    const SUPPORT_DIR: &str = "/Library/Application Support/Foctal/chmod-bpf";
+
+    /// A failing report whose messages hold every character XML reserves.
+    fn report() -> CheckReport {
+        let backend = MemoryBackend::root("alice");
+        let mut report =
+            CheckReport::collect(&backend, &Config::default(), CheckOptions::default());
+        report
+            .permissions
+            .messages
+            .push("<bpf0> & \"bpf1\" aren't \u{1b}[31mreadable".to_string());
+        report.drift = Section {
+            status: Status::Warning,
+            messages: vec!["1 file <modified>".to_string()],
+            hints: vec!["Run `sudo chmod-bpf install` & retry".to_string()],
+        };
+        report.drift_files = vec![
+            FileDrift {
+                path: format!("{SUPPORT_DIR}/chmod-bpf"),
+                state: DriftState::Modified,
+                diff: None,
+            },
+            FileDrift {
+                path: "/Library/LaunchDaemons/com.foctal.chmod-bpf.plist".to_string(),
+                state: DriftState::Matches,
+                diff: None,
+            },
+        ];
+        report.security = Some(Section {
+            status: Status::Error,
+            messages: vec!["2 finding(s)".to_string()],
+            hints: Vec::new(),
+        });
+        report.security_findings = Some(vec![
+            SecurityFinding {
+                severity: Severity::Critical,
+                path: SUPPORT_DIR.to_string(),
+                issue: "is world-writable".to_string(),
+            },
+            SecurityFinding {
+                severity: Severity::Low,
+                path: format!("{SUPPORT_DIR}/notes & <todo>.txt"),
+                issue: "was not installed by chmod-bpf".to_string(),
+            },
+        ]);
+        report
+    }
+
+    /// Checks that tags nest and close, attributes are quoted, and `&` and
+    /// `<` only appear as markup or entities. Returns the element names in
+    /// document order.
+    fn check_well_formed(xml: &str) -> Vec<String> {
+        let body = xml
+            .strip_prefix("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n")
+            .expect("missing XML declaration");
+        let check_text = |text: &str| {
+            assert!(!text.contains('<'), "unescaped < in {text:?}");
+            for (index, _) in text.match_indices('&') {
+                assert!(
+                    ["&amp;", "&lt;", "&gt;", "&quot;", "&apos;"]
+                        .iter()
+                        .any(|entity| text[index..].starts_with(entity)),
+                    "unescaped & in {text:?}"
+                );
+            }
+            assert!(
+                text.chars()
+                    .all(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r')),
+                "control character in {text:?}"
+            );
+        };
+
+        let mut open: Vec<String> = Vec::new();
+        let mut elements = Vec::new();
+        let mut rest = body;
+        while let Some(start) = rest.find('<') {
+            check_text(&rest[..start]);
+            let end = start + rest[start..].find('>').expect("unterminated tag");
+            let tag = &rest[start + 1..end];
+            rest = &rest[end + 1..];
+            if let Some(name) = tag.strip_prefix('/') {
+                assert_eq!(open.pop().as_deref(), Some(name), "mismatched </{name}>");
+                continue;
+            }
+            assert!(
+                !open.is_empty() || elements.is_empty(),
+                "more than one root element"
+            );
+            let (tag, self_closing) = match tag.strip_suffix('/') {
+                Some(tag) => (tag, true),
+                None => (tag, false),
+            };
+            let (name, mut attributes) = tag.split_once(' ').unwrap_or((tag, ""));
+            while !attributes.trim().is_empty() {
+                let (_, value) = attributes
+                    .split_once("=\"")
+                    .unwrap_or_else(|| panic!("unquoted attribute in <{tag}>"));
+                let (value, remaining) = value
+                    .split_once('"')
+                    .unwrap_or_else(|| panic!("unterminated attribute in <{tag}>"));
+                check_text(value);
+                attributes = remaining;
+            }
+            elements.push(name.to_string());
+            if !self_closing {
+                open.push(name.to_string());
+            }
+        }
+        assert!(rest.trim().is_empty(), "text after the root element");
+        assert!(open.is_empty(), "unclosed elements {open:?}");
+        elements
+    }
+
+    #[test]
+    fn junit_is_well_formed() {
+        let report = report();
+        for strict in [false, true] {
+            let xml = render_junit(&report, strict);
+            let elements = check_well_formed(&xml);
+            assert_eq!(elements[..2], ["testsuites", "testsuite"]);
+            let count = |name: &str| elements.iter().filter(|element| *element == name).count();
+            assert_eq!(count("testcase"), report.sections().len());
+            assert_eq!(
+                count("failure") + count("system-out"),
+                report.sections().len()
+            );
+            assert!(xml.contains("&lt;bpf0&gt; &amp; &quot;bpf1&quot; aren&apos;t \u{fffd}[31m"));
+        }
+        let failures = |strict| {
+            check_well_formed(&render_junit(&report, strict))
+                .iter()
+                .filter(|element| *element == "failure")
+                .count()
+        };
+        assert!(failures(true) > failures(false));
+    }
+
+    #[test]
+    fn sarif_is_valid_json_with_locations() {
+        let sarif: serde_json::Value =
+            serde_json::from_str(&render_sarif(&report()).unwrap()).unwrap();
+        assert_eq!(sarif["version"], "2.1.0");
+        let run = &sarif["runs"][0];
+        let rules: Vec<&str> = run["tool"]["driver"]["rules"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|rule| rule["id"].as_str().unwrap())
+            .collect();
+        let results = run["results"].as_array().unwrap();
+        for result in results {
+            assert!(
+                rules.contains(&result["ruleId"].as_str().unwrap()),
+                "{result}"
+            );
+            assert!(["error", "warning", "note"].contains(&result["level"].as_str().unwrap()));
+            assert!(result["message"]["text"].is_string());
+        }
+
+        let uris = |rule: &str| -> Vec<(&str, Vec<&str>)> {
+            results
+                .iter()
+                .filter(|result| result["ruleId"] == rule)
+                .map(|result| {
+                    (
+                        result["level"].as_str().unwrap(),
+                        result["locations"]
+                            .as_array()
+                            .map(|locations| {
+                                locations
+                                    .iter()
+                                    .map(|location| {
+                                        location["physicalLocation"]["artifactLocation"]["uri"]
+                                            .as_str()
+                                            .unwrap()
+                                    })
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(
+            uris("security"),
+            [
+                (
+                    "error",
+                    vec!["file:///Library/Application%20Support/Foctal/chmod-bpf"]
+                ),
+                (
+                    "note",
+                    vec![
+                        "file:///Library/Application%20Support/Foctal/chmod-bpf/notes%20%26%20%3Ctodo%3E.txt"
+                    ]
+                ),
+            ]
+        );
+        assert_eq!(
+            uris("drift"),
+            [(
+                "warning",
+                vec!["file:///Library/Application%20Support/Foctal/chmod-bpf/chmod-bpf"]
+            )]
+        );
+        assert_eq!(uris("permissions").len(), 1);
+        assert!(uris("permissions")[0].1.is_empty());
+    }
+
+    #[test]
+    fn report_target_parses_kind_and_path() {
+        assert_eq!(
+            "sarif=out/check.sarif".parse::<ReportTarget>(),
+            Ok(ReportTarget {
+                kind: ReportKind::Sarif,
+                path: PathBuf::from("out/check.sarif"),
+            })
+        );
+        for value in ["junit", "xml=report.xml", "junit="] {
+            assert!(value.parse::<ReportTarget>().is_err(), "{value}");
+        }
+    }
+}
@@ -0,0 +1,346 @@
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    bpf, resource,
+    user::{self, GidRange},
+};
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/chmod-bpf.toml";
+
+/// System-wide settings read from `/etc/chmod-bpf.toml` (or `--config`).
+/// Every key is optional and falls back to the built-in default.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub group: GroupConfig,
+    pub devices: DeviceConfig,
+    pub paths: PathConfig,
+    /// File the settings were loaded from, if any.
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupConfig {
+    /// Short name of the group that owns the BPF devices.
+    pub name: String,
+    /// Human-readable group name shown by Directory Services.
+    pub display_name: String,
+    /// Pinned gid for the group; a free gid is picked when unset.
+    pub gid: Option<u32>,
+    /// Lowest gid considered when picking a free gid.
+    pub min_gid: u32,
+    /// Highest gid considered when picking a free gid; unbounded when unset.
+    pub max_gid: Option<u32>,
+    /// Additional users added to the group on install.
+    pub members: Vec<String>,
+}
+
+impl Default for GroupConfig {
+    fn default() -> Self {
+        Self {
+            name: bpf::BPF_GROUP.to_string(),
+            display_name: bpf::BPF_GROUP_NAME.to_string(),
+            gid: None,
+            min_gid: user::MIN_GID,
+            max_gid: None,
+            members: Vec::new(),
+        }
+    }
+}
+
+impl GroupConfig {
+    /// Gids a new group may be created with.
+    pub fn gid_range(&self) -> GidRange {
+        GidRange {
+            start: self.min_gid,
+            end: self.max_gid.unwrap_or(u32::MAX),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// Number of BPF devices to pre-create, clamped to the kernel maximum.
+    pub count: u32,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            count: bpf::FORCE_CREATE_BPF_MAX,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathConfig {
+    /// Directory holding the installed binary and the install manifest.
+    pub support_dir: String,
+}
+
+impl Default for PathConfig {
+    fn default() -> Self {
+        Self {
+            support_dir: resource::CHMOD_BPF_SUPPORT_DIR_PATH.to_string(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration. An explicit path must exist; the default path
+    /// is optional and the built-in defaults apply when it is missing.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path, true),
+            None => (Path::new(DEFAULT_CONFIG_PATH), false),
+        };
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) if !required && error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("Failed to read config file {}", path.display()));
+            }
+        };
+        let mut config: Config = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        config.source = Some(path.to_path_buf());
+        Ok(config)
+    }
+
+    /// Applies `--group` and `--group-display-name` on top of the loaded
+    /// settings.
+    pub fn override_group(&mut self, name: Option<&str>, display_name: Option<&str>) -> Result<()> {
+        if let Some(name) = name {
+            validate_name("--group", name)?;
+            self.group.name = name.to_string();
+        }
+        if let Some(display_name) = display_name {
+            if display_name.trim().is_empty() {
+                bail!("--group-display-name must not be empty");
+            }
+            self.group.display_name = display_name.to_string();
+        }
+        Ok(())
+    }
+
+    /// Applies `--gid` and `--gid-range` on top of the loaded settings. A
+    /// pinned gid replaces any gid range from the config file and vice versa.
+    pub fn override_gid(&mut self, gid: Option<u32>, range: Option<GidRange>) -> Result<()> {
+        if let Some(gid) = gid {
+            if gid == 0 {
+                bail!("--gid must not be 0");
+            }
+            self.group.gid = Some(gid);
+        }
+        if let Some(range) = range {
+            self.group.gid = None;
+            self.group.min_gid = range.start;
+            self.group.max_gid = Some(range.end);
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        validate_name("group.name", &self.group.name)?;
+        if self.group.display_name.trim().is_empty() {
+            bail!("group.display_name must not be empty");
+        }
+        if self.group.min_gid == 0 {
+            bail!("group.min_gid must be greater than 0");
+        }
+        if self
+            .group
+            .max_gid
+            .is_some_and(|max_gid| max_gid < self.group.min_gid)
+        {
+            bail!("group.max_gid must not be lower than group.min_gid");
+        }
+        if self.group.gid == Some(0) {
+            bail!("group.gid must not be 0");
+        }
+        for member in &self.group.members {
+            validate_name("group.members", member)?;
+        }
+        if self.devices.count == 0 {
+            bail!("devices.count must be greater than 0");
+        }
+        if !Path::new(&self.paths.support_dir).is_absolute() {
+            bail!("paths.support_dir must be an absolute path");
+        }
+        Ok(())
+    }
+
+    /// Path of the binary the launch daemon runs.
+    pub fn binary_path(&self) -> String {
+        self.support_path("chmod-bpf")
+    }
+
+    /// Path of the install manifest.
+    pub fn manifest_path(&self) -> String {
+        self.support_path("manifest.json")
+    }
+
+    fn support_path(&self, file_name: &str) -> String {
+        Path::new(&self.paths.support_dir)
+            .join(file_name)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Accepts POSIX-style account names: letters, digits, `_`, `-` and `.`,
+/// not starting with `-`.
+fn validate_name(key: &str, name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.starts_with('-')
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        bail!("{key} contains an invalid name {name:?}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_rejects_invalid_settings() {
+        type Case = (&'static str, fn(&mut Config), &'static str);
+        let cases: [Case; 9] = [
+            (
+                "group name",
+                |config| config.group.name = "-bpf".to_string(),
+                "group.name contains an invalid name \"-bpf\"",
+            ),
+            (
+                "display name",
+                |config| config.group.display_name = " ".to_string(),
+                "group.display_name must not be empty",
+            ),
+            (
+                "min gid",
+                |config| config.group.min_gid = 0,
+                "group.min_gid must be greater than 0",
+            ),
+            (
+                "max gid",
+                |config| config.group.max_gid = Some(config.group.min_gid - 1),
+                "group.max_gid must not be lower than group.min_gid",
+            ),
+            (
+                "gid",
+                |config| config.group.gid = Some(0),
+                "group.gid must not be 0",
+            ),
+            (
+                "members",
+                |config| config.group.members = vec!["alice".to_string(), "bob smith".to_string()],
+                "group.members contains an invalid name \"bob smith\"",
+            ),
+            (
+                "device count",
+                |config| config.devices.count = 0,
+                "devices.count must be greater than 0",
+            ),
+            (
+                "support dir",
+                |config| config.paths.support_dir = "chmod-bpf".to_string(),
+                "paths.support_dir must be an absolute path",
+            ),
+            (
+                "empty support dir",
+                |config| config.paths.support_dir = String::new(),
+                "paths.support_dir must be an absolute path",
+            ),
+        ];
+        Config::default().validate().unwrap();
+        for (name, breaks, message) in cases {
+            let mut config = Config::default();
+            breaks(&mut config);
+            let error = config.validate().unwrap_err();
+            assert_eq!(error.to_string(), message, "{name}");
+        }
+    }
+
+    #[test]
+    fn override_group_checks_and_applies_the_flags() {
+        let mut config = Config::default();
+        config.override_group(None, None).unwrap();
+        assert_eq!(config, Config::default());
+
+        config
+            .override_group(Some("pcap.users"), Some("Packet Capture"))
+            .unwrap();
+        assert_eq!(config.group.name, "pcap.users");
+        assert_eq!(config.group.display_name, "Packet Capture");
+
+        let error = config.override_group(Some("pcap users"), None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "--group contains an invalid name \"pcap users\""
+        );
+        let error = config.override_group(None, Some("")).unwrap_err();
+        assert_eq!(error.to_string(), "--group-display-name must not be empty");
+        assert_eq!(config.group.name, "pcap.users");
+    }
+
+    #[test]
+    fn override_gid_replaces_the_other_setting() {
+        let mut config = Config::default();
+        let error = config.override_gid(Some(0), None).unwrap_err();
+        assert_eq!(error.to_string(), "--gid must not be 0");
+        assert_eq!(config.group.gid, None);
+
+        config.override_gid(Some(700), None).unwrap();
+        assert_eq!(config.group.gid, Some(700));
+
+        config
+            .override_gid(
+                None,
+                Some(GidRange {
+                    start: 800,
+                    end: 899,
+                }),
+            )
+            .unwrap();
+        assert_eq!(config.group.gid, None);
+        assert_eq!(
+            config.group.gid_range(),
+            GidRange {
+                start: 800,
+                end: 899
+            }
+        );
+
+        let mut config = Config::default();
+        config.group.min_gid = 800;
+        config.group.max_gid = Some(899);
+        config.override_gid(Some(900), None).unwrap();
+        assert_eq!(config.group.gid, Some(900));
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn support_paths_ignore_a_trailing_slash() {
+        let mut config = Config::default();
+        let binary_path = config.binary_path();
+        config.paths.support_dir.push('/');
+        assert_eq!(config.binary_path(), binary_path);
+        assert_eq!(binary_path, resource::CHMOD_BPF_BINARY_PATH);
+    }
+}
@@ -1,43 +1,371 @@
-use anyhow::{Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Serialize;
+use std::path::Path;
 use std::process::Command;
-use std::str;
-use tracing::debug;
+use termtree::Tree;
 
+use crate::backend::SystemBackend;
 use crate::command;
+use crate::config::Config;
+use crate::manifest;
+use crate::output::{self, node_label};
+use crate::plist::LaunchdPlist;
+use crate::resource;
 
 pub const KNOWN_DAEMON_PLISTS: [&str; 2] = [
-    "/Library/LaunchDaemons/com.foctal.chmod-bpf.plist",
-    "/Library/LaunchDaemons/org.wireshark.ChmodBPF.plist",
+    resource::CHMOD_BPF_PLIST_PATH,
+    resource::WIRESHARK_CHMODBPF_PLIST_PATH,
 ];
 
-/// Manages the LaunchDaemon by unloading and reloading it.
-pub fn reload_daemon(plist_path: &str) -> Result<()> {
-    let mut bootout = Command::new("launchctl");
-    bootout.arg("bootout").arg("system").arg(plist_path);
-    if let Ok(status) = bootout.status() {
-        if !status.success() {
-            debug!(?status, "launchctl bootout returned a non-zero exit status");
-        }
-    }
-
-    let mut bootstrap = Command::new("launchctl");
-    bootstrap.arg("bootstrap").arg("system").arg(plist_path);
-    command::run(&mut bootstrap, "bootstrap the chmod-bpf daemon")
-}
-
-/// Unloads the specified LaunchDaemon.
-pub fn unload_daemon(plist_path: &str) -> Result<()> {
+/// Unloads the specified LaunchDaemon from the system domain.
+pub fn bootout_daemon(plist_path: &str) -> Result<()> {
     let mut command = Command::new("launchctl");
     command.arg("bootout").arg("system").arg(plist_path);
     command::run(&mut command, "unload the chmod-bpf daemon")
 }
 
-/// Checks if any of the known daemon settings are present.
-pub fn check_known_daemon_settings() -> Result<String> {
+/// Loads the specified LaunchDaemon into the system domain.
+pub fn bootstrap_daemon(plist_path: &str) -> Result<()> {
+    let mut command = Command::new("launchctl");
+    command.arg("bootstrap").arg("system").arg(plist_path);
+    command::run(&mut command, "bootstrap the chmod-bpf daemon")
+}
+
+/// Checks if any of the known daemon settings are present, starting with the
+/// plist recorded by the install manifest.
+pub fn check_known_daemon_settings(backend: &dyn SystemBackend, config: &Config) -> Result<String> {
+    if let Ok(Some(manifest)) = manifest::load(backend, &config.manifest_path())
+        && backend.path_exists(&manifest.plist_path)
+    {
+        return Ok(manifest.plist_path);
+    }
     for plist in KNOWN_DAEMON_PLISTS.iter() {
-        if std::path::Path::new(plist).exists() {
+        if backend.path_exists(plist) {
             return Ok(plist.to_string());
         }
     }
     Err(anyhow!("No known chmod-bpf daemon configuration was found"))
 }
+
+/// Reads and parses the launchd job definition at the given path.
+pub fn read_daemon_plist(backend: &dyn SystemBackend, plist_path: &str) -> Result<LaunchdPlist> {
+    let contents = backend
+        .read_file(plist_path)?
+        .ok_or_else(|| anyhow!("{plist_path} does not exist"))?;
+    let xml = String::from_utf8(contents).map_err(|_| anyhow!("{plist_path} is not UTF-8"))?;
+    LaunchdPlist::parse(&xml).map_err(|error| anyhow!("Failed to parse {plist_path}: {error}"))
+}
+
+/// launchctl exits with this status when the service is not loaded.
+const LAUNCHCTL_SERVICE_NOT_FOUND: i32 = 113;
+
+/// Runs `launchctl print system/<label>`, returning `None` when the service
+/// is not loaded.
+pub fn launchctl_print(label: &str) -> Result<Option<String>> {
+    let output = Command::new("launchctl")
+        .arg("print")
+        .arg(format!("system/{label}"))
+        .output()
+        .with_context(|| format!("Failed to run launchctl print for {label}"))?;
+    if output.status.success() {
+        return Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()));
+    }
+    if output.status.code() == Some(LAUNCHCTL_SERVICE_NOT_FOUND) {
+        return Ok(None);
+    }
+    bail!(
+        "launchctl print system/{label} returned a non-zero exit status: {}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    )
+}
+
+/// Runtime state of a loaded launchd job, from `launchctl print`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DaemonStatus {
+    /// e.g. `running` or `not running`.
+    pub state: Option<String>,
+    pub pid: Option<u32>,
+    pub runs: Option<u32>,
+    /// Raw value, e.g. `0`, `78: Function not implemented` or `(never exited)`.
+    pub last_exit_status: Option<String>,
+    /// Numeric part of the last exit status, when the job has exited.
+    pub last_exit_code: Option<i32>,
+    pub program: Option<String>,
+    pub arguments: Vec<String>,
+    pub path: Option<String>,
+}
+
+impl DaemonStatus {
+    /// Parses the top-level `key = value` pairs of `launchctl print` output,
+    /// plus the `arguments` block. Keys inside other blocks are ignored.
+    pub fn parse(output: &str) -> Self {
+        let mut status = DaemonStatus::default();
+        let mut depth = 0usize;
+        let mut in_arguments = false;
+        for line in output.lines() {
+            let line = line.trim();
+            if line == "}" {
+                depth = depth.saturating_sub(1);
+                in_arguments = false;
+                continue;
+            }
+            if in_arguments {
+                status.arguments.push(line.to_string());
+                continue;
+            }
+            // Blocks open with `key = {` or, for keyed entries, `"name" => {`.
+            if let Some(opening) = line.strip_suffix('{') {
+                in_arguments = depth == 1 && opening.trim_end() == "arguments =";
+                depth += 1;
+                continue;
+            }
+            let Some((key, value)) = line.split_once(" = ") else {
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            if depth != 1 {
+                continue;
+            }
+            match key {
+                "state" => status.state = Some(value.to_string()),
+                "pid" => status.pid = value.parse().ok(),
+                "runs" => status.runs = value.parse().ok(),
+                "last exit code" => {
+                    status.last_exit_code = value
+                        .split(':')
+                        .next()
+                        .and_then(|code| code.trim().parse().ok());
+                    status.last_exit_status = Some(value.to_string());
+                }
+                "program" => status.program = Some(value.to_string()),
+                "path" => status.path = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        status
+    }
+
+    /// One-line summary, e.g. `not running, 1 run(s), last exit code 0`.
+    pub fn summary(&self) -> String {
+        let mut parts = vec![
+            self.state
+                .clone()
+                .unwrap_or_else(|| "state unknown".to_string()),
+        ];
+        if let Some(pid) = self.pid {
+            parts.push(format!("pid {pid}"));
+        }
+        if let Some(runs) = self.runs {
+            parts.push(format!("{runs} run(s)"));
+        }
+        if let Some(last_exit) = &self.last_exit_status {
+            parts.push(format!("last exit code {last_exit}"));
+        }
+        parts.join(", ")
+    }
+}
+
+/// Launchd label of the job defined at `plist_path`, from its file name.
+pub fn label_for_plist(plist_path: &str) -> Option<String> {
+    Path::new(plist_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+}
+
+/// Queries launchd for the job, returning `None` when it is not loaded.
+pub fn daemon_status(backend: &dyn SystemBackend, label: &str) -> Result<Option<DaemonStatus>> {
+    Ok(backend
+        .launchctl_print(label)?
+        .map(|output| DaemonStatus::parse(&output)))
+}
+
+/// Where the launch daemon is defined and whether launchd has it loaded.
+#[derive(Debug, Clone, Serialize)]
+pub struct DaemonReport {
+    pub label: Option<String>,
+    /// Plist found on disk, if any.
+    pub plist_path: Option<String>,
+    /// Program and arguments from the plist.
+    pub program_arguments: Vec<String>,
+    pub loaded: bool,
+    /// Runtime state, when the job is loaded.
+    pub status: Option<DaemonStatus>,
+    /// Problems reading the plist or querying launchd.
+    pub errors: Vec<String>,
+}
+
+impl DaemonReport {
+    pub fn collect(backend: &dyn SystemBackend, config: &Config) -> Self {
+        let mut report = DaemonReport {
+            label: None,
+            plist_path: None,
+            program_arguments: Vec::new(),
+            loaded: false,
+            status: None,
+            errors: Vec::new(),
+        };
+        let Ok(plist_path) = check_known_daemon_settings(backend, config) else {
+            return report;
+        };
+        report.label = label_for_plist(&plist_path);
+        match read_daemon_plist(backend, &plist_path) {
+            Ok(definition) => {
+                report.label = Some(definition.label);
+                report.program_arguments = definition.program_arguments;
+            }
+            Err(error) => report.errors.push(error.to_string()),
+        }
+        report.plist_path = Some(plist_path);
+        if let Some(label) = &report.label {
+            match daemon_status(backend, label) {
+                Ok(status) => {
+                    report.loaded = status.is_some();
+                    report.status = status;
+                }
+                Err(error) => report.errors.push(format!("{error:#}")),
+            }
+        }
+        report
+    }
+
+    pub fn render_text(&self) -> String {
+        let title = self.label.as_deref().unwrap_or("chmod-bpf daemon");
+        let mut tree: Tree<String> = Tree::new(node_label("Daemon", Some(title), None));
+        let Some(plist_path) = &self.plist_path else {
+            tree.push(node_label(
+                &output::get_check_error_log("No known chmod-bpf daemon configuration was found"),
+                None,
+                None,
+            ));
+            return format!("{tree}");
+        };
+        tree.push(node_label("Plist", Some(plist_path), None));
+        if !self.program_arguments.is_empty() {
+            tree.push(node_label(
+                "Runs",
+                Some(&self.program_arguments.join(" ")),
+                None,
+            ));
+        }
+        tree.push(node_label(
+            "Loaded",
+            Some(if self.loaded { "yes" } else { "no" }),
+            None,
+        ));
+        if let Some(status) = &self.status {
+            let unknown = "unknown".to_string();
+            tree.push(node_label(
+                "State",
+                Some(status.state.as_ref().unwrap_or(&unknown)),
+                None,
+            ));
+            if let Some(pid) = status.pid {
+                tree.push(node_label("PID", Some(&pid.to_string()), None));
+            }
+            tree.push(node_label(
+                "Runs",
+                Some(&status.runs.map_or(unknown.clone(), |runs| runs.to_string())),
+                None,
+            ));
+            tree.push(node_label(
+                "Last exit code",
+                Some(status.last_exit_status.as_ref().unwrap_or(&unknown)),
+                None,
+            ));
+            if let Some(program) = &status.program {
+                tree.push(node_label("Program", Some(program), None));
+            }
+        }
+        for error in &self.errors {
+            tree.push(node_label(&output::get_check_error_log(error), None, None));
+        }
+        format!("{tree}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Written by hand in the layout of `launchctl print system/<label>` on
+    // macOS 14, including the nested blocks the parser has to skip.
+    const RUNNING: &str = include_str!("../tests/fixtures/launchctl/print-running.txt");
+    const NOT_RUNNING: &str = include_str!("../tests/fixtures/launchctl/print-not-running.txt");
+    const NEVER_EXITED: &str = include_str!("../tests/fixtures/launchctl/print-never-exited.txt");
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_a_running_daemon() {
+        let binary = "/Library/Application Support/Foctal/chmod-bpf/chmod-bpf";
+        assert_eq!(
+            DaemonStatus::parse(RUNNING),
+            DaemonStatus {
+                state: Some("running".to_string()),
+                pid: Some(412),
+                runs: Some(1),
+                last_exit_status: Some("(never exited)".to_string()),
+                last_exit_code: None,
+                program: Some(binary.to_string()),
+                arguments: strings(&[binary, "--group", "access_bpf", "apply"]),
+                path: Some("/Library/LaunchDaemons/com.foctal.chmod-bpf.plist".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_a_daemon_that_exited_with_an_error() {
+        let binary = "/Library/Application Support/Foctal/chmod-bpf/chmod-bpf";
+        let status = DaemonStatus::parse(NOT_RUNNING);
+        assert_eq!(
+            status,
+            DaemonStatus {
+                state: Some("not running".to_string()),
+                pid: None,
+                runs: Some(3),
+                last_exit_status: Some("78: Function not implemented".to_string()),
+                last_exit_code: Some(78),
+                program: Some(binary.to_string()),
+                arguments: strings(&[binary, "apply"]),
+                path: Some("/Library/LaunchDaemons/com.foctal.chmod-bpf.plist".to_string()),
+            }
+        );
+        assert_eq!(
+            status.summary(),
+            "not running, 3 run(s), last exit code 78: Function not implemented"
+        );
+    }
+
+    #[test]
+    fn nested_blocks_do_not_leak_into_top_level_keys() {
+        assert_eq!(
+            DaemonStatus::parse(NEVER_EXITED),
+            DaemonStatus {
+                state: Some("not running".to_string()),
+                pid: None,
+                runs: Some(0),
+                last_exit_status: Some("(never exited)".to_string()),
+                last_exit_code: None,
+                program: Some("/usr/local/bin/chmod-bpf".to_string()),
+                arguments: strings(&[
+                    "/usr/local/bin/chmod-bpf",
+                    "--config",
+                    "/etc/chmod-bpf.toml",
+                    "apply",
+                ]),
+                path: Some("/Library/LaunchDaemons/org.example.chmod-bpf.plist".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn label_comes_from_the_plist_file_name() {
+        assert_eq!(
+            label_for_plist("/Library/LaunchDaemons/com.foctal.chmod-bpf.plist").as_deref(),
+            Some("com.foctal.chmod-bpf")
+        );
+    }
+}
@@ -0,0 +1,846 @@
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+use std::fmt;
+use termtree::Tree;
+use tracing::warn;
+
+use crate::{
+    backend::SystemBackend,
+    bpf::{self, DeviceVerdict},
+    config::Config,
+    manifest,
+    output::{self, node_label},
+    plan,
+    plist::LaunchdPlist,
+    report::{self, CheckOptions, CheckReport, Status},
+    resource,
+    user::GroupMembership,
+};
+
+/// A change `doctor` can make on the operator's behalf.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "fix", rename_all = "snake_case")]
+pub enum Fix {
+    /// Remove Wireshark's ChmodBPF daemon and install chmod-bpf in its place.
+    Migrate,
+    /// Remove Wireshark's ChmodBPF daemon installed next to chmod-bpf.
+    RemoveWireshark,
+    /// Install, or reinstall, the launch daemon, group and binary.
+    Install,
+    /// Load an installed but unloaded launch daemon.
+    Bootstrap { plist_path: String },
+    /// Pre-create BPF devices and reset their group and mode.
+    Apply,
+}
+
+impl Fix {
+    /// Command an operator would run to make the same change. Removing
+    /// Wireshark's daemon is spelled out from the plan, so it lists every
+    /// file that goes.
+    pub fn command(&self, backend: &dyn SystemBackend) -> Result<String> {
+        Ok(match self {
+            Fix::Migrate => "sudo chmod-bpf migrate".to_string(),
+            Fix::RemoveWireshark => plan::remove_wireshark_plan(backend)?.sudo_command_line(),
+            Fix::Install => "sudo chmod-bpf install".to_string(),
+            Fix::Bootstrap { plist_path } => {
+                format!("sudo launchctl bootstrap system {plist_path}")
+            }
+            Fix::Apply => "sudo chmod-bpf apply".to_string(),
+        })
+    }
+
+    /// Whether `doctor` may apply the fix itself. Migrating and removing
+    /// Wireshark's daemon delete files that cannot be restored, so they are
+    /// left to the operator.
+    pub fn is_safe(&self) -> bool {
+        !matches!(self, Fix::Migrate | Fix::RemoveWireshark)
+    }
+
+    /// Applies the fix. Every fix is safe to repeat and rolls back on failure.
+    pub fn apply(
+        &self,
+        backend: &dyn SystemBackend,
+        config: &Config,
+        daemon: &LaunchdPlist,
+    ) -> Result<()> {
+        match self {
+            Fix::Migrate => plan::migrate_plan(backend, config, daemon)?.execute(backend),
+            Fix::RemoveWireshark => plan::remove_wireshark_plan(backend)?.execute(backend),
+            Fix::Install => {
+                let daemon = reinstall_daemon(backend, config, daemon)?;
+                plan::install_plan(backend, config, &daemon)?.execute(backend)
+            }
+            Fix::Bootstrap { plist_path } => backend.bootstrap_daemon(plist_path),
+            Fix::Apply => {
+                bpf::apply_device_permissions(backend, &config.group.name, config.devices.count)
+            }
+        }
+    }
+}
+
+/// The daemon definition an install fix writes. An earlier install's plist
+/// is parsed and its label and settings are kept, so the fix rewrites that
+/// plist as it was installed instead of adding a second daemon. Only the
+/// program comes from `daemon`, so it is the binary this install writes.
+fn reinstall_daemon(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    daemon: &LaunchdPlist,
+) -> Result<LaunchdPlist> {
+    let Some(manifest) = manifest::load(backend, &config.manifest_path())? else {
+        return Ok(daemon.clone());
+    };
+    let mut reinstall = daemon.clone();
+    if let Some(label) = crate::daemon::label_for_plist(&manifest.plist_path) {
+        reinstall.label = label;
+    }
+    if reinstall.path() != manifest.plist_path {
+        bail!(
+            "chmod-bpf was installed with the launch daemon {}, which doctor cannot reinstall; run `sudo chmod-bpf install` with the original options instead",
+            manifest.plist_path
+        );
+    }
+    match crate::daemon::read_daemon_plist(backend, &manifest.plist_path) {
+        Ok(installed) => {
+            // A plist from the script-based releases runs no `apply`.
+            if installed.program_arguments.last().map(String::as_str) == Some("apply") {
+                reinstall.program_arguments.truncate(1);
+                reinstall
+                    .program_arguments
+                    .extend(installed.program_arguments.into_iter().skip(1));
+            }
+            reinstall.run_at_load = installed.run_at_load;
+            reinstall.keep_alive = installed.keep_alive;
+            reinstall.standard_out_path = installed.standard_out_path;
+            reinstall.standard_error_path = installed.standard_error_path;
+            reinstall.watch_paths = installed.watch_paths;
+        }
+        Err(error) => warn!(
+            ?error,
+            "The installed plist cannot be read; reinstalling it with the current daemon options"
+        ),
+    }
+    Ok(reinstall)
+}
+
+impl fmt::Display for Fix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fix::Migrate => f.write_str("Replace Wireshark's ChmodBPF daemon with chmod-bpf"),
+            Fix::RemoveWireshark => f.write_str("Remove the conflicting Wireshark ChmodBPF daemon"),
+            Fix::Install => f.write_str("Install chmod-bpf"),
+            Fix::Bootstrap { plist_path } => write!(f, "Load the launch daemon {plist_path}"),
+            Fix::Apply => f.write_str("Pre-create BPF devices and reset their permissions"),
+        }
+    }
+}
+
+/// One problem found by `doctor`, with what to do about it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    /// Area of the check the problem belongs to.
+    pub area: &'static str,
+    pub status: Status,
+    pub problem: String,
+    /// What the operator should do.
+    pub remediation: String,
+    /// Change `doctor` can make itself; `None` when only the operator can act.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub fix: Option<Fix>,
+    /// Command that makes the change, when there is a fix.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+}
+
+impl Finding {
+    fn new(area: &'static str, status: Status, problem: impl Into<String>) -> Self {
+        Self {
+            area,
+            status,
+            problem: problem.into(),
+            remediation: String::new(),
+            fix: None,
+            command: None,
+        }
+    }
+
+    fn manual(mut self, remediation: impl Into<String>) -> Self {
+        self.remediation = remediation.into();
+        self
+    }
+
+    fn fixed_by(mut self, remediation: impl Into<String>, fix: Fix) -> Self {
+        self.remediation = remediation.into();
+        self.fix = Some(fix);
+        self
+    }
+}
+
+/// Findings of one `doctor` run.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnosis {
+    pub version: String,
+    /// Most severe status across all findings.
+    pub status: Status,
+    pub findings: Vec<Finding>,
+    /// Exit status `check` would return for the same state.
+    #[serde(skip)]
+    pub exit_code: u8,
+}
+
+impl Diagnosis {
+    /// Runs every check and classifies what it found.
+    pub fn collect(backend: &dyn SystemBackend, config: &Config) -> Result<Self> {
+        let report = CheckReport::collect(backend, config, CheckOptions::default());
+        let installed = manifest::load(backend, &config.manifest_path())?.is_some();
+        let wireshark = backend.path_exists(resource::WIRESHARK_CHMODBPF_PLIST_PATH);
+        let mut findings = diagnose(&report, installed, wireshark);
+        for finding in &mut findings {
+            if let Some(fix) = &finding.fix {
+                finding.command = Some(fix.command(backend)?);
+            }
+        }
+        Ok(Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            status: findings
+                .iter()
+                .map(|finding| finding.status)
+                .max()
+                .unwrap_or(Status::Ok),
+            findings,
+            exit_code: report.exit_code(false),
+        })
+    }
+
+    /// Distinct fixes `doctor` may apply, in the order they should run. The
+    /// ones that are not safe are left out, and so is an install that an
+    /// unapplied migration would have done.
+    pub fn fixes(&self) -> Vec<Fix> {
+        let mut fixes: Vec<Fix> = Vec::new();
+        for fix in self
+            .findings
+            .iter()
+            .filter_map(|finding| finding.fix.clone())
+        {
+            if !fixes.contains(&fix) {
+                fixes.push(fix);
+            }
+        }
+        if fixes.contains(&Fix::Migrate) {
+            fixes.retain(|fix| *fix != Fix::Install);
+        }
+        fixes.retain(Fix::is_safe);
+        // Installing loads the daemon, which applies the permissions at load.
+        if fixes.iter().any(|fix| matches!(fix, Fix::Install)) {
+            fixes.retain(|fix| !matches!(fix, Fix::Bootstrap { .. } | Fix::Apply));
+        }
+        fixes.sort_by_key(|fix| match fix {
+            Fix::Install => 0,
+            Fix::Bootstrap { .. } => 1,
+            _ => 2,
+        });
+        fixes
+    }
+
+    pub fn render_text(&self) -> String {
+        let mut tree: Tree<String> = Tree::new(node_label("chmod-bpf doctor", None, None));
+        if self.findings.is_empty() {
+            tree.push(node_label(
+                &output::get_check_ok_log("No problems found"),
+                None,
+                None,
+            ));
+        }
+        for finding in &self.findings {
+            let mut finding_tree = Tree::new(report::status_log(finding.status, finding.area));
+            let problem = match finding.status {
+                Status::Ok => output::get_check_ok_log(&finding.problem),
+                Status::Warning => output::get_check_warn_log(&finding.problem),
+                Status::Error => output::get_check_error_log(&finding.problem),
+            };
+            finding_tree.push(node_label(&problem, None, None));
+            finding_tree.push(node_label("Fix", Some(&finding.remediation), None));
+            if let Some(command) = &finding.command {
+                finding_tree.push(node_label("Run", Some(command), None));
+            }
+            tree.push(finding_tree);
+        }
+        format!("\n{tree}\n")
+    }
+
+    pub fn render_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("Failed to serialize the diagnosis")
+    }
+
+    pub fn render_yaml(&self) -> Result<String> {
+        serde_yaml::to_string(self).context("Failed to serialize the diagnosis")
+    }
+}
+
+/// Turns a check report into findings with remediations. `installed` tells
+/// whether chmod-bpf's manifest exists and `wireshark` whether Wireshark's
+/// ChmodBPF daemon is present.
+pub fn diagnose(report: &CheckReport, installed: bool, wireshark: bool) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let first_message = |section: &report::Section| section.messages.first().cloned();
+
+    if wireshark {
+        let finding = Finding::new(
+            "Conflict",
+            Status::Warning,
+            format!(
+                "Wireshark's ChmodBPF daemon is installed at {}",
+                resource::WIRESHARK_CHMODBPF_PLIST_PATH
+            ),
+        );
+        findings.push(if installed {
+            finding.fixed_by(
+                "Remove the conflicting Wireshark daemon; both reset BPF permissions at boot.",
+                Fix::RemoveWireshark,
+            )
+        } else {
+            finding.fixed_by(
+                "Migrate to chmod-bpf, reusing Wireshark's group.",
+                Fix::Migrate,
+            )
+        });
+    }
+
+    if report.permissions.status != Status::Ok {
+        let problem = first_message(&report.permissions)
+            .unwrap_or_else(|| "BPF devices are not accessible".to_string());
+        let finding = Finding::new("Permissions", report.permissions.status, problem);
+        let problems = &report.device_summary.problems;
+        findings.push(if report.device_summary.total == 0 {
+            finding.fixed_by("Pre-create the BPF devices.", Fix::Apply)
+        } else if problems.contains_key(&DeviceVerdict::WrongGroup)
+            || problems.contains_key(&DeviceVerdict::WrongMode)
+        {
+            finding.fixed_by("Reset the group and mode of the BPF devices.", Fix::Apply)
+        } else if problems.contains_key(&DeviceVerdict::NotInGroup) {
+            finding.manual("Join the BPF group; see the group finding.")
+        } else {
+            finding.manual("Inspect the failing devices with `chmod-bpf check --devices`.")
+        });
+    }
+
+    if report.device_count.status != Status::Ok {
+        let problem = first_message(&report.device_count)
+            .unwrap_or_else(|| "Too few BPF devices exist".to_string());
+        findings.push(
+            Finding::new("Device count", report.device_count.status, problem)
+                .fixed_by("Pre-create the missing BPF devices.", Fix::Apply),
+        );
+    }
+
+    let group = &report.group;
+    let user = report.user.as_deref().unwrap_or("the current user");
+    match report.group_state {
+        // Doctor runs under sudo, where the login session cannot be seen;
+        // directory membership is all it can fix.
+        GroupMembership::Active | GroupMembership::SessionUnknown => {}
+        GroupMembership::NotActiveInSession => findings.push(
+            Finding::new(
+                "Group",
+                Status::Warning,
+                format!("{user} was added to {group}, but this login session predates the change"),
+            )
+            .manual("Log out and back in (or reboot) to activate the group membership."),
+        ),
+        GroupMembership::Absent => findings.push(
+            Finding::new(
+                "Group",
+                Status::Error,
+                format!("{user} is not a member of {group}"),
+            )
+            .fixed_by(
+                "Install chmod-bpf, which adds you to the group, then log in again.",
+                Fix::Install,
+            ),
+        ),
+        GroupMembership::GroupMissing => findings.push(
+            Finding::new(
+                "Group",
+                Status::Error,
+                format!("Group {group} does not exist"),
+            )
+            .fixed_by("Install chmod-bpf to create the group.", Fix::Install),
+        ),
+    }
+
+    let launchd = &report.launchd;
+    let wireshark_daemon =
+        launchd.plist_path.as_deref() == Some(resource::WIRESHARK_CHMODBPF_PLIST_PATH);
+    if report.daemon.status != Status::Ok && !wireshark_daemon {
+        let problem = first_message(&report.daemon)
+            .unwrap_or_else(|| "The launch daemon has a problem".to_string());
+        let finding = Finding::new("Daemon", report.daemon.status, problem);
+        findings.push(match &launchd.plist_path {
+            None => finding.fixed_by("Install the launch daemon.", Fix::Install),
+            Some(_) if !launchd.errors.is_empty() => finding.fixed_by(
+                "Reinstall: the launch daemon plist could not be read.",
+                Fix::Install,
+            ),
+            Some(plist_path) if !launchd.loaded => finding.fixed_by(
+                "Load the launch daemon into launchd.",
+                Fix::Bootstrap {
+                    plist_path: plist_path.clone(),
+                },
+            ),
+            Some(_) => finding.manual(
+                "Run `sudo chmod-bpf apply` to see why the daemon fails, or check its log.",
+            ),
+        });
+    }
+
+    if report.drift.status != Status::Ok {
+        let drifted: Vec<String> = report
+            .drift_files
+            .iter()
+            .filter(|file| file.state != crate::drift::DriftState::Matches)
+            .map(|file| format!("{} ({})", file.path, file.state))
+            .collect();
+        let problem = if drifted.is_empty() {
+            first_message(&report.drift).unwrap_or_else(|| "Drift detected".to_string())
+        } else {
+            format!("Drift detected: {}", drifted.join(", "))
+        };
+        let remediation = if report
+            .drift_files
+            .iter()
+            .any(|file| file.path.ends_with(crate::drift::LEGACY_SCRIPT_NAME))
+        {
+            "Reinstall: script drift detected."
+        } else {
+            "Reinstall to bring the installed assets in line with this version."
+        };
+        findings.push(
+            Finding::new("Drift", report.drift.status, problem).fixed_by(remediation, Fix::Install),
+        );
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{FileMetadata, MemoryBackend, MemoryDevice};
+    use crate::drift::{DriftState, FileDrift};
+    use crate::report::Section;
+    use std::collections::BTreeSet;
+    use std::path::PathBuf;
+
+    fn install_daemon(backend: &MemoryBackend, config: &Config, daemon: &LaunchdPlist) {
+        plan::install_plan(backend, config, daemon)
+            .unwrap()
+            .execute(backend)
+            .unwrap();
+    }
+
+    /// A daemon installed with a custom label and every option set.
+    fn custom_daemon() -> LaunchdPlist {
+        LaunchdPlist {
+            label: "org.example.bpf".to_string(),
+            program_arguments: vec![
+                resource::CHMOD_BPF_BINARY_PATH.to_string(),
+                "--config".to_string(),
+                "/etc/chmod-bpf.toml".to_string(),
+                "apply".to_string(),
+            ],
+            run_at_load: true,
+            keep_alive: Some(true),
+            standard_out_path: Some("/var/log/chmod-bpf.log".to_string()),
+            standard_error_path: Some("/var/log/chmod-bpf.err".to_string()),
+            watch_paths: vec!["/dev".to_string()],
+        }
+    }
+
+    #[test]
+    fn install_fix_keeps_the_installed_daemon_settings() {
+        let backend = MemoryBackend::root("alice");
+        let config = Config::default();
+        let installed = custom_daemon();
+        install_daemon(&backend, &config, &installed);
+        // The binary drifted; the plist is intact.
+        backend
+            .state
+            .borrow_mut()
+            .files
+            .insert(config.binary_path(), b"modified".to_vec());
+
+        Fix::Install
+            .apply(&backend, &config, &LaunchdPlist::default())
+            .unwrap();
+        let state = backend.state.borrow();
+        assert_eq!(state.loaded_daemons, BTreeSet::from([installed.path()]));
+        assert!(!state.files.contains_key(&LaunchdPlist::default().path()));
+        assert_eq!(
+            state.files[&installed.path()],
+            installed.render().into_bytes()
+        );
+    }
+
+    #[test]
+    fn install_fix_keeps_the_label_of_an_unreadable_plist() {
+        let backend = MemoryBackend::root("alice");
+        let config = Config::default();
+        let installed = custom_daemon();
+        install_daemon(&backend, &config, &installed);
+        backend
+            .state
+            .borrow_mut()
+            .files
+            .insert(installed.path(), b"tampered".to_vec());
+
+        Fix::Install
+            .apply(&backend, &config, &LaunchdPlist::default())
+            .unwrap();
+        let expected = LaunchdPlist {
+            label: installed.label.clone(),
+            ..LaunchdPlist::default()
+        };
+        let state = backend.state.borrow();
+        assert_eq!(state.loaded_daemons, BTreeSet::from([installed.path()]));
+        assert_eq!(
+            state.files[&installed.path()],
+            expected.render().into_bytes()
+        );
+    }
+
+    #[test]
+    fn install_fix_refuses_a_plist_outside_launch_daemons() {
+        let backend = MemoryBackend::root("alice");
+        let config = Config::default();
+        install_daemon(&backend, &config, &custom_daemon());
+        let mut manifest = manifest::load(&backend, &config.manifest_path())
+            .unwrap()
+            .unwrap();
+        manifest.plist_path = "/opt/bpf/org.example.bpf.plist".to_string();
+        backend.state.borrow_mut().files.insert(
+            config.manifest_path(),
+            manifest.to_json().unwrap().into_bytes(),
+        );
+        let before = backend.state.borrow().clone();
+
+        let error = Fix::Install
+            .apply(&backend, &config, &LaunchdPlist::default())
+            .unwrap_err();
+        assert!(error.to_string().contains("cannot reinstall"), "{error:#}");
+        assert_eq!(*backend.state.borrow(), before);
+    }
+
+    #[test]
+    fn remove_wireshark_command_lists_every_removal() {
+        let backend = MemoryBackend::root("alice");
+        let script = format!("{}/ChmodBPF", resource::WIRESHARK_CHMODBPF_DIR_PATH);
+        {
+            let mut state = backend.state.borrow_mut();
+            state.files.insert(
+                resource::WIRESHARK_CHMODBPF_PLIST_PATH.to_string(),
+                b"<plist/>".to_vec(),
+            );
+            state
+                .dirs
+                .insert(resource::WIRESHARK_CHMODBPF_DIR_PATH.to_string());
+            state.files.insert(script, Vec::new());
+        }
+        assert_eq!(
+            Fix::RemoveWireshark.command(&backend).unwrap(),
+            format!(
+                "sudo launchctl bootout system {plist}; sudo rm -rf '{dir}' && sudo rm -f {plist}",
+                plist = resource::WIRESHARK_CHMODBPF_PLIST_PATH,
+                dir = resource::WIRESHARK_CHMODBPF_DIR_PATH,
+            )
+        );
+        assert!(!Fix::RemoveWireshark.is_safe());
+        assert!(!Fix::Migrate.is_safe());
+    }
+
+    /// Report of a healthy installation, for the cases to break.
+    fn healthy_report() -> CheckReport {
+        let backend = MemoryBackend::root("alice");
+        let config = Config::default();
+        install_daemon(&backend, &config, &LaunchdPlist::default());
+        let gid = backend.group_gid(&config.group.name).unwrap();
+        {
+            let mut state = backend.state.borrow_mut();
+            state.sysctls.insert(
+                bpf::BPF_MAX_DEVICES_SYSCTL.to_string(),
+                config.devices.count.to_string(),
+            );
+            for index in 0..config.devices.count {
+                state.devices.insert(
+                    PathBuf::from(format!("/dev/bpf{index}")),
+                    MemoryDevice {
+                        metadata: FileMetadata {
+                            uid: 0,
+                            gid,
+                            mode: 0o020660,
+                        },
+                        accessible: true,
+                    },
+                );
+            }
+        }
+        let report = CheckReport::collect(&backend, &config, CheckOptions::default());
+        assert_eq!(report.status, Status::Ok, "{report:?}");
+        report
+    }
+
+    fn failing(status: Status) -> Section {
+        Section {
+            status,
+            messages: vec!["failing".to_string()],
+            hints: Vec::new(),
+        }
+    }
+
+    fn permission_problem(report: &mut CheckReport, verdict: DeviceVerdict) {
+        report.permissions = failing(Status::Error);
+        report.device_summary.accessible = 0;
+        report
+            .device_summary
+            .problems
+            .insert(verdict, report.device_summary.total);
+    }
+
+    #[test]
+    fn diagnose_maps_reports_to_findings_and_fixes() {
+        type Case = (
+            &'static str,
+            fn(&mut CheckReport),
+            bool,
+            bool,
+            Vec<(&'static str, Option<Fix>)>,
+        );
+        let plist_path = LaunchdPlist::default().path();
+        let cases: Vec<Case> = vec![
+            ("healthy", |_| {}, true, false, vec![]),
+            (
+                "wireshark next to chmod-bpf",
+                |_| {},
+                true,
+                true,
+                vec![("Conflict", Some(Fix::RemoveWireshark))],
+            ),
+            (
+                "wireshark only",
+                |_| {},
+                false,
+                true,
+                vec![("Conflict", Some(Fix::Migrate))],
+            ),
+            (
+                "wrong group",
+                |report| permission_problem(report, DeviceVerdict::WrongGroup),
+                true,
+                false,
+                vec![("Permissions", Some(Fix::Apply))],
+            ),
+            (
+                "wrong mode",
+                |report| permission_problem(report, DeviceVerdict::WrongMode),
+                true,
+                false,
+                vec![("Permissions", Some(Fix::Apply))],
+            ),
+            (
+                "not in group",
+                |report| permission_problem(report, DeviceVerdict::NotInGroup),
+                true,
+                false,
+                vec![("Permissions", None)],
+            ),
+            (
+                "unreadable devices",
+                |report| permission_problem(report, DeviceVerdict::Unreadable),
+                true,
+                false,
+                vec![("Permissions", None)],
+            ),
+            (
+                "no devices",
+                |report| {
+                    report.permissions = failing(Status::Error);
+                    report.device_summary = Default::default();
+                },
+                true,
+                false,
+                vec![("Permissions", Some(Fix::Apply))],
+            ),
+            (
+                "too few devices",
+                |report| report.device_count = failing(Status::Warning),
+                true,
+                false,
+                vec![("Device count", Some(Fix::Apply))],
+            ),
+            (
+                "stale session",
+                |report| report.group_state = GroupMembership::NotActiveInSession,
+                true,
+                false,
+                vec![("Group", None)],
+            ),
+            (
+                "session unknown under sudo",
+                |report| report.group_state = GroupMembership::SessionUnknown,
+                true,
+                false,
+                vec![],
+            ),
+            (
+                "not a member",
+                |report| report.group_state = GroupMembership::Absent,
+                true,
+                false,
+                vec![("Group", Some(Fix::Install))],
+            ),
+            (
+                "group missing",
+                |report| report.group_state = GroupMembership::GroupMissing,
+                false,
+                false,
+                vec![("Group", Some(Fix::Install))],
+            ),
+            (
+                "no daemon",
+                |report| {
+                    report.daemon = failing(Status::Error);
+                    report.launchd.plist_path = None;
+                },
+                false,
+                false,
+                vec![("Daemon", Some(Fix::Install))],
+            ),
+            (
+                "unreadable plist",
+                |report| {
+                    report.daemon = failing(Status::Warning);
+                    report.launchd.errors.push("not UTF-8".to_string());
+                },
+                true,
+                false,
+                vec![("Daemon", Some(Fix::Install))],
+            ),
+            (
+                "daemon not loaded",
+                |report| {
+                    report.daemon = failing(Status::Error);
+                    report.launchd.loaded = false;
+                },
+                true,
+                false,
+                vec![(
+                    "Daemon",
+                    Some(Fix::Bootstrap {
+                        plist_path: LaunchdPlist::default().path(),
+                    }),
+                )],
+            ),
+            (
+                "daemon exits with an error",
+                |report| report.daemon = failing(Status::Warning),
+                true,
+                false,
+                vec![("Daemon", None)],
+            ),
+            (
+                "wireshark's daemon is the one found",
+                |report| {
+                    report.daemon = failing(Status::Error);
+                    report.launchd.plist_path =
+                        Some(resource::WIRESHARK_CHMODBPF_PLIST_PATH.to_string());
+                },
+                false,
+                true,
+                vec![("Conflict", Some(Fix::Migrate))],
+            ),
+            (
+                "drift",
+                |report| {
+                    report.drift = failing(Status::Warning);
+                    report.drift_files = vec![FileDrift {
+                        path: resource::CHMOD_BPF_BINARY_PATH.to_string(),
+                        state: DriftState::Modified,
+                        diff: None,
+                    }];
+                },
+                true,
+                false,
+                vec![("Drift", Some(Fix::Install))],
+            ),
+        ];
+        let healthy = healthy_report();
+        assert_eq!(
+            healthy.launchd.plist_path.as_deref(),
+            Some(plist_path.as_str())
+        );
+        for (name, breaks, installed, wireshark, expected) in cases {
+            let mut report = healthy.clone();
+            breaks(&mut report);
+            let findings: Vec<_> = diagnose(&report, installed, wireshark)
+                .into_iter()
+                .map(|finding| (finding.area, finding.fix))
+                .collect();
+            assert_eq!(findings, expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn diagnose_names_script_drift() {
+        let mut report = healthy_report();
+        report.drift = failing(Status::Warning);
+        report.drift_files = vec![FileDrift {
+            path: format!(
+                "{}/{}",
+                resource::CHMOD_BPF_SUPPORT_DIR_PATH,
+                crate::drift::LEGACY_SCRIPT_NAME
+            ),
+            state: DriftState::Outdated {
+                installed_by: "0.1.0".to_string(),
+            },
+            diff: None,
+        }];
+        let findings = diagnose(&report, true, false);
+        assert_eq!(findings[0].remediation, "Reinstall: script drift detected.");
+    }
+
+    fn diagnosis(fixes: Vec<Fix>) -> Diagnosis {
+        Diagnosis {
+            version: String::new(),
+            status: Status::Error,
+            findings: fixes
+                .into_iter()
+                .map(|fix| Finding::new("Test", Status::Error, "").fixed_by("", fix))
+                .collect(),
+            exit_code: 0,
+        }
+    }
+
+    #[test]
+    fn fixes_only_include_safe_ones_in_order() {
+        let bootstrap = Fix::Bootstrap {
+            plist_path: LaunchdPlist::default().path(),
+        };
+        let cases = [
+            (
+                vec![Fix::Apply, bootstrap.clone()],
+                vec![bootstrap.clone(), Fix::Apply],
+            ),
+            (
+                vec![Fix::Apply, Fix::Install, bootstrap.clone(), Fix::Install],
+                vec![Fix::Install],
+            ),
+            (vec![Fix::RemoveWireshark, Fix::Apply], vec![Fix::Apply]),
+            (
+                vec![Fix::Migrate, Fix::Install, Fix::Apply],
+                vec![Fix::Apply],
+            ),
+            (vec![Fix::Migrate], vec![]),
+        ];
+        for (offered, expected) in cases {
+            assert_eq!(diagnosis(offered.clone()).fixes(), expected, "{offered:?}");
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use anyhow::Result;
+use serde::Serialize;
+use similar::TextDiff;
+use std::fmt;
+use std::path::Path;
+
+use crate::{
+    backend::SystemBackend,
+    config::Config,
+    manifest::{self, Manifest},
+    plist::LaunchdPlist,
+};
+
+/// Name of the shell script that releases before the native `apply`
+/// subcommand installed in the support directory.
+pub const LEGACY_SCRIPT_NAME: &str = "chmod-bpf.sh";
+
+/// How an installed asset compares with what this version would install.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum DriftState {
+    /// Identical to what this version installs.
+    Matches,
+    /// Changed since it was installed.
+    Modified,
+    /// Untouched since install, but written by another version.
+    Outdated { installed_by: String },
+    /// Recorded by the install manifest but no longer present.
+    Missing,
+}
+
+impl fmt::Display for DriftState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DriftState::Matches => f.write_str("matches"),
+            DriftState::Modified => f.write_str("modified"),
+            DriftState::Outdated { installed_by } => {
+                write!(f, "outdated (installed by {installed_by})")
+            }
+            DriftState::Missing => f.write_str("missing"),
+        }
+    }
+}
+
+/// Drift verdict for one installed file.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileDrift {
+    pub path: String,
+    #[serde(flatten)]
+    pub state: DriftState,
+    /// Unified diff from the installed file to the expected one, for text
+    /// files when requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+}
+
+/// Compares the installed binary and plist with the running binary and the
+/// plist this version renders, using the install manifest to tell local
+/// modifications apart from files written by another version.
+pub fn detect_drift(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    with_diff: bool,
+) -> Result<Vec<FileDrift>> {
+    let manifest = manifest::load(backend, &config.manifest_path())?;
+    let binary_path = config.binary_path();
+    let plist_path = manifest
+        .as_ref()
+        .map_or_else(manifest::default_plist_path, |manifest| {
+            manifest.plist_path.clone()
+        });
+
+    let mut results = Vec::new();
+    let binary = backend.current_executable()?;
+    if let Some(drift) = compare(backend, manifest.as_ref(), &binary_path, &binary, false)? {
+        results.push(drift);
+    }
+    let expected_plist = expected_plist(config, &plist_path).render();
+    if let Some(drift) = compare(
+        backend,
+        manifest.as_ref(),
+        &plist_path,
+        expected_plist.as_bytes(),
+        with_diff,
+    )? {
+        results.push(drift);
+    }
+
+    let legacy_script = Path::new(&config.paths.support_dir)
+        .join(LEGACY_SCRIPT_NAME)
+        .to_string_lossy()
+        .into_owned();
+    if backend.path_exists(&legacy_script) {
+        results.push(FileDrift {
+            path: legacy_script,
+            state: DriftState::Outdated {
+                installed_by: "a release using the shell script".to_string(),
+            },
+            diff: None,
+        });
+    }
+    Ok(results)
+}
+
+/// The plist a default install of this version writes at `plist_path`.
+fn expected_plist(config: &Config, plist_path: &str) -> LaunchdPlist {
+    let mut plist = LaunchdPlist::default();
+    if let Some(label) = Path::new(plist_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+    {
+        plist.label = label;
+    }
+    plist.program_arguments[0] = config.binary_path();
+    plist
+}
+
+/// Returns `None` when the file is neither installed nor recorded.
+fn compare(
+    backend: &dyn SystemBackend,
+    manifest: Option<&Manifest>,
+    path: &str,
+    expected: &[u8],
+    with_diff: bool,
+) -> Result<Option<FileDrift>> {
+    let recorded = manifest.and_then(|manifest| {
+        manifest
+            .files
+            .iter()
+            .find(|file| file.path == path)
+            .map(|file| (manifest, file))
+    });
+    let Some(installed) = backend.read_file(path)? else {
+        return Ok(recorded.map(|_| FileDrift {
+            path: path.to_string(),
+            state: DriftState::Missing,
+            diff: None,
+        }));
+    };
+
+    let installed_hash = manifest::sha256_hex(&installed);
+    let matches_expected = installed == expected;
+    let state = match recorded {
+        Some((_, file)) if file.sha256 != installed_hash => DriftState::Modified,
+        // Files written by this version may carry install options, so only
+        // another version's files are compared with the defaults.
+        Some((manifest, _)) if manifest.version == env!("CARGO_PKG_VERSION") => DriftState::Matches,
+        Some((manifest, _)) if !matches_expected => DriftState::Outdated {
+            installed_by: format!("v{}", manifest.version),
+        },
+        None if !matches_expected => DriftState::Outdated {
+            installed_by: "an unknown version".to_string(),
+        },
+        _ => DriftState::Matches,
+    };
+    let diff = (with_diff && !matches_expected && state != DriftState::Matches)
+        .then(|| unified_diff(path, &installed, expected))
+        .flatten();
+    Ok(Some(FileDrift {
+        path: path.to_string(),
+        state,
+        diff,
+    }))
+}
+
+/// Unified diff from the installed contents to the expected ones; `None`
+/// for binary files.
+fn unified_diff(path: &str, installed: &[u8], expected: &[u8]) -> Option<String> {
+    let installed = std::str::from_utf8(installed).ok()?;
+    let expected = std::str::from_utf8(expected).ok()?;
+    Some(
+        TextDiff::from_lines(installed, expected)
+            .unified_diff()
+            .header(
+                &format!("{path} (installed)"),
+                &format!("{path} (expected)"),
+            )
+            .to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use crate::plan;
+
+    fn installed() -> (MemoryBackend, Config) {
+        let backend = MemoryBackend::root("alice");
+        let config = Config::default();
+        plan::install_plan(&backend, &config, &LaunchdPlist::default())
+            .unwrap()
+            .execute(&backend)
+            .unwrap();
+        (backend, config)
+    }
+
+    fn states(drift: &[FileDrift]) -> Vec<(&str, DriftState)> {
+        drift
+            .iter()
+            .map(|file| (file.path.as_str(), file.state.clone()))
+            .collect()
+    }
+
+    /// Rewrites the manifest as if another version had installed `contents`
+    /// at `path`.
+    fn installed_by(
+        backend: &MemoryBackend,
+        config: &Config,
+        version: &str,
+        path: &str,
+        contents: &[u8],
+    ) {
+        let mut manifest = manifest::load(backend, &config.manifest_path())
+            .unwrap()
+            .unwrap();
+        manifest.version = version.to_string();
+        manifest.files.retain(|file| file.path != path);
+        manifest.add_file(path, contents);
+        let mut state = backend.state.borrow_mut();
+        state.files.insert(path.to_string(), contents.to_vec());
+        state.files.insert(
+            config.manifest_path(),
+            manifest.to_json().unwrap().into_bytes(),
+        );
+    }
+
+    #[test]
+    fn fresh_install_matches() {
+        let (backend, config) = installed();
+        let plist_path = LaunchdPlist::default().path();
+        assert_eq!(
+            states(&detect_drift(&backend, &config, true).unwrap()),
+            [
+                (config.binary_path().as_str(), DriftState::Matches),
+                (plist_path.as_str(), DriftState::Matches),
+            ]
+        );
+    }
+
+    #[test]
+    fn nothing_installed_has_no_drift() {
+        let backend = MemoryBackend::root("alice");
+        assert!(
+            detect_drift(&backend, &Config::default(), true)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn detects_modified_outdated_and_missing_files() {
+        let (backend, config) = installed();
+        let plist_path = LaunchdPlist::default().path();
+        let old_plist = LaunchdPlist {
+            run_at_load: false,
+            ..LaunchdPlist::default()
+        }
+        .render();
+        installed_by(
+            &backend,
+            &config,
+            "0.1.0",
+            &plist_path,
+            old_plist.as_bytes(),
+        );
+        backend
+            .state
+            .borrow_mut()
+            .files
+            .remove(&config.binary_path());
+        assert_eq!(
+            states(&detect_drift(&backend, &config, false).unwrap()),
+            [
+                (config.binary_path().as_str(), DriftState::Missing),
+                (
+                    plist_path.as_str(),
+                    DriftState::Outdated {
+                        installed_by: "v0.1.0".to_string()
+                    }
+                ),
+            ]
+        );
+
+        backend
+            .state
+            .borrow_mut()
+            .files
+            .insert(plist_path.clone(), b"<plist>edited</plist>\n".to_vec());
+        let drift = detect_drift(&backend, &config, false).unwrap();
+        assert_eq!(drift[1].state, DriftState::Modified);
+        assert_eq!(drift[1].diff, None);
+    }
+
+    #[test]
+    fn untracked_files_that_differ_are_from_an_unknown_version() {
+        let (backend, config) = installed();
+        backend
+            .state
+            .borrow_mut()
+            .files
+            .remove(&config.manifest_path());
+        backend
+            .state
+            .borrow_mut()
+            .files
+            .insert(config.binary_path(), b"old binary".to_vec());
+        let drift = detect_drift(&backend, &config, true).unwrap();
+        assert_eq!(
+            drift[0].state,
+            DriftState::Outdated {
+                installed_by: "an unknown version".to_string()
+            }
+        );
+        // Binary files are never diffed.
+        assert_eq!(drift[0].diff, None);
+        assert_eq!(drift[1].state, DriftState::Matches);
+    }
+
+    #[test]
+    fn reports_the_legacy_script() {
+        let (backend, config) = installed();
+        let script = Path::new(&config.paths.support_dir)
+            .join(LEGACY_SCRIPT_NAME)
+            .to_string_lossy()
+            .into_owned();
+        backend
+            .state
+            .borrow_mut()
+            .files
+            .insert(script.clone(), b"#!/bin/sh\n".to_vec());
+        let drift = detect_drift(&backend, &config, true).unwrap();
+        assert_eq!(drift.len(), 3);
+        assert_eq!(drift[2].path, script);
+        assert_eq!(
+            drift[2].state.to_string(),
+            "outdated (installed by a release using the shell script)"
+        );
+    }
+
+    #[test]
+    fn diffs_the_installed_plist_against_the_expected_one() {
+        let (backend, config) = installed();
+        let plist_path = LaunchdPlist::default().path();
+        let expected = LaunchdPlist::default().render();
+        let edited = expected.replace("<true/>", "<false/>");
+        assert_ne!(edited, expected);
+        backend
+            .state
+            .borrow_mut()
+            .files
+            .insert(plist_path.clone(), edited.into_bytes());
+
+        let drift = detect_drift(&backend, &config, true).unwrap();
+        assert_eq!(drift[1].state, DriftState::Modified);
+        let diff = drift[1].diff.as_deref().unwrap();
+        assert!(
+            diff.starts_with(&format!(
+                "--- {plist_path} (installed)\n+++ {plist_path} (expected)\n@@"
+            )),
+            "{diff}"
+        );
+        assert!(diff.contains("\n-\t<false/>\n"), "{diff}");
+        assert!(diff.contains("\n+\t<true/>\n"), "{diff}");
+    }
+}
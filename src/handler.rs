@@ -1,232 +1,382 @@
-use anyhow::{Context, Result, anyhow};
-use chrono::Local;
+use anyhow::{Context, Result, anyhow, bail};
 use inquire::Confirm;
-use termtree::Tree;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::time::Duration;
 use tracing::{info, warn};
 
 use crate::{
-    bpf, daemon,
-    output::{self, node_label},
-    permission, resource, user,
+    backend::SystemBackend,
+    bpf,
+    ci_report::ReportTarget,
+    config::Config,
+    daemon::DaemonReport,
+    doctor::Diagnosis,
+    holders::{self, HolderReport},
+    members::{self, Change, Member},
+    output::OutputFormat,
+    plan::{self, Plan},
+    plist::LaunchdPlist,
+    report::{self, CheckOptions, CheckReport},
+    security::{self, Severity},
+    watch,
 };
 
-pub fn check_bpf_devices() -> Result<()> {
-    let mut tree: Tree<String> = Tree::new(node_label("BPF device audit", None, None));
-
-    info!("Validating BPF device permissions");
-    let mut permission_tree = Tree::new(node_label("Permissions", None, None));
-    match bpf::check_all_bpf_device_permissions() {
-        Ok(_) => {
-            permission_tree.push(node_label(
-                &output::get_ok_log(output::LOG_LABEL_OK, "Permission"),
-                None,
-                None,
-            ));
-            permission_tree.push(node_label(
-                &output::get_check_ok_log("You have access to all detected BPF devices."),
-                None,
-                None,
-            ));
-        }
-        Err(e) => {
-            permission_tree.push(node_label(
-                &output::get_error_log(output::LOG_LABEL_ERROR, "Permission"),
-                None,
-                None,
-            ));
-            permission_tree.push(node_label(
-                &output::get_check_error_log(&e.to_string()),
-                None,
-                None,
-            ));
-        }
+/// Prints the audit and returns the exit status for monitoring systems.
+pub fn check_bpf_devices(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    options: CheckOptions,
+    format: OutputFormat,
+    reports: &[ReportTarget],
+) -> Result<u8> {
+    let report = CheckReport::collect(backend, config, options);
+    match format {
+        OutputFormat::Text => print!("{}", report.render_text()),
+        OutputFormat::Json => println!("{}", report.render_json()?),
+        OutputFormat::Yaml => print!("{}", report.render_yaml()?),
+    }
+    for target in reports {
+        target.write(&report, options.strict)?;
+        info!(
+            "Wrote the {} report to {}",
+            target.kind,
+            target.path.display()
+        );
     }
-    tree.push(permission_tree);
-
-    info!("Checking current group membership");
-    let mut group_tree = Tree::new(node_label("Group", None, None));
-    if user::current_user_in_group(bpf::BPF_GROUP) {
-        group_tree.push(node_label(
-            &output::get_ok_log(output::LOG_LABEL_OK, "Group"),
-            None,
-            None,
-        ));
-        group_tree.push(node_label(
-            &output::get_check_ok_log(&format!(
-                "Current user belongs to {group}",
-                group = bpf::BPF_GROUP
-            )),
-            None,
-            None,
-        ));
+    Ok(report.exit_code(options.strict))
+}
+
+/// Watches the BPF devices until interrupted, reporting each permission
+/// regression as it happens.
+pub fn watch_bpf_devices(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    interval: Duration,
+    format: OutputFormat,
+) -> Result<u8> {
+    let mut watcher = watch::device_watcher(Path::new(watch::DEVICE_DIR), interval);
+    watch::watch_devices(backend, watcher.as_mut(), &config.group.name, format)?;
+    Ok(report::EXIT_OK)
+}
+
+/// Prints which processes hold each BPF device open.
+pub fn print_holders(backend: &dyn SystemBackend, format: OutputFormat) -> Result<()> {
+    let holders = HolderReport::collect(backend, holders::default_scanner().as_ref())?;
+    match format {
+        OutputFormat::Text => print!("{}", holders.render_text()),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&holders)
+                .context("Failed to serialize the device holders")?
+        ),
+        OutputFormat::Yaml => print!(
+            "{}",
+            serde_yaml::to_string(&holders).context("Failed to serialize the device holders")?
+        ),
+    }
+    Ok(())
+}
+
+/// Prints whether the launch daemon is installed and loaded. Exits with
+/// the daemon-missing status when it is not loaded.
+pub fn print_daemon_status(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    format: OutputFormat,
+) -> Result<u8> {
+    let launchd = DaemonReport::collect(backend, config);
+    match format {
+        OutputFormat::Text => println!("\n{}", launchd.render_text()),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&launchd)
+                .context("Failed to serialize the daemon status")?
+        ),
+        OutputFormat::Yaml => print!(
+            "{}",
+            serde_yaml::to_string(&launchd).context("Failed to serialize the daemon status")?
+        ),
+    }
+    Ok(if launchd.loaded {
+        report::EXIT_OK
     } else {
-        group_tree.push(node_label(
-            &output::get_error_log(output::LOG_LABEL_ERROR, "Group"),
-            None,
-            None,
-        ));
-        group_tree.push(node_label(
-            &output::get_check_error_log(&format!(
-                "Current user is not a member of {group}",
-                group = bpf::BPF_GROUP
-            )),
-            None,
-            None,
-        ));
-    }
-    tree.push(group_tree);
-    info!("Inspecting known daemon configurations");
-    let mut daemon_tree = Tree::new(node_label("Daemon", None, None));
-    match daemon::check_known_daemon_settings() {
-        Ok(plist) => {
-            daemon_tree.push(node_label(
-                &output::get_ok_log(output::LOG_LABEL_OK, "Daemon"),
-                None,
-                None,
-            ));
-            daemon_tree.push(node_label(
-                &output::get_check_ok_log(&format!("Found configuration at {plist}")),
-                None,
-                None,
-            ));
-        }
-        Err(e) => {
-            daemon_tree.push(node_label(
-                &output::get_error_log(output::LOG_LABEL_ERROR, "Daemon"),
-                None,
-                None,
-            ));
-            daemon_tree.push(node_label(
-                &output::get_check_error_log(&e.to_string()),
-                None,
-                None,
-            ));
+        report::EXIT_DAEMON_MISSING
+    })
+}
+
+/// Prints every finding with its remediation, then offers to apply the fixes
+/// chmod-bpf can make itself. Returns the exit status `check` would return
+/// afterwards.
+pub fn run_doctor(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    daemon: &LaunchdPlist,
+    format: OutputFormat,
+    auto_confirm: bool,
+) -> Result<u8> {
+    let diagnosis = Diagnosis::collect(backend, config)?;
+    if format == OutputFormat::Text {
+        print!("{}", diagnosis.render_text());
+    }
+    let fixes = diagnosis.fixes();
+    let interactive = format == OutputFormat::Text && std::io::stdin().is_terminal();
+    let mut applied = 0;
+    if !fixes.is_empty() && (auto_confirm || interactive) {
+        if backend.require_root().is_err() {
+            info!("Re-run `sudo chmod-bpf doctor` to apply the fixes");
+        } else {
+            for fix in &fixes {
+                let command = fix.command(backend)?;
+                if !confirm_or_skip(auto_confirm, &format!("{fix}? ({command})"))? {
+                    continue;
+                }
+                match fix.apply(backend, config, daemon) {
+                    Ok(()) => {
+                        info!("Applied fix: {fix}");
+                        applied += 1;
+                    }
+                    Err(error) => warn!(?error, "Fix failed: {fix}"),
+                }
+            }
         }
     }
-    tree.push(daemon_tree);
 
-    let timestamp = Local::now().to_rfc2822();
-    tree.push(node_label("Checked at", Some(&timestamp), Some(" :")));
+    for finding in &diagnosis.findings {
+        if let (Some(fix), Some(command)) = (&finding.fix, &finding.command)
+            && !fix.is_safe()
+        {
+            info!(
+                "Not applied by doctor, as it cannot be undone: {fix}. Run `{command}` to do it."
+            );
+        }
+    }
 
-    println!("\n{}", tree);
-    Ok(())
+    let diagnosis = if applied > 0 {
+        let after = Diagnosis::collect(backend, config)?;
+        if format == OutputFormat::Text {
+            print!("{}", after.render_text());
+        }
+        after
+    } else {
+        diagnosis
+    };
+    match format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => println!("{}", diagnosis.render_json()?),
+        OutputFormat::Yaml => print!("{}", diagnosis.render_yaml()?),
+    }
+    Ok(diagnosis.exit_code)
 }
 
-pub fn install_daemon(auto_confirm: bool) -> Result<()> {
-    user::require_root()?;
+pub fn install_daemon(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    daemon: &LaunchdPlist,
+    auto_confirm: bool,
+) -> Result<()> {
+    backend.require_root()?;
     if !confirm_or_skip(auto_confirm, "Install the chmod-bpf launch daemon?")? {
         info!("Installation cancelled by the operator");
         return Ok(());
     }
 
     info!("Ensuring BPF devices are accessible");
-    if let Err(error) = bpf::check_all_bpf_device_permissions() {
+    if let Err(error) = bpf::check_all_bpf_device_permissions(backend) {
         warn!(
             ?error,
             "BPF device permissions check failed; continuing with installation"
         );
     }
 
-    if user::group_exists(bpf::BPF_GROUP) {
-        info!("Group {group} already exists", group = bpf::BPF_GROUP);
-    } else {
-        let gid = user::get_free_gid(user::MIN_GID)?;
-        user::create_group(bpf::BPF_GROUP, bpf::BPF_GROUP_NAME, gid)?;
-        info!(
-            "Created group {group} with gid {gid}",
-            group = bpf::BPF_GROUP
-        );
+    plan::install_plan(backend, config, daemon)?.execute(backend)?;
+    info!("Installation completed successfully");
+    verify_installed_assets(backend, config);
+    Ok(())
+}
+
+/// Replaces Wireshark's ChmodBPF daemon with chmod-bpf and lists what
+/// changed.
+pub fn migrate_from_wireshark(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    daemon: &LaunchdPlist,
+    auto_confirm: bool,
+) -> Result<()> {
+    backend.require_root()?;
+    let plan = plan::migrate_plan(backend, config, daemon)?;
+    if !confirm_or_skip(
+        auto_confirm,
+        "Replace Wireshark's ChmodBPF launch daemon with chmod-bpf?",
+    )? {
+        info!("Migration cancelled by the operator");
+        return Ok(());
     }
 
-    user::add_group_to_group("admin", bpf::BPF_GROUP)?;
-    user::add_current_user_to_group(bpf::BPF_GROUP)?;
-
-    std::fs::create_dir_all(resource::CHMOD_BPF_SCRIPT_DIR_PATH).with_context(|| {
-        format!(
-            "Failed to create script directory at {}",
-            resource::CHMOD_BPF_SCRIPT_DIR_PATH
-        )
-    })?;
-    std::fs::write(resource::CHMOD_BPF_SCRIPT_PATH, resource::CHMOD_BPF_SCRIPT).with_context(
-        || {
-            format!(
-                "Failed to write script to {}",
-                resource::CHMOD_BPF_SCRIPT_PATH
-            )
-        },
-    )?;
-
-    permission::set_owner_group_recursive(resource::CHMOD_BPF_SCRIPT_DIR_PATH)?;
-    permission::set_read_execute_permissions_recursive(resource::CHMOD_BPF_SCRIPT_DIR_PATH)?;
-
-    std::fs::write(resource::CHMOD_BPF_PLIST_PATH, resource::CHMOD_BPF_PLIST).with_context(
-        || {
-            format!(
-                "Failed to write plist to {}",
-                resource::CHMOD_BPF_PLIST_PATH
-            )
-        },
-    )?;
-    permission::set_read_write_permissions(resource::CHMOD_BPF_PLIST_PATH)?;
-    permission::set_owner_group(resource::CHMOD_BPF_PLIST_PATH)?;
-
-    daemon::reload_daemon(resource::CHMOD_BPF_PLIST_PATH)?;
-    info!("Installation completed successfully");
+    plan.execute(backend)?;
+    info!("Migration completed successfully");
+    verify_installed_assets(backend, config);
+    print!(
+        "Migrated from Wireshark's ChmodBPF; {} change(s) made:\n{}",
+        plan.actions.len(),
+        plan.render_steps()
+    );
     Ok(())
 }
 
-pub fn uninstall_daemon(auto_confirm: bool) -> Result<()> {
-    user::require_root()?;
+/// Prints the migration plan without changing anything.
+pub fn print_migrate_plan(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    daemon: &LaunchdPlist,
+    format: OutputFormat,
+) -> Result<()> {
+    print_plan(&plan::migrate_plan(backend, config, daemon)?, format)
+}
+
+pub fn uninstall_daemon(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    auto_confirm: bool,
+) -> Result<()> {
+    backend.require_root()?;
     if !confirm_or_skip(auto_confirm, "Uninstall the chmod-bpf launch daemon?")? {
         info!("Uninstallation cancelled by the operator");
         return Ok(());
     }
 
-    daemon::unload_daemon(resource::CHMOD_BPF_PLIST_PATH)?;
+    plan::uninstall_plan(backend, config)?.execute(backend)?;
+    info!("Uninstallation completed successfully");
+    Ok(())
+}
 
-    if user::group_exists(bpf::BPF_GROUP) {
-        user::delete_group(bpf::BPF_GROUP)?;
-        info!("Removed group {group}", group = bpf::BPF_GROUP);
-    } else {
-        info!("Group {group} was not present", group = bpf::BPF_GROUP);
+/// Prints the users and nested groups in the BPF group.
+pub fn list_group_members(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    format: OutputFormat,
+) -> Result<()> {
+    let group = &config.group.name;
+    if !backend.group_exists(group) {
+        bail!("Group {group} does not exist; run `sudo chmod-bpf install` first");
     }
-
-    match std::fs::remove_dir_all(resource::CHMOD_BPF_SCRIPT_DIR_PATH) {
-        Ok(_) => info!(
-            "Removed script directory at {path}",
-            path = resource::CHMOD_BPF_SCRIPT_DIR_PATH
+    let current = backend.group_members(group)?;
+    match format {
+        OutputFormat::Text => print!("{}", members::render_diff(group, &current, &current)),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&current)
+                .context("Failed to serialize the group members")?
+        ),
+        OutputFormat::Yaml => print!(
+            "{}",
+            serde_yaml::to_string(&current).context("Failed to serialize the group members")?
         ),
-        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
-            info!("No script directory to remove")
-        }
-        Err(error) => {
-            return Err(anyhow!(
-                "Failed to delete script directory at {}: {error}",
-                resource::CHMOD_BPF_SCRIPT_DIR_PATH
-            ));
-        }
     }
+    Ok(())
+}
 
-    match std::fs::remove_file(resource::CHMOD_BPF_PLIST_PATH) {
-        Ok(_) => info!(
-            "Removed plist at {path}",
-            path = resource::CHMOD_BPF_PLIST_PATH
-        ),
-        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
-            info!("No plist file to remove")
-        }
-        Err(error) => {
-            return Err(anyhow!(
-                "Failed to delete plist at {}: {error}",
-                resource::CHMOD_BPF_PLIST_PATH
-            ));
-        }
+/// Adds or removes members of the BPF group and prints the membership
+/// before and after. Members already in the requested state are skipped.
+pub fn change_group_members(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    requested: &[Member],
+    change: Change,
+    auto_confirm: bool,
+) -> Result<()> {
+    backend.require_root()?;
+    let group = &config.group.name;
+    let plan = members::membership_plan(backend, config, requested, change)?;
+    let before = backend.group_members(group)?;
+    if plan.actions.is_empty() {
+        info!("Nothing to change; the membership of {group} is already as requested");
+        print!("{}", members::render_diff(group, &before, &before));
+        return Ok(());
     }
 
-    info!("Uninstallation completed successfully");
+    let names: Vec<String> = requested.iter().map(Member::to_string).collect();
+    let prompt = match change {
+        Change::Add => format!("Add {} to {group}?", names.join(", ")),
+        Change::Remove => format!("Remove {} from {group}?", names.join(", ")),
+    };
+    if !confirm_or_skip(auto_confirm, &prompt)? {
+        info!("Membership change cancelled by the operator");
+        return Ok(());
+    }
+
+    plan.execute(backend)?;
+    let after = backend.group_members(group)?;
+    print!("{}", members::render_diff(group, &before, &after));
+    if change == Change::Add {
+        info!("Added users get access after they log out and back in");
+    }
+    Ok(())
+}
+
+/// Pre-creates BPF devices and grants the BPF group access. Run by the
+/// launch daemon at boot.
+pub fn apply_device_permissions(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    count: Option<u32>,
+) -> Result<()> {
+    backend.require_root()?;
+    let count = count.unwrap_or(config.devices.count);
+    bpf::apply_device_permissions(backend, &config.group.name, count)?;
+    info!("BPF device permissions applied");
+    Ok(())
+}
+
+/// Prints the install plan without changing anything.
+pub fn print_install_plan(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    daemon: &LaunchdPlist,
+    format: OutputFormat,
+) -> Result<()> {
+    print_plan(&plan::install_plan(backend, config, daemon)?, format)
+}
+
+/// Prints the uninstall plan without changing anything.
+pub fn print_uninstall_plan(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    format: OutputFormat,
+) -> Result<()> {
+    print_plan(&plan::uninstall_plan(backend, config)?, format)
+}
+
+fn print_plan(plan: &Plan, format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Text => print!("{}", plan.render_text()),
+        OutputFormat::Json => println!("{}", plan.render_json()?),
+        OutputFormat::Yaml => print!("{}", plan.render_yaml()?),
+    }
     Ok(())
 }
 
+/// Audits the paths to the freshly installed assets and warns about anything
+/// a non-root user could use to change what the daemon runs.
+fn verify_installed_assets(backend: &dyn SystemBackend, config: &Config) {
+    info!("Verifying the owners and modes of the installed assets");
+    match security::audit_installed_assets(backend, config) {
+        Ok(findings) => {
+            let risky: Vec<_> = findings
+                .iter()
+                .filter(|finding| finding.severity > Severity::Low)
+                .collect();
+            if risky.is_empty() {
+                info!("Installed assets are only writable by root");
+            }
+            for finding in risky {
+                warn!("{finding}");
+            }
+        }
+        Err(error) => warn!(?error, "Could not verify the installed assets"),
+    }
+}
+
 fn confirm_or_skip(auto_confirm: bool, message: &str) -> Result<bool> {
     if auto_confirm {
         return Ok(true);
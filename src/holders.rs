@@ -0,0 +1,332 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{backend::SystemBackend, output};
+
+/// A file descriptor some process holds open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenFile {
+    pub pid: u32,
+    pub command: String,
+    pub uid: u32,
+    pub path: PathBuf,
+}
+
+/// Processes whose descriptors could be listed, and how many could not.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessScan {
+    pub open_files: Vec<OpenFile>,
+    /// Processes skipped because their descriptors are not readable, usually
+    /// because they belong to another user.
+    pub unreadable: usize,
+}
+
+/// Lists the files open in running processes.
+pub trait ProcessScanner {
+    fn scan(&self, prefix: &str) -> Result<ProcessScan>;
+}
+
+/// Reads `/proc/<pid>/fd` symlinks. The root is configurable so a fixture
+/// directory with the same layout can stand in for `/proc`.
+pub struct ProcFsScanner {
+    root: PathBuf,
+}
+
+impl ProcFsScanner {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl Default for ProcFsScanner {
+    fn default() -> Self {
+        Self::new("/proc")
+    }
+}
+
+impl ProcessScanner for ProcFsScanner {
+    /// Returns the descriptors whose target starts with `prefix`.
+    fn scan(&self, prefix: &str) -> Result<ProcessScan> {
+        let mut scan = ProcessScan::default();
+        let entries = fs::read_dir(&self.root)
+            .with_context(|| format!("Failed to list {}", self.root.display()))?;
+        for entry in entries.flatten() {
+            let Some(pid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+            else {
+                continue;
+            };
+            let process_dir = entry.path();
+            // Processes can exit while being scanned.
+            let Ok(fds) = fs::read_dir(process_dir.join("fd")) else {
+                if process_dir.exists() {
+                    scan.unreadable += 1;
+                }
+                continue;
+            };
+            let mut targets: Vec<PathBuf> = fds
+                .flatten()
+                .filter_map(|fd| fs::read_link(fd.path()).ok())
+                .filter(|target| target.to_string_lossy().starts_with(prefix))
+                .collect();
+            if targets.is_empty() {
+                continue;
+            }
+            targets.sort();
+            targets.dedup();
+            let command = fs::read_to_string(process_dir.join("comm"))
+                .map(|comm| comm.trim_end().to_string())
+                .unwrap_or_default();
+            let uid = fs::read_to_string(process_dir.join("status"))
+                .ok()
+                .and_then(|status| parse_status_uid(&status))
+                .unwrap_or(u32::MAX);
+            for path in targets {
+                scan.open_files.push(OpenFile {
+                    pid,
+                    command: command.clone(),
+                    uid,
+                    path,
+                });
+            }
+        }
+        Ok(scan)
+    }
+}
+
+/// macOS has no `/proc`; a libproc based scanner is still to be written.
+pub struct UnsupportedScanner;
+
+impl ProcessScanner for UnsupportedScanner {
+    fn scan(&self, _prefix: &str) -> Result<ProcessScan> {
+        anyhow::bail!("Listing open BPF devices is not supported on this platform yet")
+    }
+}
+
+/// The scanner for the platform this binary runs on.
+pub fn default_scanner() -> Box<dyn ProcessScanner> {
+    if cfg!(target_os = "linux") {
+        Box::new(ProcFsScanner::default())
+    } else {
+        Box::new(UnsupportedScanner)
+    }
+}
+
+/// Real uid from the `Uid:` line of `/proc/<pid>/status`.
+pub fn parse_status_uid(status: &str) -> Option<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))
+        .and_then(|ids| ids.split_whitespace().next())
+        .and_then(|uid| uid.parse().ok())
+}
+
+/// A process holding a BPF device open.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Holder {
+    pub pid: u32,
+    pub command: String,
+    pub user: String,
+}
+
+/// Every BPF device that is open, with the processes holding it.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HolderReport {
+    /// Number of BPF devices that exist.
+    pub total: usize,
+    pub devices: BTreeMap<String, Vec<Holder>>,
+    /// Processes that could not be inspected; run with sudo to include them.
+    pub unreadable_processes: usize,
+}
+
+impl HolderReport {
+    pub fn collect(backend: &dyn SystemBackend, scanner: &dyn ProcessScanner) -> Result<Self> {
+        let total = backend.bpf_device_paths()?.len();
+        let scan = scanner.scan("/dev/bpf")?;
+        let mut devices: BTreeMap<String, Vec<Holder>> = BTreeMap::new();
+        for file in scan.open_files {
+            let user = backend
+                .user_name_by_uid(file.uid)
+                .unwrap_or_else(|| file.uid.to_string());
+            devices
+                .entry(file.path.to_string_lossy().into_owned())
+                .or_default()
+                .push(Holder {
+                    pid: file.pid,
+                    command: file.command,
+                    user,
+                });
+        }
+        Ok(Self {
+            total,
+            devices,
+            unreadable_processes: scan.unreadable,
+        })
+    }
+
+    /// Holders of one device, e.g. `1234 tcpdump (root)`.
+    pub fn describe(&self, path: &Path) -> Option<String> {
+        let holders = self.devices.get(path.to_string_lossy().as_ref())?;
+        Some(
+            holders
+                .iter()
+                .map(|holder| format!("{} {} ({})", holder.pid, holder.command, holder.user))
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+    }
+
+    pub fn render_text(&self) -> String {
+        let mut text = format!(
+            "{} of {} BPF device(s) are open\n",
+            self.devices.len(),
+            self.total
+        );
+        if !self.devices.is_empty() {
+            let rows: Vec<[String; 4]> = self
+                .devices
+                .iter()
+                .flat_map(|(path, holders)| {
+                    holders.iter().map(move |holder| {
+                        [
+                            path.clone(),
+                            holder.pid.to_string(),
+                            holder.user.clone(),
+                            holder.command.clone(),
+                        ]
+                    })
+                })
+                .collect();
+            text.push_str(&output::render_table(
+                ["DEVICE", "PID", "USER", "COMMAND"],
+                rows,
+            ));
+        }
+        if self.unreadable_processes > 0 {
+            text.push_str(&format!(
+                "{} process(es) could not be inspected; run with sudo to include them\n",
+                self.unreadable_processes
+            ));
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{FileMetadata, MemoryBackend, MemoryDevice};
+    use crate::test_support::TempDir;
+    use std::os::unix::fs::symlink;
+
+    /// Lays out `<pid>/fd/<n>`, `<pid>/comm` and `<pid>/status` like /proc.
+    fn add_process(proc: &TempDir, pid: u32, comm: &str, uid: u32, fds: &[&str]) {
+        let dir = proc.join(&pid.to_string());
+        fs::create_dir_all(dir.join("fd")).unwrap();
+        fs::write(dir.join("comm"), format!("{comm}\n")).unwrap();
+        fs::write(
+            dir.join("status"),
+            format!(
+                "Name:\t{comm}\nPid:\t{pid}\nUid:\t{uid}\t{uid}\t{uid}\t{uid}\nGid:\t0\t0\t0\t0\n"
+            ),
+        )
+        .unwrap();
+        for (fd, target) in fds.iter().enumerate() {
+            symlink(target, dir.join(format!("fd/{fd}"))).unwrap();
+        }
+    }
+
+    #[test]
+    fn groups_holders_by_device() {
+        let proc = TempDir::new();
+        add_process(
+            &proc,
+            120,
+            "tcpdump",
+            0,
+            &["/dev/null", "/dev/bpf0", "/dev/bpf0"],
+        );
+        add_process(&proc, 340, "Wireshark", 501, &["/dev/bpf1", "/dev/ttys000"]);
+        add_process(&proc, 560, "dumpcap", 501, &["/dev/bpf0"]);
+        add_process(&proc, 780, "zsh", 501, &["/dev/ttys000"]);
+        // A process whose descriptors cannot be listed.
+        fs::create_dir(proc.join("901")).unwrap();
+        fs::create_dir(proc.join("self")).unwrap();
+
+        let backend = MemoryBackend::root("alice");
+        for index in 0..3 {
+            backend.state.borrow_mut().devices.insert(
+                PathBuf::from(format!("/dev/bpf{index}")),
+                MemoryDevice {
+                    metadata: FileMetadata {
+                        uid: 0,
+                        gid: 0,
+                        mode: 0o020600,
+                    },
+                    accessible: true,
+                },
+            );
+        }
+        let mut report = HolderReport::collect(&backend, &ProcFsScanner::new(proc.path())).unwrap();
+        for holders in report.devices.values_mut() {
+            holders.sort_by_key(|holder| holder.pid);
+        }
+
+        let holder = |pid, command: &str, user: &str| Holder {
+            pid,
+            command: command.to_string(),
+            user: user.to_string(),
+        };
+        assert_eq!(report.total, 3);
+        assert_eq!(report.unreadable_processes, 1);
+        assert_eq!(
+            report.devices,
+            BTreeMap::from([
+                (
+                    "/dev/bpf0".to_string(),
+                    vec![
+                        holder(120, "tcpdump", "root"),
+                        holder(560, "dumpcap", "501")
+                    ]
+                ),
+                (
+                    "/dev/bpf1".to_string(),
+                    vec![holder(340, "Wireshark", "501")]
+                ),
+            ])
+        );
+        assert_eq!(
+            report.describe(Path::new("/dev/bpf0")).as_deref(),
+            Some("120 tcpdump (root), 560 dumpcap (501)")
+        );
+        assert_eq!(report.describe(Path::new("/dev/bpf2")), None);
+        assert!(
+            report
+                .render_text()
+                .starts_with("2 of 3 BPF device(s) are open\n")
+        );
+    }
+
+    #[test]
+    fn parse_status_uid_reads_the_real_uid() {
+        let cases = [
+            (
+                "Name:\tzsh\nUid:\t501\t0\t0\t0\nGid:\t20\t20\t20\t20\n",
+                Some(501),
+            ),
+            ("Uid:\t0\t0\t0\t0", Some(0)),
+            ("Name:\tzsh\nGid:\t20\t20\t20\t20\n", None),
+            ("Uid:\n", None),
+            ("Uid:\tnobody\t0\t0\t0\n", None),
+            ("", None),
+        ];
+        for (status, expected) in cases {
+            assert_eq!(parse_status_uid(status), expected, "{status:?}");
+        }
+    }
+}
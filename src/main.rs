@@ -1,20 +1,66 @@
+mod access;
+mod backend;
 mod bpf;
+mod ci_report;
 mod command;
+mod config;
 mod daemon;
+mod doctor;
+mod drift;
 mod handler;
+mod holders;
+mod manifest;
+mod members;
 mod output;
 mod permission;
+mod plan;
+mod plist;
+mod report;
 mod resource;
+mod security;
+#[cfg(test)]
+mod test_support;
+mod transaction;
 mod user;
+mod watch;
 
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use backend::MacOsBackend;
+use ci_report::ReportTarget;
+use clap::{Args, Parser, Subcommand};
+use config::Config;
+use members::{Change, Member};
+use output::OutputFormat;
+use plist::LaunchdPlist;
+use report::CheckOptions;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
 use tracing::{error, info};
 use tracing_subscriber::{filter::EnvFilter, fmt::time::ChronoLocal};
+use user::GidRange;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    /// Configuration file to use instead of /etc/chmod-bpf.toml.
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Group that owns the BPF devices [default: group.name from the config file].
+    #[arg(long, global = true, value_name = "NAME")]
+    group: Option<String>,
+    /// Display name of the group created by install
+    /// [default: group.display_name from the config file].
+    #[arg(long, global = true, value_name = "NAME")]
+    group_display_name: Option<String>,
+    /// Create the group with this gid; fails if another group already uses it
+    /// [default: group.gid from the config file].
+    #[arg(long, global = true, value_name = "GID", conflicts_with = "gid_range")]
+    gid: Option<u32>,
+    /// Pick the group's gid from this inclusive range, e.g. 600..699
+    /// [default: group.min_gid and group.max_gid from the config file].
+    #[arg(long, global = true, value_name = "START..END")]
+    gid_range: Option<GidRange>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -22,38 +68,326 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Audit BPF permissions, group membership, and daemon configurations.
-    Check,
+    Check {
+        /// Output format for the report.
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+        /// List every BPF device with its owner, group, mode and verdict.
+        #[arg(long)]
+        devices: bool,
+        /// Exit with a failure status on warnings as well as errors.
+        #[arg(long)]
+        strict: bool,
+        /// Show a unified diff for installed files that drifted.
+        #[arg(long)]
+        diff: bool,
+        /// Keep running and report each BPF device that drifts from the
+        /// expected group and mode (JSON lines with --format json).
+        #[arg(long, conflicts_with_all = ["devices", "strict", "diff", "reports", "security"])]
+        watch: bool,
+        /// Audit owners, modes, symlinks and extra files on every directory
+        /// leading to the installed binary and plist.
+        #[arg(long)]
+        security: bool,
+        /// Under sudo, ask the kernel for access as the invoking user by
+        /// temporarily switching to their credentials, so every ACL counts.
+        #[arg(long, conflicts_with = "watch")]
+        impersonate: bool,
+        /// Also write the results for CI as `junit=PATH` or `sarif=PATH` (repeatable).
+        #[arg(long = "report", value_name = "KIND=PATH")]
+        reports: Vec<ReportTarget>,
+        /// Seconds between re-checks in watch mode, even without change events.
+        #[arg(long, value_name = "SECONDS", default_value_t = 5, requires = "watch")]
+        interval: u64,
+    },
+    /// Show whether the launch daemon is loaded, its run count and last exit code.
+    Status {
+        /// Output format for the status.
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// List the processes holding BPF devices open.
+    Holders {
+        /// Output format for the list.
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Diagnose problems, suggest remediations and offer to apply the safe fixes.
+    Doctor {
+        /// Output format for the findings.
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+        /// Apply every available fix without prompting.
+        #[arg(short = 'y', long = "yes")]
+        assume_yes: bool,
+        /// Launch daemon settings used when a fix installs chmod-bpf.
+        #[command(flatten)]
+        daemon: DaemonArgs,
+    },
     /// Install the helper launch daemon and supporting assets.
     Install {
         /// Skip interactive confirmation prompts.
         #[arg(short = 'y', long = "yes")]
         assume_yes: bool,
+        /// Print the planned actions without changing anything.
+        #[arg(long)]
+        dry_run: bool,
+        /// Output format for the dry-run plan.
+        #[arg(long, value_enum, default_value_t, requires = "dry_run")]
+        format: OutputFormat,
+        #[command(flatten)]
+        daemon: DaemonArgs,
+    },
+    /// Replace Wireshark's ChmodBPF launch daemon with chmod-bpf, reusing its group.
+    Migrate {
+        /// Skip interactive confirmation prompts.
+        #[arg(short = 'y', long = "yes")]
+        assume_yes: bool,
+        /// Print the planned actions without changing anything.
+        #[arg(long)]
+        dry_run: bool,
+        /// Output format for the dry-run plan.
+        #[arg(long, value_enum, default_value_t, requires = "dry_run")]
+        format: OutputFormat,
+        #[command(flatten)]
+        daemon: DaemonArgs,
+    },
+    /// List, add or remove members of the BPF group.
+    Users {
+        #[command(subcommand)]
+        command: UsersCommand,
+    },
+    /// Pre-create BPF devices and grant the BPF group access (run by the daemon).
+    Apply {
+        /// Number of devices to pre-create, clamped to debug.bpf_maxdevices
+        /// [default: devices.count from the config file].
+        #[arg(long)]
+        count: Option<u32>,
     },
     /// Remove the helper launch daemon and clean up all assets.
     Uninstall {
         /// Skip interactive confirmation prompts.
         #[arg(short = 'y', long = "yes")]
         assume_yes: bool,
+        /// Print the planned actions without changing anything.
+        #[arg(long)]
+        dry_run: bool,
+        /// Output format for the dry-run plan.
+        #[arg(long, value_enum, default_value_t, requires = "dry_run")]
+        format: OutputFormat,
     },
 }
 
-fn main() {
-    if let Err(err) = run() {
-        error!(?err, "Command failed");
-        std::process::exit(1);
+#[derive(Subcommand)]
+enum UsersCommand {
+    /// List the users and nested groups in the BPF group.
+    List {
+        /// Output format for the members.
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Add users, or nested groups as `group:NAME`, to the BPF group.
+    Add {
+        #[arg(required = true, value_name = "NAME")]
+        members: Vec<Member>,
+        /// Skip interactive confirmation prompts.
+        #[arg(short = 'y', long = "yes")]
+        assume_yes: bool,
+    },
+    /// Remove users, or nested groups as `group:NAME`, from the BPF group.
+    Remove {
+        #[arg(required = true, value_name = "NAME")]
+        members: Vec<Member>,
+        /// Skip interactive confirmation prompts.
+        #[arg(short = 'y', long = "yes")]
+        assume_yes: bool,
+    },
+}
+
+/// Launch daemon settings written to the generated plist.
+#[derive(Args)]
+struct DaemonArgs {
+    /// Launchd label; the plist is written to /Library/LaunchDaemons/<LABEL>.plist.
+    #[arg(long, default_value = plist::DEFAULT_LABEL)]
+    label: String,
+    /// Program the daemon runs with the `apply` argument
+    /// [default: chmod-bpf in paths.support_dir from the config file].
+    #[arg(long, value_name = "PATH")]
+    program: Option<String>,
+    /// File that receives the daemon's standard output.
+    #[arg(long, value_name = "PATH")]
+    stdout_path: Option<String>,
+    /// File that receives the daemon's standard error.
+    #[arg(long, value_name = "PATH")]
+    stderr_path: Option<String>,
+    /// Re-run the daemon whenever this path changes (repeatable).
+    #[arg(long = "watch-path", value_name = "PATH")]
+    watch_paths: Vec<String>,
+    /// Ask launchd to keep the daemon running.
+    #[arg(long)]
+    keep_alive: bool,
+}
+
+impl DaemonArgs {
+    /// Builds the plist. A non-default config file and a `--group` override
+    /// are passed on to the daemon so `apply` uses the same settings.
+    fn to_plist(
+        &self,
+        config: &Config,
+        config_path: Option<&PathBuf>,
+        group_override: bool,
+    ) -> LaunchdPlist {
+        let mut program_arguments =
+            vec![self.program.clone().unwrap_or_else(|| config.binary_path())];
+        if let Some(path) = config_path {
+            program_arguments.push("--config".to_string());
+            program_arguments.push(path.to_string_lossy().into_owned());
+        }
+        program_arguments.push("apply".to_string());
+        let mut plist = LaunchdPlist {
+            label: self.label.clone(),
+            program_arguments,
+            keep_alive: self.keep_alive.then_some(true),
+            standard_out_path: self.stdout_path.clone(),
+            standard_error_path: self.stderr_path.clone(),
+            watch_paths: self.watch_paths.clone(),
+            ..LaunchdPlist::default()
+        };
+        if group_override {
+            plist.set_apply_group(&config.group.name);
+        }
+        plist
     }
 }
 
-fn run() -> Result<()> {
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => ExitCode::from(code),
+        Err(err) => {
+            error!(?err, "Command failed");
+            ExitCode::from(report::EXIT_INTERNAL_ERROR)
+        }
+    }
+}
+
+/// Runs the subcommand and returns the process exit status.
+fn run() -> Result<u8> {
     init_tracing();
     let cli = Cli::parse();
 
     info!("Launching chmod-bpf");
-    match cli.command {
-        Commands::Check => handler::check_bpf_devices(),
-        Commands::Install { assume_yes } => handler::install_daemon(assume_yes),
-        Commands::Uninstall { assume_yes } => handler::uninstall_daemon(assume_yes),
+    let mut config = Config::load(cli.config.as_deref())?;
+    config.override_group(cli.group.as_deref(), cli.group_display_name.as_deref())?;
+    config.override_gid(cli.gid, cli.gid_range)?;
+    if let Some(source) = &config.source {
+        info!("Loaded configuration from {}", source.display());
     }
+    let backend = MacOsBackend;
+    match cli.command {
+        Commands::Check {
+            format,
+            devices,
+            strict,
+            diff,
+            watch,
+            security,
+            impersonate,
+            reports,
+            interval,
+        } => {
+            if watch {
+                return handler::watch_bpf_devices(
+                    &backend,
+                    &config,
+                    Duration::from_secs(interval),
+                    format,
+                );
+            }
+            return handler::check_bpf_devices(
+                &backend,
+                &config,
+                CheckOptions {
+                    devices,
+                    strict,
+                    diff,
+                    security,
+                    impersonate,
+                },
+                format,
+                &reports,
+            );
+        }
+        Commands::Status { format } => {
+            return handler::print_daemon_status(&backend, &config, format);
+        }
+        Commands::Holders { format } => handler::print_holders(&backend, format),
+        Commands::Doctor {
+            format,
+            assume_yes,
+            daemon,
+        } => {
+            let daemon = daemon.to_plist(&config, cli.config.as_ref(), cli.group.is_some());
+            return handler::run_doctor(&backend, &config, &daemon, format, assume_yes);
+        }
+        Commands::Install {
+            assume_yes,
+            dry_run,
+            format,
+            daemon,
+        } => {
+            let daemon = daemon.to_plist(&config, cli.config.as_ref(), cli.group.is_some());
+            if dry_run {
+                handler::print_install_plan(&backend, &config, &daemon, format)
+            } else {
+                handler::install_daemon(&backend, &config, &daemon, assume_yes)
+            }
+        }
+        Commands::Migrate {
+            assume_yes,
+            dry_run,
+            format,
+            daemon,
+        } => {
+            let daemon = daemon.to_plist(&config, cli.config.as_ref(), cli.group.is_some());
+            if dry_run {
+                handler::print_migrate_plan(&backend, &config, &daemon, format)
+            } else {
+                handler::migrate_from_wireshark(&backend, &config, &daemon, assume_yes)
+            }
+        }
+        Commands::Users { command } => match command {
+            UsersCommand::List { format } => handler::list_group_members(&backend, &config, format),
+            UsersCommand::Add {
+                members,
+                assume_yes,
+            } => {
+                handler::change_group_members(&backend, &config, &members, Change::Add, assume_yes)
+            }
+            UsersCommand::Remove {
+                members,
+                assume_yes,
+            } => handler::change_group_members(
+                &backend,
+                &config,
+                &members,
+                Change::Remove,
+                assume_yes,
+            ),
+        },
+        Commands::Apply { count } => handler::apply_device_permissions(&backend, &config, count),
+        Commands::Uninstall {
+            assume_yes,
+            dry_run,
+            format,
+        } => {
+            if dry_run {
+                handler::print_uninstall_plan(&backend, &config, format)
+            } else {
+                handler::uninstall_daemon(&backend, &config, assume_yes)
+            }
+        }
+    }?;
+    Ok(report::EXIT_OK)
 }
 
 fn init_tracing() {
@@ -62,6 +396,7 @@ fn init_tracing() {
     tracing_subscriber::fmt()
         .with_env_filter(env_filter)
         .with_target(false)
+        .with_writer(std::io::stderr)
         .with_timer(ChronoLocal::new("%Y-%m-%d %H:%M:%S".into()))
         .init();
 }
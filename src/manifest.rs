@@ -0,0 +1,129 @@
+use anyhow::{Context, Result};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+use crate::{backend::SystemBackend, resource};
+
+/// A file written by install, with the hash of the contents that were written.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestFile {
+    pub path: String,
+    pub sha256: String,
+}
+
+/// Record of what an install created, so uninstall only removes that.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    pub installed_at: String,
+    pub group: String,
+    pub gid: u32,
+    /// Whether the group existed before chmod-bpf was first installed.
+    pub group_preexisted: bool,
+    pub files: Vec<ManifestFile>,
+    /// Launch daemon definition written by install.
+    #[serde(default = "default_plist_path")]
+    pub plist_path: String,
+    /// Users that chmod-bpf added to the group.
+    pub users_added: Vec<String>,
+    /// Groups that chmod-bpf nested in the group.
+    pub groups_added: Vec<String>,
+    /// Whether install created the support directory, so uninstall may
+    /// remove it once it is empty. Unset in manifests written before this was
+    /// recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub support_dir_created: Option<bool>,
+}
+
+impl Manifest {
+    pub fn new(group: &str, gid: u32, group_preexisted: bool, plist_path: &str) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            installed_at: Local::now().to_rfc3339(),
+            group: group.to_string(),
+            gid,
+            group_preexisted,
+            files: Vec::new(),
+            plist_path: plist_path.to_string(),
+            users_added: Vec::new(),
+            groups_added: Vec::new(),
+            support_dir_created: None,
+        }
+    }
+
+    /// Whether install created `support_dir`. Older releases always installed
+    /// into the default support directory, which they created.
+    pub fn created_support_dir(&self, support_dir: &str) -> bool {
+        self.support_dir_created
+            .unwrap_or_else(|| is_default_support_dir(support_dir))
+    }
+
+    pub fn add_file(&mut self, path: &str, contents: &[u8]) {
+        self.files.push(ManifestFile {
+            path: path.to_string(),
+            sha256: sha256_hex(contents),
+        });
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("Failed to serialize the install manifest")
+    }
+}
+
+/// Whether `support_dir` is the default support directory, regardless of a
+/// trailing slash.
+pub fn is_default_support_dir(support_dir: &str) -> bool {
+    Path::new(support_dir) == Path::new(resource::CHMOD_BPF_SUPPORT_DIR_PATH)
+}
+
+pub fn default_plist_path() -> String {
+    resource::CHMOD_BPF_PLIST_PATH.to_string()
+}
+
+/// Loads the manifest left by a previous install at `path`, if any.
+pub fn load(backend: &dyn SystemBackend, path: &str) -> Result<Option<Manifest>> {
+    let Some(contents) = backend.read_file(path)? else {
+        return Ok(None);
+    };
+    let manifest = serde_json::from_slice(&contents)
+        .with_context(|| format!("Failed to parse the install manifest at {path}"))?;
+    Ok(Some(manifest))
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn support_dir_defaults_compare_as_paths() {
+        let cases = [
+            (resource::CHMOD_BPF_SUPPORT_DIR_PATH.to_string(), true),
+            (format!("{}/", resource::CHMOD_BPF_SUPPORT_DIR_PATH), true),
+            ("/opt/chmod-bpf".to_string(), false),
+        ];
+        let manifest = Manifest::new("access_bpf", 600, false, &default_plist_path());
+        for (support_dir, default) in cases {
+            assert_eq!(
+                is_default_support_dir(&support_dir),
+                default,
+                "{support_dir}"
+            );
+            assert_eq!(
+                manifest.created_support_dir(&support_dir),
+                default,
+                "{support_dir}"
+            );
+        }
+        let recorded = Manifest {
+            support_dir_created: Some(false),
+            ..manifest
+        };
+        assert!(!recorded.created_support_dir(resource::CHMOD_BPF_SUPPORT_DIR_PATH));
+    }
+}
@@ -0,0 +1,444 @@
+use anyhow::{Result, bail};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{
+    backend::SystemBackend,
+    config::Config,
+    manifest,
+    plan::{Action, Plan},
+    user::GroupMembers,
+};
+
+/// Prefix that marks a nested group on the command line, e.g. `group:admin`.
+pub const GROUP_PREFIX: &str = "group:";
+
+/// A user account or nested group to add to or remove from the BPF group.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Member {
+    User(String),
+    Group(String),
+}
+
+impl FromStr for Member {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (member, name) = match value.strip_prefix(GROUP_PREFIX) {
+            Some(name) => (Member::Group(name.to_string()), name),
+            None => (Member::User(value.to_string()), value),
+        };
+        if name.is_empty() {
+            return Err("the member name is empty".to_string());
+        }
+        if name
+            .chars()
+            .any(|c| c.is_whitespace() || c == ':' || c == '/')
+        {
+            return Err(format!("{name:?} is not a valid account name"));
+        }
+        Ok(member)
+    }
+}
+
+impl fmt::Display for Member {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Member::User(name) => f.write_str(name),
+            Member::Group(name) => write!(f, "{GROUP_PREFIX}{name}"),
+        }
+    }
+}
+
+impl Member {
+    fn is_in(&self, members: &GroupMembers) -> bool {
+        match self {
+            Member::User(name) => members.users.contains(name),
+            Member::Group(name) => members.groups.contains(name),
+        }
+    }
+}
+
+/// Whether members are being added or removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Add,
+    Remove,
+}
+
+/// Builds the plan that adds or removes the members that are not already in
+/// the requested state, and keeps the install manifest's record of added
+/// members in step so uninstall removes exactly what chmod-bpf added.
+pub fn membership_plan(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    members: &[Member],
+    change: Change,
+) -> Result<Plan> {
+    let group = config.group.name.as_str();
+    if !backend.group_exists(group) {
+        bail!("Group {group} does not exist; run `sudo chmod-bpf install` first");
+    }
+    let unknown: Vec<String> = members
+        .iter()
+        .filter(|member| match member {
+            Member::User(name) => !backend.user_exists(name),
+            Member::Group(name) => !backend.group_exists(name),
+        })
+        .map(|member| match member {
+            Member::User(name) => format!("user {name}"),
+            Member::Group(name) => format!("group {name}"),
+        })
+        .collect();
+    if !unknown.is_empty() {
+        bail!("No such account: {}", unknown.join(", "));
+    }
+    if members.contains(&Member::Group(group.to_string())) {
+        bail!("Group {group} cannot be nested in itself");
+    }
+
+    let current = backend.group_members(group)?;
+    let mut pending: Vec<&Member> = members
+        .iter()
+        .filter(|member| member.is_in(&current) == (change == Change::Remove))
+        .collect();
+    pending.sort();
+    pending.dedup();
+
+    let mut actions: Vec<Action> = pending
+        .iter()
+        .map(|member| match (change, member) {
+            (Change::Add, Member::User(user)) => Action::AddUserToGroup {
+                user: user.clone(),
+                group: group.to_string(),
+            },
+            (Change::Add, Member::Group(nested)) => Action::AddGroupToGroup {
+                group: nested.clone(),
+                target_group: group.to_string(),
+            },
+            (Change::Remove, Member::User(user)) => Action::RemoveUserFromGroup {
+                user: user.clone(),
+                group: group.to_string(),
+            },
+            (Change::Remove, Member::Group(nested)) => Action::RemoveGroupFromGroup {
+                group: nested.clone(),
+                target_group: group.to_string(),
+            },
+        })
+        .collect();
+
+    let manifest_path = config.manifest_path();
+    if !actions.is_empty()
+        && let Some(mut manifest) = manifest::load(backend, &manifest_path)?
+        && manifest.group == group
+    {
+        let mut changed = false;
+        for member in &pending {
+            let (added, name) = match member {
+                Member::User(name) => (&mut manifest.users_added, name),
+                Member::Group(name) => (&mut manifest.groups_added, name),
+            };
+            let was_recorded = added.contains(name);
+            match change {
+                // A group chmod-bpf created is deleted on uninstall with its
+                // members, so only a pre-existing group needs the record.
+                Change::Add if manifest.group_preexisted && !was_recorded => {
+                    added.push(name.clone());
+                    changed = true;
+                }
+                Change::Remove if was_recorded => {
+                    added.retain(|added| added != name);
+                    changed = true;
+                }
+                _ => {}
+            }
+        }
+        if changed {
+            actions.push(Action::write_file(
+                &manifest_path,
+                manifest.to_json()?.as_bytes(),
+            ));
+        }
+    }
+
+    Ok(Plan {
+        operation: match change {
+            Change::Add => "users add",
+            Change::Remove => "users remove",
+        },
+        actions,
+    })
+}
+
+/// Membership before and after a change, one member per line: `+` for
+/// added, `-` for removed, unchanged members indented.
+pub fn render_diff(group: &str, before: &GroupMembers, after: &GroupMembers) -> String {
+    let collect = |members: &GroupMembers| {
+        let mut all: Vec<Member> = members
+            .users
+            .iter()
+            .cloned()
+            .map(Member::User)
+            .chain(members.groups.iter().cloned().map(Member::Group))
+            .collect();
+        all.sort();
+        all
+    };
+    let before = collect(before);
+    let after = collect(after);
+    let mut all: Vec<&Member> = before.iter().chain(after.iter()).collect();
+    all.sort();
+    all.dedup();
+
+    let mut text = format!("Members of {group}:\n");
+    if all.is_empty() {
+        text.push_str("  (none)\n");
+    }
+    for member in all {
+        let marker = match (before.contains(member), after.contains(member)) {
+            (false, true) => '+',
+            (true, false) => '-',
+            _ => ' ',
+        };
+        text.push_str(&format!("{marker} {member}\n"));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{MemoryBackend, MemoryGroup};
+    use crate::plist::LaunchdPlist;
+
+    /// An install into a group that already existed, next to other accounts.
+    fn installed(group_preexisted: bool) -> (MemoryBackend, Config) {
+        let backend = MemoryBackend::root("alice");
+        let config = Config::default();
+        {
+            let mut state = backend.state.borrow_mut();
+            state.users.extend(["bob".to_string(), "carol".to_string()]);
+            for (name, gid) in [("staff", 20), ("wheel", 0)] {
+                state.groups.insert(
+                    name.to_string(),
+                    MemoryGroup {
+                        gid,
+                        ..Default::default()
+                    },
+                );
+            }
+            if group_preexisted {
+                state.groups.insert(
+                    config.group.name.clone(),
+                    MemoryGroup {
+                        gid: 300,
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+        crate::plan::install_plan(&backend, &config, &LaunchdPlist::default())
+            .unwrap()
+            .execute(&backend)
+            .unwrap();
+        (backend, config)
+    }
+
+    fn members(names: &[&str]) -> Vec<Member> {
+        names.iter().map(|name| name.parse().unwrap()).collect()
+    }
+
+    fn manifest_of(backend: &MemoryBackend, config: &Config) -> manifest::Manifest {
+        manifest::load(backend, &config.manifest_path())
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_users_and_nested_groups() {
+        let cases = [
+            ("bob", Ok(Member::User("bob".to_string()))),
+            ("group:staff", Ok(Member::Group("staff".to_string()))),
+            ("group:", Err("the member name is empty".to_string())),
+            ("", Err("the member name is empty".to_string())),
+            (
+                "bob smith",
+                Err("\"bob smith\" is not a valid account name".to_string()),
+            ),
+            (
+                "group:a:b",
+                Err("\"a:b\" is not a valid account name".to_string()),
+            ),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(value.parse::<Member>(), expected, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_accounts_and_self_nesting() {
+        let (backend, config) = installed(false);
+        let group = config.group.name.clone();
+        let self_nested = format!("group:{group}");
+        let cases = [
+            (
+                members(&["bob", "mallory", "group:nobody"]),
+                "No such account: user mallory, group nobody".to_string(),
+            ),
+            (
+                members(&["bob", &self_nested]),
+                format!("Group {group} cannot be nested in itself"),
+            ),
+        ];
+        for (members, message) in cases {
+            for change in [Change::Add, Change::Remove] {
+                let error = membership_plan(&backend, &config, &members, change).unwrap_err();
+                assert_eq!(error.to_string(), message, "{members:?} {change:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn requires_the_bpf_group() {
+        let backend = MemoryBackend::root("alice");
+        let config = Config::default();
+        let error =
+            membership_plan(&backend, &config, &members(&["alice"]), Change::Add).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("run `sudo chmod-bpf install` first")
+        );
+    }
+
+    #[test]
+    fn members_already_in_the_requested_state_need_no_actions() {
+        let (backend, config) = installed(true);
+        // Install added the operator.
+        let plan = membership_plan(&backend, &config, &members(&["alice"]), Change::Add).unwrap();
+        assert!(plan.actions.is_empty(), "{plan:?}");
+        let plan = membership_plan(
+            &backend,
+            &config,
+            &members(&["bob", "group:staff"]),
+            Change::Remove,
+        )
+        .unwrap();
+        assert!(plan.actions.is_empty(), "{plan:?}");
+    }
+
+    #[test]
+    fn deduplicates_and_orders_members() {
+        let (backend, config) = installed(false);
+        let group = config.group.name.clone();
+        let plan = membership_plan(
+            &backend,
+            &config,
+            &members(&["group:staff", "carol", "bob", "group:staff", "carol"]),
+            Change::Add,
+        )
+        .unwrap();
+        assert_eq!(plan.operation, "users add");
+        // chmod-bpf created the group, so the manifest is left alone.
+        assert_eq!(
+            plan.actions,
+            [
+                Action::AddUserToGroup {
+                    user: "bob".to_string(),
+                    group: group.clone(),
+                },
+                Action::AddUserToGroup {
+                    user: "carol".to_string(),
+                    group: group.clone(),
+                },
+                Action::AddGroupToGroup {
+                    group: "staff".to_string(),
+                    target_group: group,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn records_members_added_to_a_preexisting_group() {
+        let (backend, config) = installed(true);
+        let before = manifest_of(&backend, &config);
+        // Install added the operator and the admin group.
+        assert_eq!(before.users_added, ["alice"]);
+        assert_eq!(before.groups_added, ["admin"]);
+
+        let plan = membership_plan(
+            &backend,
+            &config,
+            &members(&["bob", "group:staff"]),
+            Change::Add,
+        )
+        .unwrap();
+        assert!(matches!(
+            plan.actions.last(),
+            Some(Action::WriteFile { path, .. }) if *path == config.manifest_path()
+        ));
+        plan.execute(&backend).unwrap();
+        let added = manifest_of(&backend, &config);
+        assert_eq!(added.users_added, ["alice", "bob"]);
+        assert_eq!(added.groups_added, ["admin", "staff"]);
+
+        membership_plan(
+            &backend,
+            &config,
+            &members(&["alice", "group:staff"]),
+            Change::Remove,
+        )
+        .unwrap()
+        .execute(&backend)
+        .unwrap();
+        let removed = manifest_of(&backend, &config);
+        assert_eq!(removed.users_added, ["bob"]);
+        assert_eq!(removed.groups_added, ["admin"]);
+    }
+
+    #[test]
+    fn removing_a_member_install_did_not_add_keeps_the_manifest() {
+        let (backend, config) = installed(true);
+        backend
+            .state
+            .borrow_mut()
+            .groups
+            .get_mut(&config.group.name)
+            .unwrap()
+            .users
+            .insert("carol".to_string());
+        let plan =
+            membership_plan(&backend, &config, &members(&["carol"]), Change::Remove).unwrap();
+        assert_eq!(plan.operation, "users remove");
+        assert_eq!(
+            plan.actions,
+            [Action::RemoveUserFromGroup {
+                user: "carol".to_string(),
+                group: config.group.name.clone(),
+            }]
+        );
+    }
+
+    #[test]
+    fn diff_marks_added_removed_and_unchanged_members() {
+        let group_members = |users: &[&str], groups: &[&str]| GroupMembers {
+            users: users.iter().map(|name| name.to_string()).collect(),
+            groups: groups.iter().map(|name| name.to_string()).collect(),
+        };
+        let before = group_members(&["alice", "bob"], &["staff"]);
+        let after = group_members(&["alice", "carol"], &["staff", "wheel"]);
+        assert_eq!(
+            render_diff("access_bpf", &before, &after),
+            "Members of access_bpf:\n  alice\n- bob\n+ carol\n  group:staff\n+ group:wheel\n"
+        );
+        assert_eq!(
+            render_diff(
+                "access_bpf",
+                &GroupMembers::default(),
+                &GroupMembers::default()
+            ),
+            "Members of access_bpf:\n  (none)\n"
+        );
+    }
+}
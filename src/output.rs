@@ -1,9 +1,21 @@
 /// UTF-8 check mark emoji (✅), used to indicate that a step was successful
 pub const EMOJI_CHECK_MARK: &str = "\u{2705}";
+/// UTF-8 warning sign emoji (⚠️), used to indicate that a step needs attention
+pub const EMOJI_WARNING: &str = "\u{26A0}\u{FE0F}";
 /// UTF-8 cross mark emoji (❌), used to indicate that a step failed
 pub const EMOJI_CROSS_MARK: &str = "\u{274C}";
 
+/// Output format for machine-readable subcommand results.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    Yaml,
+}
+
 pub const LOG_LABEL_OK: &str = "OK";
+pub const LOG_LABEL_WARN: &str = "WARN";
 pub const LOG_LABEL_ERROR: &str = "ERROR";
 
 pub fn get_ok_log(label: &str, message: &str) -> String {
@@ -19,6 +31,19 @@ pub fn get_check_ok_log(message: &str) -> String {
     format!("{EMOJI_CHECK_MARK} {message}")
 }
 
+pub fn get_warn_log(label: &str, message: &str) -> String {
+    let yellow_style =
+        anstyle::Style::new().fg_color(Some(anstyle::Color::Ansi(anstyle::AnsiColor::Yellow)));
+    let dimmed_style = anstyle::Style::new().dimmed();
+    format!(
+        "{dimmed_style}[{dimmed_style:#}{yellow_style}{label}{yellow_style:#}{dimmed_style}]{dimmed_style:#} {message}"
+    )
+}
+
+pub fn get_check_warn_log(message: &str) -> String {
+    format!("{EMOJI_WARNING} {message}")
+}
+
 pub fn get_error_log(label: &str, message: &str) -> String {
     let red_style =
         anstyle::Style::new().fg_color(Some(anstyle::Color::Ansi(anstyle::AnsiColor::Red)));
@@ -35,12 +60,50 @@ pub fn get_check_error_log(message: &str) -> String {
 pub fn node_label(label: &str, value: Option<&str>, delimiter: Option<&str>) -> String {
     match value {
         Some(value) => {
-            let delimiter = match delimiter {
-                Some(delimiter) => delimiter,
-                None => ":",
-            };
+            let delimiter = delimiter.unwrap_or(":");
             format!("{}{} {}", label, delimiter, value)
         }
         None => label.to_string(),
     }
 }
+
+/// Formats rows as left-aligned columns separated by two spaces.
+pub fn render_table<const N: usize>(header: [&str; N], rows: Vec<[String; N]>) -> String {
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut text = String::new();
+    for row in std::iter::once(header.map(str::to_string)).chain(rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        text.push_str(cells.join("  ").trim_end());
+        text.push('\n');
+    }
+    text
+}
+
+/// Escapes the characters XML reserves in text and attribute values, for the
+/// plist and the JUnit report. Control characters XML 1.0 does not allow are
+/// replaced with U+FFFD.
+pub fn escape_xml(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c < '\u{20}' => escaped.push(char::REPLACEMENT_CHARACTER),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
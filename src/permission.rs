@@ -1,41 +1,485 @@
-use anyhow::Result;
-use std::process::Command;
-
-use crate::command;
-
-/// Sets the read/write permissions for the specified path.
-pub fn set_read_write_permissions(path: &str) -> Result<()> {
-    let mut command = Command::new("chmod");
-    command.arg("u=rw,g=r,o=r").arg(path);
-    command::run(
-        &mut command,
-        &format!("set read/write permissions for {path}"),
-    )
+use anyhow::{Result, anyhow, bail};
+use std::fs;
+use std::io;
+use std::os::unix::fs::{PermissionsExt, lchown};
+use std::path::Path;
+use uzers::{get_group_by_name, get_user_by_name};
+
+const SETUID: u32 = 0o4000;
+const SETGID: u32 = 0o2000;
+const STICKY: u32 = 0o1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add,
+    Remove,
+    Set,
 }
 
-/// Sets the owner and group for the specified path.
-pub fn set_owner_group(path: &str) -> Result<()> {
-    let mut command = Command::new("chown");
-    command.arg("root:wheel").arg(path);
-    command::run(&mut command, &format!("set owner and group for {path}"))
+/// One comma-separated clause of a symbolic mode, e.g. `go-w`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ModeClause {
+    /// Bits owned by the classes the clause applies to.
+    who: u32,
+    op: Op,
+    /// Bits granted regardless of the file type.
+    perms: u32,
+    /// Whether `X` was given: execute only for directories or files that are
+    /// already executable by someone.
+    conditional_execute: bool,
 }
 
-/// Sets the read/execute permissions for the specified path recursively.
-pub fn set_read_execute_permissions_recursive(path: &str) -> Result<()> {
-    let mut command = Command::new("chmod");
-    command.arg("-R").arg("a+rX,go-w").arg(path);
-    command::run(
-        &mut command,
-        &format!("set recursive read/execute permissions for {path}"),
+/// A parsed `chmod` mode, either octal (`644`) or symbolic (`a+rX,go-w`).
+///
+/// When the class is omitted (`+x`) the clause applies to everyone; unlike
+/// chmod(1) the process umask is not consulted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mode {
+    clauses: Vec<ModeClause>,
+}
+
+impl Mode {
+    pub fn parse(expression: &str) -> Result<Self> {
+        if !expression.is_empty() && expression.chars().all(|c| c.is_digit(8)) {
+            let bits = u32::from_str_radix(expression, 8)
+                .map_err(|error| anyhow!("Invalid octal mode {expression}: {error}"))?;
+            if bits > 0o7777 {
+                bail!("Invalid octal mode {expression}");
+            }
+            return Ok(Self {
+                clauses: vec![ModeClause {
+                    who: 0o7777,
+                    op: Op::Set,
+                    perms: bits,
+                    conditional_execute: false,
+                }],
+            });
+        }
+
+        let mut clauses = Vec::new();
+        for part in expression.split(',') {
+            clauses.extend(parse_clause(part).map_err(|error| {
+                anyhow!("Invalid mode {expression:?}: {error} in clause {part:?}")
+            })?);
+        }
+        Ok(Self { clauses })
+    }
+
+    /// Computes the new permission bits for a file with the given mode.
+    pub fn apply(&self, mode: u32, is_dir: bool) -> u32 {
+        let original = mode & 0o7777;
+        let mut mode = original;
+        for clause in &self.clauses {
+            let mut perms = clause.perms;
+            if clause.conditional_execute && (is_dir || original & 0o111 != 0) {
+                perms |= 0o111;
+            }
+            let bits = perms & clause.who;
+            mode = match clause.op {
+                Op::Add => mode | bits,
+                Op::Remove => mode & !bits,
+                Op::Set => (mode & !clause.who) | bits,
+            };
+        }
+        mode
+    }
+}
+
+/// Parses one clause such as `u=rw` or `go-w`. A clause with several
+/// operators (`u+r-w`) expands to one `ModeClause` per operator.
+fn parse_clause(part: &str) -> Result<Vec<ModeClause>> {
+    let mut chars = part.chars().peekable();
+    let mut who = 0;
+    while let Some(&c) = chars.peek() {
+        who |= match c {
+            'u' => 0o700 | SETUID,
+            'g' => 0o070 | SETGID,
+            'o' => 0o007 | STICKY,
+            'a' => 0o7777,
+            _ => break,
+        };
+        chars.next();
+    }
+    if who == 0 {
+        who = 0o7777;
+    }
+
+    let mut clauses = Vec::new();
+    while let Some(c) = chars.next() {
+        let op = match c {
+            '+' => Op::Add,
+            '-' => Op::Remove,
+            '=' => Op::Set,
+            other => bail!("unexpected character {other:?}"),
+        };
+        let mut clause = ModeClause {
+            who,
+            op,
+            perms: 0,
+            conditional_execute: false,
+        };
+        while let Some(&c) = chars.peek() {
+            match c {
+                'r' => clause.perms |= 0o444,
+                'w' => clause.perms |= 0o222,
+                'x' => clause.perms |= 0o111,
+                'X' => clause.conditional_execute = true,
+                's' => clause.perms |= SETUID | SETGID,
+                't' => clause.perms |= STICKY,
+                '+' | '-' | '=' => break,
+                other => bail!("unsupported permission {other:?}"),
+            }
+            chars.next();
+        }
+        clauses.push(clause);
+    }
+    if clauses.is_empty() {
+        bail!("missing operator");
+    }
+    Ok(clauses)
+}
+
+/// Formats `st_mode` the way `ls -l` does, e.g. `crw-rw----`.
+pub fn mode_string(mode: u32) -> String {
+    let kind = match mode & 0o170000 {
+        0o020000 => 'c',
+        0o060000 => 'b',
+        0o040000 => 'd',
+        0o120000 => 'l',
+        0o010000 => 'p',
+        0o140000 => 's',
+        _ => '-',
+    };
+    let mut text = String::with_capacity(10);
+    text.push(kind);
+    for (shift, special, set, unset) in [
+        (6, SETUID, 's', 'S'),
+        (3, SETGID, 's', 'S'),
+        (0, STICKY, 't', 'T'),
+    ] {
+        let bits = (mode >> shift) & 0o7;
+        text.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        text.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        text.push(match (bits & 0o1 != 0, mode & special != 0) {
+            (true, true) => set,
+            (false, true) => unset,
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    text
+}
+
+/// An `owner:group` pair resolved to numeric ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Owner {
+    /// Parses `owner`, `owner:group` or `:group`, by name or numeric id.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (user, group) = match spec.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (spec, None),
+        };
+        let uid = match user {
+            "" => None,
+            user => Some(match user.parse::<u32>() {
+                Ok(uid) => uid,
+                Err(_) => get_user_by_name(user)
+                    .ok_or_else(|| anyhow!("Unknown user {user}"))?
+                    .uid(),
+            }),
+        };
+        let gid = match group {
+            None | Some("") => None,
+            Some(group) => Some(match group.parse::<u32>() {
+                Ok(gid) => gid,
+                Err(_) => get_group_by_name(group)
+                    .ok_or_else(|| anyhow!("Unknown group {group}"))?
+                    .gid(),
+            }),
+        };
+        if uid.is_none() && gid.is_none() {
+            bail!("Invalid owner {spec:?}");
+        }
+        Ok(Self { uid, gid })
+    }
+}
+
+/// Applies a mode (e.g. `u=rw,g=r,o=r`) to the specified path.
+///
+/// Symbolic links are never followed: they are skipped, and recursion does
+/// not descend through them.
+pub fn set_mode(path: &str, mode: &str, recursive: bool) -> Result<()> {
+    let mode = Mode::parse(mode)?;
+    walk(
+        Path::new(path),
+        recursive,
+        &format!("set mode for {path}"),
+        &mut |entry, metadata| {
+            if metadata.file_type().is_symlink() {
+                return Ok(());
+            }
+            let current = metadata.permissions().mode();
+            let updated = mode.apply(current, metadata.is_dir());
+            if updated == current & 0o7777 {
+                return Ok(());
+            }
+            fs::set_permissions(entry, fs::Permissions::from_mode(updated))
+        },
     )
 }
 
-/// Sets the owner and group for the specified path recursively.
-pub fn set_owner_group_recursive(path: &str) -> Result<()> {
-    let mut command = Command::new("chown");
-    command.arg("-R").arg("root:wheel").arg(path);
-    command::run(
-        &mut command,
-        &format!("set recursive owner and group for {path}"),
+/// Sets the owner and group (e.g. `root:wheel`) for the specified path.
+///
+/// Symbolic links themselves are re-owned, but never followed.
+pub fn set_owner(path: &str, owner: &str, recursive: bool) -> Result<()> {
+    let owner = Owner::parse(owner)?;
+    walk(
+        Path::new(path),
+        recursive,
+        &format!("set owner for {path}"),
+        &mut |entry, _| lchown(entry, owner.uid, owner.gid),
     )
 }
+
+/// Visits `root` (and, when recursive, everything below it) without following
+/// symbolic links. Failures are collected per path instead of aborting.
+fn walk(
+    root: &Path,
+    recursive: bool,
+    action: &str,
+    visit: &mut dyn FnMut(&Path, &fs::Metadata) -> io::Result<()>,
+) -> Result<()> {
+    let mut failures = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(entry) = pending.pop() {
+        let metadata = match fs::symlink_metadata(&entry) {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                failures.push(format!("{}: {error}", entry.display()));
+                continue;
+            }
+        };
+        if let Err(error) = visit(&entry, &metadata) {
+            failures.push(format!("{}: {error}", entry.display()));
+        }
+        if recursive && metadata.is_dir() {
+            match fs::read_dir(&entry) {
+                Ok(children) => {
+                    for child in children {
+                        match child {
+                            Ok(child) => pending.push(child.path()),
+                            Err(error) => failures.push(format!("{}: {error}", entry.display())),
+                        }
+                    }
+                }
+                Err(error) => failures.push(format!("{}: {error}", entry.display())),
+            }
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Failed to {action} on {} path(s):\n  {}",
+            failures.len(),
+            failures.join("\n  ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::os::unix::fs::{MetadataExt, symlink};
+
+    const FILE: bool = false;
+    const DIR: bool = true;
+
+    #[test]
+    fn mode_parse_and_apply() {
+        let cases = [
+            // expression, current mode, is_dir, expected
+            ("a+rX,go-w", 0o600, FILE, 0o644),
+            ("a+rX,go-w", 0o700, FILE, 0o755),
+            ("a+rX,go-w", 0o777, DIR, 0o755),
+            ("a+rX,go-w", 0o700, DIR, 0o755),
+            ("u=rw,g=r,o=r", 0o777, FILE, 0o644),
+            ("u=rw,g=r,o=r", 0o4755, FILE, 0o644),
+            ("u=rwx,go=rx", 0o600, FILE, 0o755),
+            ("644", 0o777, FILE, 0o644),
+            ("0750", 0o644, DIR, 0o750),
+            ("4755", 0o644, FILE, 0o4755),
+            ("a+X", 0o644, FILE, 0o644),
+            ("a+X", 0o644, DIR, 0o755),
+            ("a+X", 0o744, FILE, 0o755),
+            ("+x", 0o644, FILE, 0o755),
+            ("u+r-w", 0o200, FILE, 0o400),
+            ("g+rw", 0o600, FILE, 0o660),
+            ("g+s,o+t", 0o755, DIR, 0o3755),
+            ("o=", 0o777, FILE, 0o770),
+        ];
+        for (expression, mode, is_dir, expected) in cases {
+            let parsed = Mode::parse(expression).unwrap();
+            assert_eq!(
+                parsed.apply(mode, is_dir),
+                expected,
+                "{expression} on {mode:o} (dir: {is_dir})"
+            );
+        }
+    }
+
+    #[test]
+    fn mode_apply_ignores_the_file_type_bits() {
+        let mode = Mode::parse("g+rw").unwrap();
+        assert_eq!(mode.apply(0o020600, FILE), 0o660);
+    }
+
+    #[test]
+    fn mode_parse_rejects_invalid_input() {
+        for expression in ["", "u", "u+q", "z+r", "8", "77777", "u=rw,", ",", "u+r,=w!"] {
+            assert!(
+                Mode::parse(expression).is_err(),
+                "{expression:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn mode_string_formats_like_ls() {
+        assert_eq!(mode_string(0o020660), "crw-rw----");
+        assert_eq!(mode_string(0o040755), "drwxr-xr-x");
+        assert_eq!(mode_string(0o104755), "-rwsr-xr-x");
+        assert_eq!(mode_string(0o041777), "drwxrwxrwt");
+        assert_eq!(mode_string(0o102644), "-rw-r-Sr--");
+        assert_eq!(mode_string(0o120777), "lrwxrwxrwx");
+    }
+
+    #[test]
+    fn owner_parse_accepts_names_and_ids() {
+        assert_eq!(
+            Owner::parse("0:0").unwrap(),
+            Owner {
+                uid: Some(0),
+                gid: Some(0)
+            }
+        );
+        assert_eq!(
+            Owner::parse(":20").unwrap(),
+            Owner {
+                uid: None,
+                gid: Some(20)
+            }
+        );
+        assert_eq!(
+            Owner::parse("root").unwrap(),
+            Owner {
+                uid: Some(0),
+                gid: None
+            }
+        );
+        for spec in [
+            "",
+            ":",
+            "no-such-user-chmod-bpf:0",
+            "0:no-such-group-chmod-bpf",
+        ] {
+            assert!(Owner::parse(spec).is_err(), "{spec:?} was accepted");
+        }
+    }
+
+    fn mode_of(path: &Path) -> u32 {
+        fs::symlink_metadata(path).unwrap().mode() & 0o7777
+    }
+
+    #[test]
+    fn set_mode_changes_a_file() {
+        let dir = TempDir::new();
+        let file = dir.join("plist");
+        fs::write(&file, "").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).unwrap();
+
+        set_mode(file.to_str().unwrap(), "u=rw,g=r,o=r", false).unwrap();
+        assert_eq!(mode_of(&file), 0o644);
+    }
+
+    #[test]
+    fn set_mode_recurses_without_following_symlinks() {
+        let dir = TempDir::new();
+        let outside = TempDir::new();
+        let outside_file = outside.join("secret");
+        fs::write(&outside_file, "").unwrap();
+        fs::set_permissions(&outside_file, fs::Permissions::from_mode(0o600)).unwrap();
+
+        fs::create_dir(dir.join("sub")).unwrap();
+        let inner = dir.join("sub/binary");
+        fs::write(&inner, "").unwrap();
+        fs::set_permissions(&inner, fs::Permissions::from_mode(0o700)).unwrap();
+        symlink(outside.path(), dir.join("linked-dir")).unwrap();
+        symlink(&outside_file, dir.join("linked-file")).unwrap();
+
+        set_mode(dir.path().to_str().unwrap(), "a+rX,go-w", true).unwrap();
+        assert_eq!(mode_of(&inner), 0o755);
+        assert_eq!(mode_of(&dir.join("sub")), 0o755);
+        assert_eq!(mode_of(&outside_file), 0o600);
+
+        set_mode(dir.join("linked-file").to_str().unwrap(), "777", false).unwrap();
+        assert_eq!(mode_of(&outside_file), 0o600);
+    }
+
+    #[test]
+    fn set_owner_changes_the_link_not_its_target() {
+        let dir = TempDir::new();
+        // chown(2) would fail on a dangling link; lchown(2) does not follow it.
+        let link = dir.join("dangling");
+        symlink(dir.join("missing"), &link).unwrap();
+        let metadata = fs::symlink_metadata(dir.path()).unwrap();
+        let owner = format!("{}:{}", metadata.uid(), metadata.gid());
+
+        set_owner(link.to_str().unwrap(), &owner, false).unwrap();
+        set_owner(dir.path().to_str().unwrap(), &owner, true).unwrap();
+        assert!(!dir.join("missing").exists());
+    }
+
+    #[test]
+    fn walk_collects_every_failure() {
+        let dir = TempDir::new();
+        for name in ["a", "b", "c"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let mut visited = Vec::new();
+        let error = walk(dir.path(), true, "touch", &mut |entry, _| {
+            let name = entry.file_name().unwrap().to_string_lossy().into_owned();
+            visited.push(name.clone());
+            if name == "a" || name == "c" {
+                Err(io::Error::other("refused"))
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap_err()
+        .to_string();
+
+        assert_eq!(visited.len(), 4, "{visited:?}");
+        assert!(
+            error.starts_with("Failed to touch on 2 path(s):"),
+            "{error}"
+        );
+        assert!(error.contains(&format!("{}: refused", dir.join("a").display())));
+        assert!(error.contains(&format!("{}: refused", dir.join("c").display())));
+    }
+
+    #[test]
+    fn set_mode_reports_a_missing_path() {
+        let dir = TempDir::new();
+        let missing = dir.join("missing");
+        let error = set_mode(missing.to_str().unwrap(), "644", false).unwrap_err();
+        assert!(error.to_string().contains("on 1 path(s)"), "{error:#}");
+    }
+}
@@ -0,0 +1,1271 @@
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Serialize;
+use std::fmt;
+use std::path::Path;
+use tracing::{debug, info, warn};
+
+use crate::{
+    backend::SystemBackend,
+    config::Config,
+    drift,
+    manifest::{self, Manifest},
+    plist::LaunchdPlist,
+    resource,
+    transaction::Transaction,
+};
+
+pub const ADMIN_GROUP: &str = "admin";
+pub const OWNER_GROUP: &str = "root:wheel";
+pub const PLIST_MODE: &str = "u=rw,g=r,o=r";
+pub const SUPPORT_DIR_MODE: &str = "u=rwx,go=rx";
+pub const BINARY_MODE: &str = "u=rwx,go=rx";
+pub const MANIFEST_MODE: &str = "u=rw,go=r";
+
+/// A single system change performed by install or uninstall.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    CreateGroup {
+        group: String,
+        real_name: String,
+        gid: u32,
+    },
+    DeleteGroup {
+        group: String,
+    },
+    AddGroupToGroup {
+        group: String,
+        target_group: String,
+    },
+    AddUserToGroup {
+        user: String,
+        group: String,
+    },
+    RemoveGroupFromGroup {
+        group: String,
+        target_group: String,
+    },
+    RemoveUserFromGroup {
+        user: String,
+        group: String,
+    },
+    CreateDir {
+        path: String,
+    },
+    WriteFile {
+        path: String,
+        #[serde(skip)]
+        contents: Vec<u8>,
+        bytes: usize,
+    },
+    RemoveDir {
+        path: String,
+    },
+    /// Removes a directory only if nothing is left in it.
+    RemoveEmptyDir {
+        path: String,
+    },
+    RemoveFile {
+        path: String,
+    },
+    SetOwner {
+        path: String,
+        owner: String,
+        recursive: bool,
+    },
+    SetMode {
+        path: String,
+        mode: String,
+        recursive: bool,
+    },
+    BootoutDaemon {
+        plist_path: String,
+        /// Whether a failure is tolerated, e.g. when the daemon was not loaded.
+        allow_failure: bool,
+    },
+    BootstrapDaemon {
+        plist_path: String,
+    },
+}
+
+impl Action {
+    pub fn write_file(path: &str, contents: &[u8]) -> Self {
+        Action::WriteFile {
+            path: path.to_string(),
+            contents: contents.to_vec(),
+            bytes: contents.len(),
+        }
+    }
+
+    /// Shell equivalent of the action, when there is one.
+    pub fn command(&self) -> Option<String> {
+        let args: Vec<&str> = match self {
+            Action::CreateGroup {
+                group,
+                real_name,
+                gid,
+            } => {
+                let gid = gid.to_string();
+                return Some(shell_command(&[
+                    "dseditgroup",
+                    "-q",
+                    "-o",
+                    "create",
+                    "-i",
+                    &gid,
+                    "-r",
+                    real_name,
+                    group,
+                ]));
+            }
+            Action::DeleteGroup { group } => vec!["dseditgroup", "-o", "delete", group],
+            Action::AddGroupToGroup {
+                group,
+                target_group,
+            } => vec![
+                "dseditgroup",
+                "-q",
+                "-o",
+                "edit",
+                "-a",
+                group,
+                "-t",
+                "group",
+                target_group,
+            ],
+            Action::AddUserToGroup { user, group } => vec![
+                "dseditgroup",
+                "-q",
+                "-o",
+                "edit",
+                "-a",
+                user,
+                "-t",
+                "user",
+                group,
+            ],
+            Action::RemoveGroupFromGroup {
+                group,
+                target_group,
+            } => vec![
+                "dseditgroup",
+                "-q",
+                "-o",
+                "edit",
+                "-d",
+                group,
+                "-t",
+                "group",
+                target_group,
+            ],
+            Action::RemoveUserFromGroup { user, group } => vec![
+                "dseditgroup",
+                "-q",
+                "-o",
+                "edit",
+                "-d",
+                user,
+                "-t",
+                "user",
+                group,
+            ],
+            Action::CreateDir { path } => vec!["mkdir", "-p", path],
+            Action::WriteFile { .. } => return None,
+            Action::RemoveDir { path } => vec!["rm", "-rf", path],
+            Action::RemoveEmptyDir { path } => vec!["rmdir", path],
+            Action::RemoveFile { path } => vec!["rm", "-f", path],
+            Action::SetOwner {
+                path,
+                owner,
+                recursive,
+            } => {
+                let mut args = vec!["chown"];
+                if *recursive {
+                    args.push("-R");
+                }
+                args.extend([owner.as_str(), path.as_str()]);
+                args
+            }
+            Action::SetMode {
+                path,
+                mode,
+                recursive,
+            } => {
+                let mut args = vec!["chmod"];
+                if *recursive {
+                    args.push("-R");
+                }
+                args.extend([mode.as_str(), path.as_str()]);
+                args
+            }
+            Action::BootoutDaemon { plist_path, .. } => {
+                vec!["launchctl", "bootout", "system", plist_path]
+            }
+            Action::BootstrapDaemon { plist_path } => {
+                vec!["launchctl", "bootstrap", "system", plist_path]
+            }
+        };
+        Some(shell_command(&args))
+    }
+
+    /// Performs the action through the given backend.
+    pub fn apply(&self, backend: &dyn SystemBackend) -> Result<()> {
+        match self {
+            Action::CreateGroup {
+                group,
+                real_name,
+                gid,
+            } => backend.create_group(group, real_name, *gid),
+            Action::DeleteGroup { group } => backend.delete_group(group),
+            Action::AddGroupToGroup {
+                group,
+                target_group,
+            } => backend.add_group_to_group(group, target_group),
+            Action::AddUserToGroup { user, group } => backend.add_user_to_group(user, group),
+            Action::RemoveGroupFromGroup {
+                group,
+                target_group,
+            } => backend.remove_group_from_group(group, target_group),
+            Action::RemoveUserFromGroup { user, group } => {
+                backend.remove_user_from_group(user, group)
+            }
+            Action::CreateDir { path } => backend.create_dir_all(path),
+            Action::WriteFile { path, contents, .. } => backend.write_file(path, contents),
+            Action::RemoveDir { path } => backend.remove_dir_all(path).map(|_| ()),
+            Action::RemoveEmptyDir { path } => backend.remove_dir(path).map(|_| ()),
+            Action::RemoveFile { path } => backend.remove_file(path).map(|_| ()),
+            Action::SetOwner {
+                path,
+                owner,
+                recursive,
+            } => backend.set_owner(path, owner, *recursive),
+            Action::SetMode {
+                path,
+                mode,
+                recursive,
+            } => backend.set_mode(path, mode, *recursive),
+            Action::BootoutDaemon {
+                plist_path,
+                allow_failure,
+            } => match backend.bootout_daemon(plist_path) {
+                Err(error) if *allow_failure => {
+                    debug!(?error, "launchctl bootout failed; continuing");
+                    Ok(())
+                }
+                result => result,
+            },
+            Action::BootstrapDaemon { plist_path } => backend.bootstrap_daemon(plist_path),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::CreateGroup {
+                group,
+                real_name,
+                gid,
+            } => write!(f, "Create group {group} (\"{real_name}\") with gid {gid}"),
+            Action::DeleteGroup { group } => write!(f, "Delete group {group}"),
+            Action::AddGroupToGroup {
+                group,
+                target_group,
+            } => write!(f, "Add group {group} to group {target_group}"),
+            Action::AddUserToGroup { user, group } => {
+                write!(f, "Add user {user} to group {group}")
+            }
+            Action::RemoveGroupFromGroup {
+                group,
+                target_group,
+            } => write!(f, "Remove group {group} from group {target_group}"),
+            Action::RemoveUserFromGroup { user, group } => {
+                write!(f, "Remove user {user} from group {group}")
+            }
+            Action::CreateDir { path } => write!(f, "Create directory {path}"),
+            Action::WriteFile { path, bytes, .. } => write!(f, "Write {bytes} bytes to {path}"),
+            Action::RemoveDir { path } => write!(f, "Remove directory {path}"),
+            Action::RemoveEmptyDir { path } => write!(f, "Remove empty directory {path}"),
+            Action::RemoveFile { path } => write!(f, "Remove file {path}"),
+            Action::SetOwner {
+                path,
+                owner,
+                recursive,
+            } => write!(
+                f,
+                "Change owner of {path} to {owner}{}",
+                if *recursive { " (recursive)" } else { "" }
+            ),
+            Action::SetMode {
+                path,
+                mode,
+                recursive,
+            } => write!(
+                f,
+                "Change mode of {path} to {mode}{}",
+                if *recursive { " (recursive)" } else { "" }
+            ),
+            Action::BootoutDaemon {
+                plist_path,
+                allow_failure,
+            } => write!(
+                f,
+                "Unload daemon {plist_path}{}",
+                if *allow_failure {
+                    " (failure ignored)"
+                } else {
+                    ""
+                }
+            ),
+            Action::BootstrapDaemon { plist_path } => write!(f, "Load daemon {plist_path}"),
+        }
+    }
+}
+
+/// Ordered list of actions for an install or uninstall run.
+#[derive(Debug, Clone, Serialize)]
+pub struct Plan {
+    pub operation: &'static str,
+    pub actions: Vec<Action>,
+}
+
+impl Plan {
+    /// Runs every action in order. When a step fails, the steps that already
+    /// succeeded are rolled back in reverse order.
+    pub fn execute(&self, backend: &dyn SystemBackend) -> Result<()> {
+        let mut transaction = Transaction::new(backend);
+        let total = self.actions.len();
+        for (index, action) in self.actions.iter().enumerate() {
+            info!("[{}/{total}] {action}", index + 1);
+            if let Err(error) = transaction.apply(action) {
+                let report = transaction.rollback();
+                return Err(error.context(format!(
+                    "{} failed at step {}/{total} ({action}); {report}",
+                    self.operation,
+                    index + 1
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn render_text(&self) -> String {
+        format!(
+            "Dry run: {} plan with {} step(s), nothing has been changed\n{}",
+            self.operation,
+            self.actions.len(),
+            self.render_steps()
+        )
+    }
+
+    /// The actions as one command line run with sudo, for an operator who
+    /// makes the change by hand. Steps allowed to fail do not stop the rest.
+    pub fn sudo_command_line(&self) -> String {
+        let mut line = String::new();
+        for action in &self.actions {
+            let Some(command) = action.command() else {
+                continue;
+            };
+            line.push_str("sudo ");
+            line.push_str(&command);
+            let allow_failure = matches!(
+                action,
+                Action::BootoutDaemon {
+                    allow_failure: true,
+                    ..
+                }
+            );
+            line.push_str(if allow_failure { "; " } else { " && " });
+        }
+        line.trim_end_matches([' ', '&', ';']).to_string()
+    }
+
+    /// Numbered list of the actions with their shell equivalents.
+    pub fn render_steps(&self) -> String {
+        let mut text = String::new();
+        for (index, action) in self.actions.iter().enumerate() {
+            text.push_str(&format!("{:>3}. {action}\n", index + 1));
+            if let Some(command) = action.command() {
+                text.push_str(&format!("     $ {command}\n"));
+            }
+        }
+        text
+    }
+
+    pub fn render_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("Failed to serialize the plan")
+    }
+
+    pub fn render_yaml(&self) -> Result<String> {
+        serde_yaml::to_string(self).context("Failed to serialize the plan")
+    }
+}
+
+/// Builds the install plan from the current state of the system.
+pub fn install_plan(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    daemon: &LaunchdPlist,
+) -> Result<Plan> {
+    daemon.validate()?;
+    let group = config.group.name.as_str();
+    let support_dir = config.paths.support_dir.as_str();
+    let binary_path = config.binary_path();
+    let manifest_path = config.manifest_path();
+    let plist_path = daemon.path();
+    let plist_xml = daemon.render();
+    let mut actions = Vec::new();
+    let previous = manifest::load(backend, &manifest_path)?;
+
+    let (gid, group_preexisted) = match backend.group_gid(group) {
+        Some(gid) => {
+            info!("Group {group} already exists");
+            if let Some(pinned) = config.group.gid
+                && pinned != gid
+            {
+                bail!(
+                    "Group {group} already exists with gid {gid}, but the config pins gid {pinned}"
+                );
+            }
+            // A group created by an earlier install is still ours to remove.
+            let preexisted = previous
+                .as_ref()
+                .filter(|previous| previous.group == group)
+                .is_none_or(|previous| previous.group_preexisted);
+            (gid, preexisted)
+        }
+        None => {
+            let gid = match config.group.gid {
+                Some(gid) => {
+                    if let Some(owner) = backend.group_name_by_gid(gid) {
+                        bail!("The configured gid {gid} is already used by group {owner}");
+                    }
+                    gid
+                }
+                None => backend.free_gid(config.group.gid_range())?,
+            };
+            actions.push(Action::CreateGroup {
+                group: group.to_string(),
+                real_name: config.group.display_name.clone(),
+                gid,
+            });
+            (gid, false)
+        }
+    };
+    let support_dir_created = support_dir_created(backend, config, previous.as_ref())?;
+    let mut manifest = Manifest::new(group, gid, group_preexisted, &plist_path);
+    manifest.support_dir_created = Some(support_dir_created);
+    if let Some(previous) = previous.filter(|previous| previous.group == group) {
+        manifest.users_added = previous.users_added;
+        manifest.groups_added = previous.groups_added;
+    }
+
+    let username = backend
+        .current_user_name()
+        .ok_or_else(|| anyhow!("Unable to resolve the current user"))?;
+    let mut users = vec![username];
+    for member in &config.group.members {
+        if !users.contains(member) {
+            users.push(member.clone());
+        }
+    }
+    // Memberships only need tracking in a pre-existing group; deleting a
+    // group created by chmod-bpf drops them anyway.
+    if group_preexisted {
+        if !backend.group_is_member(ADMIN_GROUP, group)?
+            && !manifest
+                .groups_added
+                .iter()
+                .any(|added| added == ADMIN_GROUP)
+        {
+            manifest.groups_added.push(ADMIN_GROUP.to_string());
+        }
+        for user in &users {
+            if !backend.user_is_member(user, group) && !manifest.users_added.contains(user) {
+                manifest.users_added.push(user.clone());
+            }
+        }
+    }
+    actions.push(Action::AddGroupToGroup {
+        group: ADMIN_GROUP.to_string(),
+        target_group: group.to_string(),
+    });
+    for user in users {
+        actions.push(Action::AddUserToGroup {
+            user,
+            group: group.to_string(),
+        });
+    }
+
+    let binary = backend.current_executable()?;
+    manifest.add_file(&binary_path, &binary);
+    manifest.add_file(&plist_path, plist_xml.as_bytes());
+
+    actions.push(Action::CreateDir {
+        path: support_dir.to_string(),
+    });
+    actions.push(Action::write_file(&binary_path, &binary));
+    // Releases before the native `apply` subcommand ran a shell script.
+    let legacy_script = Path::new(support_dir)
+        .join(drift::LEGACY_SCRIPT_NAME)
+        .to_string_lossy()
+        .into_owned();
+    if backend.path_exists(&legacy_script) {
+        actions.push(Action::RemoveFile {
+            path: legacy_script,
+        });
+    }
+    actions.push(Action::SetOwner {
+        path: binary_path.clone(),
+        owner: OWNER_GROUP.to_string(),
+        recursive: false,
+    });
+    actions.push(Action::SetMode {
+        path: binary_path.clone(),
+        mode: BINARY_MODE.to_string(),
+        recursive: false,
+    });
+    actions.push(Action::write_file(
+        &manifest_path,
+        manifest.to_json()?.as_bytes(),
+    ));
+    actions.push(Action::SetOwner {
+        path: manifest_path.clone(),
+        owner: OWNER_GROUP.to_string(),
+        recursive: false,
+    });
+    actions.push(Action::SetMode {
+        path: manifest_path.clone(),
+        mode: MANIFEST_MODE.to_string(),
+        recursive: false,
+    });
+    // Only the files chmod-bpf wrote are touched in a directory it did not
+    // create.
+    if support_dir_created {
+        actions.push(Action::SetOwner {
+            path: support_dir.to_string(),
+            owner: OWNER_GROUP.to_string(),
+            recursive: false,
+        });
+        actions.push(Action::SetMode {
+            path: support_dir.to_string(),
+            mode: SUPPORT_DIR_MODE.to_string(),
+            recursive: false,
+        });
+    } else {
+        info!("Leaving the owner and mode of {support_dir} unchanged; chmod-bpf did not create it");
+    }
+
+    actions.push(Action::write_file(&plist_path, plist_xml.as_bytes()));
+    actions.push(Action::SetMode {
+        path: plist_path.clone(),
+        mode: PLIST_MODE.to_string(),
+        recursive: false,
+    });
+    actions.push(Action::SetOwner {
+        path: plist_path.clone(),
+        owner: OWNER_GROUP.to_string(),
+        recursive: false,
+    });
+
+    actions.push(Action::BootoutDaemon {
+        plist_path: plist_path.clone(),
+        allow_failure: true,
+    });
+    actions.push(Action::BootstrapDaemon {
+        plist_path: plist_path.clone(),
+    });
+
+    Ok(Plan {
+        operation: "install",
+        actions,
+    })
+}
+
+/// Builds the uninstall plan from the current state of the system and the
+/// install manifest. Only what chmod-bpf created is removed.
+pub fn uninstall_plan(backend: &dyn SystemBackend, config: &Config) -> Result<Plan> {
+    let manifest_path = config.manifest_path();
+    let manifest = manifest::load(backend, &manifest_path)?;
+    let plist_path = manifest
+        .as_ref()
+        .map_or(resource::CHMOD_BPF_PLIST_PATH, |manifest| {
+            manifest.plist_path.as_str()
+        })
+        .to_string();
+    let mut actions = vec![Action::BootoutDaemon {
+        plist_path: plist_path.clone(),
+        allow_failure: false,
+    }];
+
+    let group = config.group.name.as_str();
+    if let Some(manifest) = &manifest
+        && manifest.group != group
+    {
+        // The manifest records what install actually created.
+        warn!(
+            "The install manifest records group {installed}, not {group}; uninstalling {installed}",
+            installed = manifest.group
+        );
+    }
+    match &manifest {
+        Some(manifest) if backend.group_exists(&manifest.group) => {
+            if manifest.group_preexisted {
+                info!(
+                    "Group {group} existed before chmod-bpf; removing only the members it added",
+                    group = manifest.group
+                );
+                for user in &manifest.users_added {
+                    actions.push(Action::RemoveUserFromGroup {
+                        user: user.clone(),
+                        group: manifest.group.clone(),
+                    });
+                }
+                for group in &manifest.groups_added {
+                    actions.push(Action::RemoveGroupFromGroup {
+                        group: group.clone(),
+                        target_group: manifest.group.clone(),
+                    });
+                }
+            } else {
+                actions.push(Action::DeleteGroup {
+                    group: manifest.group.clone(),
+                });
+            }
+        }
+        Some(manifest) => {
+            info!("Group {group} was not present", group = manifest.group);
+        }
+        None if backend.group_exists(group) => {
+            warn!("No install manifest found at {manifest_path}; leaving group {group} in place");
+        }
+        None => info!("Group {group} was not present"),
+    }
+
+    let support_dir = config.paths.support_dir.as_str();
+    let legacy_script = Path::new(support_dir)
+        .join(drift::LEGACY_SCRIPT_NAME)
+        .to_string_lossy()
+        .into_owned();
+    let mut removals = Vec::new();
+    match &manifest {
+        Some(manifest) => {
+            for file in &manifest.files {
+                match backend.read_file(&file.path)? {
+                    None => info!("{} is already gone", file.path),
+                    Some(contents) if manifest::sha256_hex(&contents) == file.sha256 => {
+                        removals.push(file.path.clone());
+                    }
+                    Some(_) => warn!(
+                        "{} changed since it was installed; leaving it in place",
+                        file.path
+                    ),
+                }
+            }
+        }
+        None => {
+            // Releases before the manifest recorded nothing to verify against.
+            for path in [config.binary_path(), plist_path.clone()] {
+                if backend.path_exists(&path) {
+                    warn!("Removing {path} without a recorded hash to verify it");
+                    removals.push(path);
+                }
+            }
+        }
+    }
+    if backend.path_exists(&legacy_script) {
+        removals.push(legacy_script);
+    }
+    // The manifest goes last so a failed uninstall can be retried.
+    if manifest.is_some() {
+        removals.push(manifest_path.clone());
+    }
+    actions.extend(
+        removals
+            .iter()
+            .map(|path| Action::RemoveFile { path: path.clone() }),
+    );
+
+    if backend.path_exists(support_dir) {
+        let remaining: Vec<String> = backend
+            .list_dir(Path::new(support_dir))?
+            .into_iter()
+            .filter(|name| {
+                !removals
+                    .iter()
+                    .any(|path| Path::new(path) == Path::new(support_dir).join(name))
+            })
+            .collect();
+        let created = manifest.as_ref().map_or_else(
+            || manifest::is_default_support_dir(support_dir),
+            |manifest| manifest.created_support_dir(support_dir),
+        );
+        if !created {
+            info!("Leaving {support_dir} in place; chmod-bpf did not create it");
+        } else if remaining.is_empty() {
+            actions.push(Action::RemoveEmptyDir {
+                path: support_dir.to_string(),
+            });
+        } else {
+            warn!(
+                "Leaving {support_dir} in place; it holds files chmod-bpf did not install or that changed: {}",
+                remaining.join(", ")
+            );
+        }
+    } else {
+        info!("No support directory to remove");
+    }
+
+    Ok(Plan {
+        operation: "uninstall",
+        actions,
+    })
+}
+
+/// Names of the files chmod-bpf keeps in the support directory, including
+/// the script a script-based release installed there.
+pub fn support_entries(config: &Config) -> Vec<String> {
+    [config.binary_path(), config.manifest_path()]
+        .iter()
+        .filter_map(|path| Path::new(path).file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .chain([drift::LEGACY_SCRIPT_NAME.to_string()])
+        .collect()
+}
+
+/// Whether chmod-bpf created the support directory. A directory that
+/// already exists and holds files chmod-bpf did not install is refused, so
+/// install never changes or later removes someone else's files.
+fn support_dir_created(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    previous: Option<&Manifest>,
+) -> Result<bool> {
+    let support_dir = config.paths.support_dir.as_str();
+    if !backend.path_exists(support_dir) {
+        return Ok(true);
+    }
+    if let Some(previous) = previous {
+        return Ok(previous.created_support_dir(support_dir));
+    }
+    let expected = support_entries(config);
+    let entries = backend.list_dir(Path::new(support_dir))?;
+    let foreign: Vec<&String> = entries
+        .iter()
+        .filter(|name| !expected.contains(name))
+        .collect();
+    if !foreign.is_empty() {
+        bail!(
+            "{support_dir} already exists and holds files chmod-bpf did not install ({}); set paths.support_dir to a directory dedicated to chmod-bpf",
+            foreign
+                .iter()
+                .map(|name| name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    // Files from a release that predates the manifest mean chmod-bpf created
+    // the directory; an empty directory was created by someone else.
+    Ok(!entries.is_empty())
+}
+
+/// Builds the plan that replaces Wireshark's ChmodBPF launch daemon with
+/// chmod-bpf: unload and remove the Wireshark daemon and script, then install
+/// chmod-bpf reusing the existing group and its gid.
+pub fn migrate_plan(
+    backend: &dyn SystemBackend,
+    config: &Config,
+    daemon: &LaunchdPlist,
+) -> Result<Plan> {
+    let wireshark_plist = resource::WIRESHARK_CHMODBPF_PLIST_PATH;
+    if !backend.path_exists(wireshark_plist) {
+        bail!("No Wireshark ChmodBPF launch daemon was found at {wireshark_plist}");
+    }
+
+    let mut config = config.clone();
+    let mut daemon = daemon.clone();
+    let group = resource::WIRESHARK_CHMODBPF_GROUP;
+    match backend.group_gid(group) {
+        Some(gid) => {
+            if config.group.name != group {
+                warn!(
+                    "Reusing Wireshark's group {group} instead of the configured group {configured}",
+                    configured = config.group.name
+                );
+            }
+            info!("Reusing group {group} with gid {gid}");
+            config.group.name = group.to_string();
+            config.group.gid = Some(gid);
+            // The daemon reads the config file at boot, which may name
+            // another group.
+            daemon.set_apply_group(group);
+        }
+        None => warn!(
+            "Wireshark's group {group} does not exist; group {configured} will be set up instead",
+            configured = config.group.name
+        ),
+    }
+
+    // Wireshark's daemon is only removed once chmod-bpf is in place: its
+    // removal cannot be rolled back, and a failed install must not leave the
+    // machine without any daemon managing the BPF devices.
+    let mut actions = install_plan(backend, &config, &daemon)?.actions;
+    actions.extend(wireshark_removal_actions(backend));
+
+    Ok(Plan {
+        operation: "migrate",
+        actions,
+    })
+}
+
+/// Builds the plan that only unloads and removes Wireshark's ChmodBPF launch
+/// daemon and script, for when chmod-bpf is already installed next to it.
+pub fn remove_wireshark_plan(backend: &dyn SystemBackend) -> Result<Plan> {
+    let wireshark_plist = resource::WIRESHARK_CHMODBPF_PLIST_PATH;
+    if !backend.path_exists(wireshark_plist) {
+        bail!("No Wireshark ChmodBPF launch daemon was found at {wireshark_plist}");
+    }
+    Ok(Plan {
+        operation: "remove-wireshark",
+        actions: wireshark_removal_actions(backend),
+    })
+}
+
+/// Unloads Wireshark's daemon and removes its files. The plist goes last, so
+/// a failure before it leaves a daemon that launchd loads again at boot.
+fn wireshark_removal_actions(backend: &dyn SystemBackend) -> Vec<Action> {
+    let wireshark_plist = resource::WIRESHARK_CHMODBPF_PLIST_PATH;
+    let mut actions = vec![Action::BootoutDaemon {
+        plist_path: wireshark_plist.to_string(),
+        allow_failure: true,
+    }];
+    if backend.path_exists(resource::WIRESHARK_CHMODBPF_DIR_PATH) {
+        actions.push(Action::RemoveDir {
+            path: resource::WIRESHARK_CHMODBPF_DIR_PATH.to_string(),
+        });
+    }
+    actions.push(Action::RemoveFile {
+        path: wireshark_plist.to_string(),
+    });
+    actions
+}
+
+/// Formats a command line, quoting arguments that contain spaces.
+fn shell_command(args: &[&str]) -> String {
+    args.iter()
+        .map(|arg| {
+            if arg.contains([' ', '"', '\'']) {
+                format!("'{}'", arg.replace('\'', r"'\''"))
+            } else {
+                arg.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{MemoryBackend, MemoryGroup, Step};
+    use crate::user;
+
+    fn install(backend: &MemoryBackend, config: &Config) -> Result<()> {
+        install_plan(backend, config, &LaunchdPlist::default())?.execute(backend)
+    }
+
+    fn preexisting_group(backend: &MemoryBackend, users: &[&str]) {
+        backend.state.borrow_mut().groups.insert(
+            bpf_group(),
+            MemoryGroup {
+                gid: 300,
+                users: users.iter().map(|user| user.to_string()).collect(),
+                ..MemoryGroup::default()
+            },
+        );
+    }
+
+    fn bpf_group() -> String {
+        Config::default().group.name
+    }
+
+    #[test]
+    fn install_creates_the_group_files_and_daemon() {
+        let backend = MemoryBackend::root("alice");
+        let config = Config::default();
+        install(&backend, &config).unwrap();
+
+        let state = backend.state.borrow();
+        let group = &state.groups[&bpf_group()];
+        assert_eq!(group.gid, user::MIN_GID);
+        assert!(group.users.contains("alice"));
+        assert!(group.groups.contains(ADMIN_GROUP));
+        assert_eq!(state.files[&config.binary_path()], backend.executable);
+        assert!(state.files.contains_key(&config.manifest_path()));
+        let plist_path = LaunchdPlist::default().path();
+        assert!(state.files.contains_key(&plist_path));
+        assert!(state.loaded_daemons.contains(&plist_path));
+
+        drop(state);
+        let manifest = manifest::load(&backend, &config.manifest_path())
+            .unwrap()
+            .unwrap();
+        assert!(!manifest.group_preexisted);
+        assert_eq!(manifest.plist_path, plist_path);
+        assert_eq!(manifest.files.len(), 2);
+    }
+
+    #[test]
+    fn install_rolls_back_when_any_step_fails() {
+        let config = Config::default();
+        let steps = install_plan(
+            &MemoryBackend::root("alice"),
+            &config,
+            &LaunchdPlist::default(),
+        )
+        .unwrap()
+        .actions;
+        for step in Step::ALL {
+            let backend = MemoryBackend {
+                fail_at: Some(step),
+                ..MemoryBackend::root("alice")
+            };
+            let before = backend.state.borrow().clone();
+            match install(&backend, &config) {
+                Ok(()) => assert!(
+                    !steps.iter().any(|action| fails_at(action, step)),
+                    "install did not reach {step:?}"
+                ),
+                Err(error) => {
+                    assert!(
+                        format!("{error:#}").contains("install failed at step"),
+                        "{step:?}: {error:#}"
+                    );
+                    assert_eq!(*backend.state.borrow(), before, "{step:?} left changes");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reinstall_keeps_the_group_it_created_removable() {
+        let backend = MemoryBackend::root("alice");
+        let config = Config::default();
+        install(&backend, &config).unwrap();
+        install(&backend, &config).unwrap();
+
+        uninstall_plan(&backend, &config)
+            .unwrap()
+            .execute(&backend)
+            .unwrap();
+        assert!(!backend.group_exists(&bpf_group()));
+    }
+
+    #[test]
+    fn uninstall_removes_what_install_created() {
+        let backend = MemoryBackend::root("alice");
+        let config = Config::default();
+        install(&backend, &config).unwrap();
+
+        uninstall_plan(&backend, &config)
+            .unwrap()
+            .execute(&backend)
+            .unwrap();
+        let state = backend.state.borrow();
+        assert!(state.groups.is_empty());
+        assert!(state.files.is_empty());
+        assert!(state.dirs.is_empty());
+        assert!(state.loaded_daemons.is_empty());
+    }
+
+    #[test]
+    fn uninstall_only_removes_members_added_to_a_preexisting_group() {
+        let backend = MemoryBackend::root("alice");
+        preexisting_group(&backend, &["bob"]);
+        let config = Config::default();
+        install(&backend, &config).unwrap();
+
+        uninstall_plan(&backend, &config)
+            .unwrap()
+            .execute(&backend)
+            .unwrap();
+        let state = backend.state.borrow();
+        let group = &state.groups[&bpf_group()];
+        assert_eq!(group.gid, 300);
+        assert_eq!(group.users.iter().collect::<Vec<_>>(), ["bob"]);
+        assert!(group.groups.is_empty());
+    }
+
+    #[test]
+    fn uninstall_reports_the_step_that_failed() {
+        let config = Config::default();
+        for step in Step::ALL {
+            let backend = MemoryBackend {
+                fail_at: Some(step),
+                ..MemoryBackend::root("alice")
+            };
+            let healthy = MemoryBackend::root("alice");
+            install(&healthy, &config).unwrap();
+            *backend.state.borrow_mut() = healthy.state.borrow().clone();
+
+            let plan = uninstall_plan(&backend, &config).unwrap();
+            let reached = plan.actions.iter().any(|action| fails_at(action, step));
+            match plan.execute(&backend) {
+                Ok(()) => assert!(!reached, "uninstall did not reach {step:?}"),
+                Err(error) => {
+                    assert!(reached, "{step:?}: {error:#}");
+                    assert!(
+                        format!("{error:#}").contains("uninstall failed at step"),
+                        "{step:?}: {error:#}"
+                    );
+                }
+            }
+        }
+    }
+
+    fn config_with_support_dir(support_dir: &str) -> Config {
+        let mut config = Config::default();
+        config.paths.support_dir = support_dir.to_string();
+        config
+    }
+
+    #[test]
+    fn install_refuses_a_support_dir_holding_other_files() {
+        let backend = MemoryBackend::root("alice");
+        {
+            let mut state = backend.state.borrow_mut();
+            state.dirs.insert("/usr/local/bin".to_string());
+            state
+                .files
+                .insert("/usr/local/bin/tcpdump".to_string(), b"tool".to_vec());
+        }
+        let error = install_plan(
+            &backend,
+            &config_with_support_dir("/usr/local/bin"),
+            &LaunchdPlist::default(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("tcpdump"), "{error:#}");
+    }
+
+    #[test]
+    fn install_only_changes_its_own_files_in_a_directory_it_did_not_create() {
+        let backend = MemoryBackend::root("alice");
+        backend
+            .state
+            .borrow_mut()
+            .dirs
+            .insert("/opt/chmod-bpf".to_string());
+        let config = config_with_support_dir("/opt/chmod-bpf");
+        let plan = install_plan(&backend, &config, &LaunchdPlist::default()).unwrap();
+        for action in &plan.actions {
+            if let Action::SetOwner {
+                path, recursive, ..
+            }
+            | Action::SetMode {
+                path, recursive, ..
+            } = action
+            {
+                assert!(!recursive, "{action}");
+                assert_ne!(path, "/opt/chmod-bpf", "{action}");
+            }
+        }
+        plan.execute(&backend).unwrap();
+
+        uninstall_plan(&backend, &config)
+            .unwrap()
+            .execute(&backend)
+            .unwrap();
+        let state = backend.state.borrow();
+        assert!(state.dirs.contains("/opt/chmod-bpf"));
+        assert!(state.files.is_empty());
+    }
+
+    #[test]
+    fn uninstall_leaves_files_it_did_not_install() {
+        let backend = MemoryBackend::root("alice");
+        let config = Config::default();
+        install(&backend, &config).unwrap();
+        let notes = Path::new(&config.paths.support_dir)
+            .join("notes.txt")
+            .to_string_lossy()
+            .into_owned();
+        backend
+            .state
+            .borrow_mut()
+            .files
+            .insert(notes.clone(), b"keep me".to_vec());
+
+        let plan = uninstall_plan(&backend, &config).unwrap();
+        assert!(!plan.actions.iter().any(|action| matches!(
+            action,
+            Action::RemoveDir { .. } | Action::RemoveEmptyDir { .. }
+        )));
+        plan.execute(&backend).unwrap();
+        let state = backend.state.borrow();
+        assert_eq!(state.files.keys().collect::<Vec<_>>(), [&notes]);
+    }
+
+    #[test]
+    fn uninstall_keeps_files_that_changed_since_install() {
+        let backend = MemoryBackend::root("alice");
+        let config = Config::default();
+        install(&backend, &config).unwrap();
+        let plist_path = LaunchdPlist::default().path();
+        backend
+            .state
+            .borrow_mut()
+            .files
+            .insert(config.binary_path(), b"patched".to_vec());
+
+        let plan = uninstall_plan(&backend, &config).unwrap();
+        let removed: Vec<&str> = plan
+            .actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::RemoveFile { path } => Some(path.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(removed, [plist_path.as_str(), &config.manifest_path()]);
+        plan.execute(&backend).unwrap();
+
+        let state = backend.state.borrow();
+        assert_eq!(state.files[&config.binary_path()], b"patched");
+        assert!(state.dirs.contains(&config.paths.support_dir));
+    }
+
+    #[test]
+    fn uninstall_without_a_manifest_removes_the_default_assets_by_name() {
+        let backend = MemoryBackend::root("alice");
+        let config = Config::default();
+        install(&backend, &config).unwrap();
+        backend
+            .state
+            .borrow_mut()
+            .files
+            .remove(&config.manifest_path());
+
+        uninstall_plan(&backend, &config)
+            .unwrap()
+            .execute(&backend)
+            .unwrap();
+        let state = backend.state.borrow();
+        assert!(state.files.is_empty());
+        assert!(state.dirs.is_empty());
+        // Without a manifest the group may predate chmod-bpf.
+        assert!(state.groups.contains_key(&bpf_group()));
+    }
+
+    #[test]
+    fn migrate_points_the_daemon_at_wiresharks_group() {
+        let backend = MemoryBackend::root("alice");
+        {
+            let mut state = backend.state.borrow_mut();
+            state.files.insert(
+                resource::WIRESHARK_CHMODBPF_PLIST_PATH.to_string(),
+                b"<plist/>".to_vec(),
+            );
+            state.groups.insert(
+                resource::WIRESHARK_CHMODBPF_GROUP.to_string(),
+                MemoryGroup {
+                    gid: 300,
+                    ..MemoryGroup::default()
+                },
+            );
+        }
+        let mut config = Config::default();
+        config.group.name = "netcapture".to_string();
+
+        migrate_plan(&backend, &config, &LaunchdPlist::default())
+            .unwrap()
+            .execute(&backend)
+            .unwrap();
+        let plist_path = LaunchdPlist::default().path();
+        let xml = backend.read_file(&plist_path).unwrap().unwrap();
+        let plist = LaunchdPlist::parse(std::str::from_utf8(&xml).unwrap()).unwrap();
+        assert_eq!(
+            plist.program_arguments[1..],
+            ["--group", resource::WIRESHARK_CHMODBPF_GROUP, "apply"]
+        );
+        let manifest = manifest::load(&backend, &config.manifest_path())
+            .unwrap()
+            .unwrap();
+        assert_eq!(manifest.group, resource::WIRESHARK_CHMODBPF_GROUP);
+        assert!(manifest.group_preexisted);
+        assert!(!backend.path_exists(resource::WIRESHARK_CHMODBPF_PLIST_PATH));
+        assert!(!backend.group_exists("netcapture"));
+    }
+
+    #[test]
+    fn failed_migrate_keeps_wiresharks_daemon() {
+        let wireshark_script = format!("{}/ChmodBPF", resource::WIRESHARK_CHMODBPF_DIR_PATH);
+        for step in Step::ALL {
+            let backend = MemoryBackend {
+                fail_at: Some(step),
+                ..MemoryBackend::root("alice")
+            };
+            {
+                let mut state = backend.state.borrow_mut();
+                state.files.insert(
+                    resource::WIRESHARK_CHMODBPF_PLIST_PATH.to_string(),
+                    b"<plist/>".to_vec(),
+                );
+                state
+                    .dirs
+                    .insert(resource::WIRESHARK_CHMODBPF_DIR_PATH.to_string());
+                state
+                    .files
+                    .insert(wireshark_script.clone(), b"#!/bin/sh".to_vec());
+            }
+            let Err(error) = migrate_plan(&backend, &Config::default(), &LaunchdPlist::default())
+                .unwrap()
+                .execute(&backend)
+            else {
+                continue;
+            };
+            assert!(
+                format!("{error:#}").contains("migrate failed at step"),
+                "{step:?}: {error:#}"
+            );
+            assert!(
+                backend.path_exists(resource::WIRESHARK_CHMODBPF_PLIST_PATH),
+                "{step:?} removed Wireshark's plist"
+            );
+            // Only removing the plist itself comes after the directory.
+            if step != Step::RemoveFile {
+                assert!(
+                    backend.path_exists(&wireshark_script),
+                    "{step:?} removed Wireshark's script"
+                );
+            }
+        }
+    }
+
+    /// Whether applying the action runs the backend step.
+    fn fails_at(action: &Action, step: Step) -> bool {
+        let action_step = match action {
+            Action::CreateGroup { .. } => Step::CreateGroup,
+            Action::DeleteGroup { .. } => Step::DeleteGroup,
+            Action::AddGroupToGroup { .. } => Step::AddGroupToGroup,
+            Action::AddUserToGroup { .. } => Step::AddUserToGroup,
+            Action::RemoveGroupFromGroup { .. } => Step::RemoveGroupFromGroup,
+            Action::RemoveUserFromGroup { .. } => Step::RemoveUserFromGroup,
+            Action::CreateDir { .. } => Step::CreateDir,
+            Action::WriteFile { .. } => Step::WriteFile,
+            Action::RemoveDir { .. } | Action::RemoveEmptyDir { .. } => Step::RemoveDir,
+            Action::RemoveFile { .. } => Step::RemoveFile,
+            Action::SetOwner { .. } => Step::SetOwner,
+            Action::SetMode { .. } => Step::SetPermissions,
+            // A failed unload is tolerated when the daemon may not be loaded.
+            Action::BootoutDaemon { allow_failure, .. } => {
+                if *allow_failure {
+                    return false;
+                }
+                Step::BootoutDaemon
+            }
+            Action::BootstrapDaemon { .. } => Step::BootstrapDaemon,
+        };
+        action_step == step
+    }
+}
@@ -0,0 +1,584 @@
+use anyhow::{Result, anyhow, bail};
+
+use crate::{output, resource};
+
+pub const DEFAULT_LABEL: &str = "com.foctal.chmod-bpf";
+pub const LAUNCH_DAEMONS_DIR: &str = "/Library/LaunchDaemons";
+
+const PLIST_HEADER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple Computer//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+"#;
+
+/// Subset of property list values used by launchd job definitions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlistValue {
+    String(String),
+    Integer(i64),
+    Bool(bool),
+    Array(Vec<PlistValue>),
+    /// Entries in document order.
+    Dict(Vec<(String, PlistValue)>),
+}
+
+impl PlistValue {
+    pub fn get(&self, key: &str) -> Option<&PlistValue> {
+        match self {
+            PlistValue::Dict(entries) => entries
+                .iter()
+                .find(|(entry_key, _)| entry_key == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            PlistValue::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            PlistValue::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[PlistValue]> {
+        match self {
+            PlistValue::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// Renders the value as a complete XML property list document.
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::from(PLIST_HEADER);
+        self.write_xml(&mut xml, 0);
+        xml.push_str("</plist>\n");
+        xml
+    }
+
+    fn write_xml(&self, xml: &mut String, depth: usize) {
+        let indent = "\t".repeat(depth);
+        match self {
+            PlistValue::String(value) => xml.push_str(&format!(
+                "{indent}<string>{}</string>\n",
+                output::escape_xml(value)
+            )),
+            PlistValue::Integer(value) => {
+                xml.push_str(&format!("{indent}<integer>{value}</integer>\n"))
+            }
+            PlistValue::Bool(true) => xml.push_str(&format!("{indent}<true/>\n")),
+            PlistValue::Bool(false) => xml.push_str(&format!("{indent}<false/>\n")),
+            PlistValue::Array(values) => {
+                xml.push_str(&format!("{indent}<array>\n"));
+                for value in values {
+                    value.write_xml(xml, depth + 1);
+                }
+                xml.push_str(&format!("{indent}</array>\n"));
+            }
+            PlistValue::Dict(entries) => {
+                xml.push_str(&format!("{indent}<dict>\n"));
+                for (key, value) in entries {
+                    xml.push_str(&format!(
+                        "{indent}\t<key>{}</key>\n",
+                        output::escape_xml(key)
+                    ));
+                    value.write_xml(xml, depth + 1);
+                }
+                xml.push_str(&format!("{indent}</dict>\n"));
+            }
+        }
+    }
+
+    /// Parses an XML property list document.
+    pub fn from_xml(xml: &str) -> Result<Self> {
+        let mut parser = Parser { input: xml, pos: 0 };
+        let tag = parser.next_tag()?;
+        if tag.name != "plist" || tag.closing {
+            bail!("Expected a <plist> root element, found <{}>", tag.name);
+        }
+        let value = if tag.self_closing {
+            PlistValue::Dict(Vec::new())
+        } else {
+            let value = parser.parse_value()?;
+            parser.expect_closing("plist")?;
+            value
+        };
+        Ok(value)
+    }
+}
+
+/// Launchd job definition for the chmod-bpf daemon.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LaunchdPlist {
+    pub label: String,
+    pub program_arguments: Vec<String>,
+    pub run_at_load: bool,
+    pub keep_alive: Option<bool>,
+    pub standard_out_path: Option<String>,
+    pub standard_error_path: Option<String>,
+    pub watch_paths: Vec<String>,
+}
+
+impl Default for LaunchdPlist {
+    fn default() -> Self {
+        Self {
+            label: DEFAULT_LABEL.to_string(),
+            program_arguments: vec![
+                resource::CHMOD_BPF_BINARY_PATH.to_string(),
+                "apply".to_string(),
+            ],
+            run_at_load: true,
+            keep_alive: None,
+            standard_out_path: None,
+            standard_error_path: None,
+            watch_paths: Vec::new(),
+        }
+    }
+}
+
+impl LaunchdPlist {
+    /// Where launchd expects the job definition for this label.
+    pub fn path(&self) -> String {
+        format!("{LAUNCH_DAEMONS_DIR}/{}.plist", self.label)
+    }
+
+    /// Passes `--group <group>` to the program so `apply` uses that group
+    /// whatever the config file it reads says.
+    pub fn set_apply_group(&mut self, group: &str) {
+        let arguments = &mut self.program_arguments;
+        match arguments.iter().position(|argument| argument == "--group") {
+            Some(index) if index + 1 < arguments.len() => arguments[index + 1] = group.to_string(),
+            _ => {
+                let at = arguments
+                    .iter()
+                    .rposition(|argument| argument == "apply")
+                    .unwrap_or(arguments.len());
+                arguments.splice(at..at, ["--group".to_string(), group.to_string()]);
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.label.is_empty()
+            || !self
+                .label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        {
+            bail!(
+                "Invalid launchd label {:?}: use letters, digits, '.', '-' and '_'",
+                self.label
+            );
+        }
+        if self.program_arguments.is_empty() {
+            bail!("ProgramArguments must not be empty");
+        }
+        Ok(())
+    }
+
+    pub fn to_value(&self) -> PlistValue {
+        let mut entries = vec![
+            ("Label".to_string(), PlistValue::String(self.label.clone())),
+            ("RunAtLoad".to_string(), PlistValue::Bool(self.run_at_load)),
+        ];
+        if let Some(keep_alive) = self.keep_alive {
+            entries.push(("KeepAlive".to_string(), PlistValue::Bool(keep_alive)));
+        }
+        entries.push((
+            "ProgramArguments".to_string(),
+            string_array(&self.program_arguments),
+        ));
+        if let Some(path) = &self.standard_out_path {
+            entries.push((
+                "StandardOutPath".to_string(),
+                PlistValue::String(path.clone()),
+            ));
+        }
+        if let Some(path) = &self.standard_error_path {
+            entries.push((
+                "StandardErrorPath".to_string(),
+                PlistValue::String(path.clone()),
+            ));
+        }
+        if !self.watch_paths.is_empty() {
+            entries.push(("WatchPaths".to_string(), string_array(&self.watch_paths)));
+        }
+        PlistValue::Dict(entries)
+    }
+
+    pub fn render(&self) -> String {
+        self.to_value().to_xml()
+    }
+
+    pub fn parse(xml: &str) -> Result<Self> {
+        let value = PlistValue::from_xml(xml)?;
+        let label = value
+            .get("Label")
+            .and_then(PlistValue::as_str)
+            .ok_or_else(|| anyhow!("Plist is missing a Label string"))?;
+        let program_arguments = match value.get("ProgramArguments") {
+            Some(arguments) => strings(arguments, "ProgramArguments")?,
+            // launchd falls back to Program when ProgramArguments is absent.
+            None => value
+                .get("Program")
+                .and_then(PlistValue::as_str)
+                .map(|program| vec![program.to_string()])
+                .ok_or_else(|| anyhow!("Plist has neither ProgramArguments nor Program"))?,
+        };
+        let optional_string = |key: &str| {
+            value
+                .get(key)
+                .and_then(PlistValue::as_str)
+                .map(str::to_string)
+        };
+        Ok(Self {
+            label: label.to_string(),
+            program_arguments,
+            run_at_load: value
+                .get("RunAtLoad")
+                .and_then(PlistValue::as_bool)
+                .unwrap_or(false),
+            keep_alive: value.get("KeepAlive").and_then(PlistValue::as_bool),
+            standard_out_path: optional_string("StandardOutPath"),
+            standard_error_path: optional_string("StandardErrorPath"),
+            watch_paths: match value.get("WatchPaths") {
+                Some(paths) => strings(paths, "WatchPaths")?,
+                None => Vec::new(),
+            },
+        })
+    }
+}
+
+fn string_array(values: &[String]) -> PlistValue {
+    PlistValue::Array(values.iter().cloned().map(PlistValue::String).collect())
+}
+
+fn strings(value: &PlistValue, key: &str) -> Result<Vec<String>> {
+    value
+        .as_array()
+        .ok_or_else(|| anyhow!("{key} must be an array"))?
+        .iter()
+        .map(|item| {
+            item.as_str()
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("{key} must only contain strings"))
+        })
+        .collect()
+}
+
+fn unescape(value: &str) -> Result<String> {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find(';')
+            .ok_or_else(|| anyhow!("Unterminated entity in {value:?}"))?;
+        let entity = &rest[start + 1..start + end];
+        let c = match entity {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = if let Some(hex) = entity.strip_prefix("#x") {
+                    u32::from_str_radix(hex, 16).ok()
+                } else if let Some(decimal) = entity.strip_prefix('#') {
+                    decimal.parse().ok()
+                } else {
+                    None
+                };
+                code.and_then(char::from_u32)
+                    .ok_or_else(|| anyhow!("Unknown entity &{entity};"))?
+            }
+        };
+        unescaped.push(c);
+        rest = &rest[start + end + 1..];
+    }
+    unescaped.push_str(rest);
+    Ok(unescaped)
+}
+
+struct Tag {
+    name: String,
+    closing: bool,
+    self_closing: bool,
+}
+
+/// Minimal pull parser for the XML property list format.
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    /// Skips whitespace, the XML declaration, the doctype and comments.
+    fn skip_misc(&mut self) -> Result<()> {
+        loop {
+            let trimmed = self.rest().trim_start();
+            self.pos = self.input.len() - trimmed.len();
+            let terminator = if trimmed.starts_with("<?") {
+                "?>"
+            } else if trimmed.starts_with("<!--") {
+                "-->"
+            } else if trimmed.starts_with("<!") {
+                ">"
+            } else {
+                return Ok(());
+            };
+            let end = trimmed
+                .find(terminator)
+                .ok_or_else(|| anyhow!("Unterminated markup in plist"))?;
+            self.pos += end + terminator.len();
+        }
+    }
+
+    fn next_tag(&mut self) -> Result<Tag> {
+        self.skip_misc()?;
+        let rest = self.rest();
+        if !rest.starts_with('<') {
+            bail!("Expected a tag at offset {}", self.pos);
+        }
+        let end = rest
+            .find('>')
+            .ok_or_else(|| anyhow!("Unterminated tag at offset {}", self.pos))?;
+        let inner = &rest[1..end];
+        self.pos += end + 1;
+
+        let closing = inner.starts_with('/');
+        let self_closing = inner.ends_with('/');
+        let inner = inner.trim_start_matches('/').trim_end_matches('/');
+        let name = inner.split_whitespace().next().unwrap_or_default();
+        Ok(Tag {
+            name: name.to_string(),
+            closing,
+            self_closing,
+        })
+    }
+
+    fn peek_closing(&mut self) -> Result<bool> {
+        self.skip_misc()?;
+        Ok(self.rest().starts_with("</"))
+    }
+
+    fn expect_closing(&mut self, name: &str) -> Result<()> {
+        let tag = self.next_tag()?;
+        if tag.closing && tag.name == name {
+            Ok(())
+        } else {
+            Err(anyhow!("Expected </{name}>, found <{}>", tag.name))
+        }
+    }
+
+    fn text(&mut self, name: &str) -> Result<String> {
+        let closing = format!("</{name}>");
+        let end = self
+            .rest()
+            .find(&closing)
+            .ok_or_else(|| anyhow!("Missing {closing}"))?;
+        let text = unescape(&self.rest()[..end])?;
+        self.pos += end + closing.len();
+        Ok(text)
+    }
+
+    fn parse_value(&mut self) -> Result<PlistValue> {
+        let tag = self.next_tag()?;
+        if tag.closing {
+            bail!("Unexpected </{}>", tag.name);
+        }
+        let empty = tag.self_closing;
+        match tag.name.as_str() {
+            "true" => Ok(PlistValue::Bool(true)),
+            "false" => Ok(PlistValue::Bool(false)),
+            "string" if empty => Ok(PlistValue::String(String::new())),
+            "string" => Ok(PlistValue::String(self.text("string")?)),
+            "integer" => {
+                let text = self.text("integer")?;
+                let value = text
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid integer {text:?}"))?;
+                Ok(PlistValue::Integer(value))
+            }
+            "array" if empty => Ok(PlistValue::Array(Vec::new())),
+            "array" => {
+                let mut values = Vec::new();
+                while !self.peek_closing()? {
+                    values.push(self.parse_value()?);
+                }
+                self.expect_closing("array")?;
+                Ok(PlistValue::Array(values))
+            }
+            "dict" if empty => Ok(PlistValue::Dict(Vec::new())),
+            "dict" => {
+                let mut entries = Vec::new();
+                while !self.peek_closing()? {
+                    let key = self.next_tag()?;
+                    if key.name != "key" || key.closing || key.self_closing {
+                        bail!("Expected <key> in dict, found <{}>", key.name);
+                    }
+                    let key = self.text("key")?;
+                    entries.push((key, self.parse_value()?));
+                }
+                self.expect_closing("dict")?;
+                Ok(PlistValue::Dict(entries))
+            }
+            other => Err(anyhow!("Unsupported plist element <{other}>")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The job definition shipped in resources/ before the plist was generated.
+    const LEGACY_PLIST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple Computer//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>Label</key>
+	<string>com.foctal.chmod-bpf</string>
+	<key>RunAtLoad</key>
+	<true/>
+	<key>ProgramArguments</key>
+    <array>
+        <string>/bin/zsh</string>
+        <string>/Library/Application Support/Foctal/chmod-bpf/chmod-bpf.sh</string>
+    </array>
+</dict>
+</plist>
+"#;
+
+    #[test]
+    fn render_then_parse_round_trips_every_key() {
+        let plist = LaunchdPlist {
+            label: "org.example.chmod-bpf".to_string(),
+            program_arguments: vec![
+                "/usr/local/bin/chmod-bpf".to_string(),
+                "--config".to_string(),
+                "/etc/chmod-bpf.toml".to_string(),
+                "apply".to_string(),
+            ],
+            run_at_load: false,
+            keep_alive: Some(true),
+            standard_out_path: Some("/var/log/chmod-bpf.out".to_string()),
+            standard_error_path: Some("/var/log/chmod-bpf.err".to_string()),
+            watch_paths: vec!["/dev".to_string(), "/etc/chmod-bpf.toml".to_string()],
+        };
+        let rendered = plist.render();
+        assert!(rendered.starts_with(PLIST_HEADER));
+        assert_eq!(LaunchdPlist::parse(&rendered).unwrap(), plist);
+    }
+
+    #[test]
+    fn default_plist_round_trips() {
+        let plist = LaunchdPlist::default();
+        plist.validate().unwrap();
+        assert_eq!(LaunchdPlist::parse(&plist.render()).unwrap(), plist);
+    }
+
+    #[test]
+    fn reserved_characters_are_escaped() {
+        let plist = LaunchdPlist {
+            label: r#"a&b<c>"d'e"#.to_string(),
+            program_arguments: vec![r#"/opt/Tom & Jerry's "<bpf>"/chmod-bpf"#.to_string()],
+            standard_out_path: Some("/tmp/<out>&".to_string()),
+            ..LaunchdPlist::default()
+        };
+        let rendered = plist.render();
+        assert!(rendered.contains("<string>a&amp;b&lt;c&gt;&quot;d&apos;e</string>"));
+        assert!(rendered.contains(
+            "<string>/opt/Tom &amp; Jerry&apos;s &quot;&lt;bpf&gt;&quot;/chmod-bpf</string>"
+        ));
+        assert_eq!(LaunchdPlist::parse(&rendered).unwrap(), plist);
+        assert!(plist.validate().is_err());
+    }
+
+    #[test]
+    fn unescape_rejects_unknown_entities() {
+        assert_eq!(unescape("&lt;&#60;&#x3c;").unwrap(), "<<<");
+        assert!(unescape("&nbsp;").is_err());
+        assert!(unescape("a & b").is_err());
+    }
+
+    #[test]
+    fn parses_the_legacy_resource_layout() {
+        let plist = LaunchdPlist::parse(LEGACY_PLIST).unwrap();
+        assert_eq!(
+            plist,
+            LaunchdPlist {
+                label: DEFAULT_LABEL.to_string(),
+                program_arguments: vec![
+                    "/bin/zsh".to_string(),
+                    "/Library/Application Support/Foctal/chmod-bpf/chmod-bpf.sh".to_string(),
+                ],
+                run_at_load: true,
+                keep_alive: None,
+                standard_out_path: None,
+                standard_error_path: None,
+                watch_paths: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_falls_back_to_program() {
+        let xml = format!(
+            "{PLIST_HEADER}<dict><key>Label</key><string>x</string>\
+             <key>Program</key><string>/bin/true</string></dict></plist>"
+        );
+        let plist = LaunchdPlist::parse(&xml).unwrap();
+        assert_eq!(plist.program_arguments, ["/bin/true"]);
+        assert!(!plist.run_at_load);
+    }
+
+    #[test]
+    fn parse_rejects_malformed_plists() {
+        for xml in [
+            "",
+            "<plist><dict><key>Label</key></dict></plist>",
+            "<plist><dict><key>Label</key><string>x</string></dict></plist>",
+            "<plist><dict><key>Label</key><string>x</dict></plist>",
+            "<plist><dict><key>Label</key><string>x</string>\
+             <key>ProgramArguments</key><string>/bin/true</string></dict></plist>",
+        ] {
+            assert!(LaunchdPlist::parse(xml).is_err(), "{xml:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn set_apply_group_replaces_or_inserts_the_flag() {
+        let mut plist = LaunchdPlist::default();
+        plist.set_apply_group("wireshark");
+        assert_eq!(
+            plist.program_arguments,
+            [
+                resource::CHMOD_BPF_BINARY_PATH,
+                "--group",
+                "wireshark",
+                "apply"
+            ]
+        );
+        plist.set_apply_group("access_bpf");
+        assert_eq!(
+            plist.program_arguments,
+            [
+                resource::CHMOD_BPF_BINARY_PATH,
+                "--group",
+                "access_bpf",
+                "apply"
+            ]
+        );
+    }
+}
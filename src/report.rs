@@ -0,0 +1,679 @@
+use anyhow::{Context, Result};
+use chrono::Local;
+use serde::Serialize;
+use std::path::Path;
+use termtree::Tree;
+use tracing::{debug, info};
+
+use crate::{
+    backend::SystemBackend,
+    bpf::{self, DeviceCount, DeviceStatus, DeviceSummary, DeviceVerdict},
+    config::Config,
+    daemon::DaemonReport,
+    drift::{self, DriftState, FileDrift},
+    holders::{self, HolderReport},
+    output::{self, node_label},
+    security::{self, SecurityFinding, Severity},
+    user::GroupMembership,
+};
+
+/// Outcome of one section of `check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+/// Status and human-readable findings for one audited area.
+#[derive(Debug, Clone, Serialize)]
+pub struct Section {
+    pub status: Status,
+    pub messages: Vec<String>,
+    /// Suggested remediation for a failing section.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hints: Vec<String>,
+}
+
+impl Section {
+    fn new(status: Status, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            messages: vec![message.into()],
+            hints: Vec::new(),
+        }
+    }
+
+    fn ok(message: impl Into<String>) -> Self {
+        Self::new(Status::Ok, message)
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        Self::new(Status::Error, message)
+    }
+
+    fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hints.push(hint.into());
+        self
+    }
+}
+
+/// Exit status of `check` when every section passes.
+pub const EXIT_OK: u8 = 0;
+/// Exit status when the check itself could not run.
+pub const EXIT_INTERNAL_ERROR: u8 = 1;
+/// Exit status when some BPF devices are not readable and writable.
+pub const EXIT_PERMISSION_FAILURE: u8 = 3;
+/// Exit status when the user is not a member of the BPF group.
+pub const EXIT_GROUP_FAILURE: u8 = 4;
+/// Exit status when no launch daemon configuration is installed.
+pub const EXIT_DAEMON_MISSING: u8 = 5;
+
+/// Exit status when installed assets differ from this version's.
+pub const EXIT_DRIFT: u8 = 6;
+/// Exit status when `--security` finds a risky owner, mode or file.
+pub const EXIT_INSECURE: u8 = 7;
+
+/// Failing devices listed in the permissions section before the rest are
+/// left to `--devices`.
+const MAX_LISTED_DEVICES: usize = 10;
+
+/// What `check` should include beyond the default sections.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CheckOptions {
+    /// Include the per-device table.
+    pub devices: bool,
+    /// Treat warnings as failures in the exit status.
+    pub strict: bool,
+    /// Include unified diffs for drifted text files.
+    pub diff: bool,
+    /// Audit the owners and modes on the paths to the installed assets.
+    pub security: bool,
+    /// Under sudo, switch to the resolved user's credentials to ask the
+    /// kernel for each device's access verdict.
+    pub impersonate: bool,
+}
+
+/// Everything `check` found, in a form that can be printed or serialised.
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckReport {
+    pub version: String,
+    /// Most severe status across all sections.
+    pub status: Status,
+    /// RFC 3339 time the check ran.
+    pub checked_at: String,
+    /// Operator the permissions were evaluated for (`SUDO_USER` when set).
+    pub user: Option<String>,
+    pub group: String,
+    pub permissions: Section,
+    pub device_summary: DeviceSummary,
+    pub device_count: Section,
+    pub device_limits: DeviceCount,
+    /// Every BPF device, when requested with `--devices`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub devices: Option<Vec<DeviceStatus>>,
+    /// Processes holding BPF devices open, with `--devices` on platforms
+    /// where they can be listed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub holders: Option<HolderReport>,
+    #[serde(rename = "group_membership")]
+    pub membership: Section,
+    /// Whether the user's membership is active, pending a new login session,
+    /// absent, or the group is missing.
+    pub group_state: GroupMembership,
+    pub daemon: Section,
+    /// Launch daemon definition and its state in launchd.
+    pub launchd: DaemonReport,
+    pub drift: Section,
+    /// How each installed asset compares with this version's.
+    pub drift_files: Vec<FileDrift>,
+    /// Path chain audit of the installed assets, with `--security`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub security: Option<Section>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub security_findings: Option<Vec<SecurityFinding>>,
+}
+
+impl CheckReport {
+    /// Audits device permissions, group membership and the launch daemon.
+    pub fn collect(backend: &dyn SystemBackend, config: &Config, options: CheckOptions) -> Self {
+        let group = config.group.name.clone();
+
+        info!("Validating BPF device permissions");
+        let devices = bpf::audit_bpf_devices(backend, &group, options.impersonate);
+        let (permissions, device_summary) = match &devices {
+            Ok(devices) => {
+                let summary = DeviceSummary::from_devices(devices);
+                (permission_section(devices, &summary), summary)
+            }
+            Err(e) => (Section::error(e.to_string()), DeviceSummary::default()),
+        };
+
+        let holders = if options.devices {
+            info!("Listing processes holding BPF devices open");
+            HolderReport::collect(backend, holders::default_scanner().as_ref())
+                .inspect_err(|error| debug!(?error, "Could not list BPF device holders"))
+                .ok()
+        } else {
+            None
+        };
+
+        info!("Comparing BPF devices with the kernel limit");
+        let kernel_max = bpf::max_devices(backend);
+        let device_limits = DeviceCount::new(
+            device_summary.total,
+            config.devices.count,
+            kernel_max.as_ref().ok().copied(),
+        );
+        let mut device_count = device_count_section(&device_limits);
+        if let Err(e) = kernel_max {
+            device_count.status = device_count.status.max(Status::Warning);
+            device_count
+                .messages
+                .push(format!("Could not read the kernel limit: {e:#}"));
+        }
+
+        info!("Checking current group membership");
+        let user = backend.current_user_name();
+        let group_state = backend.current_user_membership(&group);
+        let membership = membership_section(
+            group_state,
+            user.as_deref().unwrap_or("the current user"),
+            &group,
+        );
+
+        info!("Inspecting known daemon configurations");
+        let launchd = DaemonReport::collect(backend, config);
+        let daemon = daemon_section(&launchd);
+
+        info!("Comparing installed assets with this version");
+        let (drift, drift_files) = match drift::detect_drift(backend, config, options.diff) {
+            Ok(files) => (drift_section(&files), files),
+            Err(e) => (Section::new(Status::Warning, e.to_string()), Vec::new()),
+        };
+
+        let (security, security_findings) = if options.security {
+            info!("Auditing the paths to the installed assets");
+            match security::audit_installed_assets(backend, config) {
+                Ok(findings) => (Some(security_section(&findings)), Some(findings)),
+                Err(e) => (Some(Section::new(Status::Warning, e.to_string())), None),
+            }
+        } else {
+            (None, None)
+        };
+
+        let mut report = Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            status: Status::Ok,
+            checked_at: Local::now().to_rfc3339(),
+            user,
+            group,
+            permissions,
+            device_summary,
+            device_count,
+            device_limits,
+            devices: devices.ok().filter(|_| options.devices),
+            holders,
+            membership,
+            group_state,
+            daemon,
+            launchd,
+            drift,
+            drift_files,
+            security,
+            security_findings,
+        };
+        report.status = report
+            .sections()
+            .iter()
+            .map(|(_, section)| section.status)
+            .max()
+            .unwrap_or(Status::Ok);
+        report
+    }
+
+    pub fn sections(&self) -> Vec<(&'static str, &Section)> {
+        let mut sections = vec![
+            ("Permissions", &self.permissions),
+            ("Device count", &self.device_count),
+            ("Group", &self.membership),
+            ("Daemon", &self.daemon),
+            ("Drift", &self.drift),
+        ];
+        if let Some(security) = &self.security {
+            sections.push(("Security", security));
+        }
+        sections
+    }
+
+    /// Exit status for monitoring: the code of the first failing section, in
+    /// the order permissions (including the device count), group, daemon,
+    /// drift, security.
+    /// With `strict`, warnings fail too.
+    pub fn exit_code(&self, strict: bool) -> u8 {
+        let threshold = if strict {
+            Status::Warning
+        } else {
+            Status::Error
+        };
+        [
+            (&self.permissions, EXIT_PERMISSION_FAILURE),
+            (&self.device_count, EXIT_PERMISSION_FAILURE),
+            (&self.membership, EXIT_GROUP_FAILURE),
+            (&self.daemon, EXIT_DAEMON_MISSING),
+            (&self.drift, EXIT_DRIFT),
+        ]
+        .into_iter()
+        .chain(
+            self.security
+                .as_ref()
+                .map(|security| (security, EXIT_INSECURE)),
+        )
+        .find(|(section, _)| section.status >= threshold)
+        .map_or(EXIT_OK, |(_, code)| code)
+    }
+
+    pub fn render_text(&self) -> String {
+        let mut tree: Tree<String> = Tree::new(node_label("BPF device audit", None, None));
+        if let Some(user) = &self.user {
+            tree.push(node_label("User", Some(user), None));
+        }
+        for (title, section) in self.sections() {
+            let mut section_tree = Tree::new(node_label(title, None, None));
+            section_tree.push(node_label(&status_log(section.status, title), None, None));
+            for (index, message) in section.messages.iter().enumerate() {
+                // The first message carries the verdict; the rest are details.
+                let line = match (index, section.status) {
+                    (0, Status::Ok) => output::get_check_ok_log(message),
+                    (0, Status::Warning) => output::get_check_warn_log(message),
+                    (0, Status::Error) => output::get_check_error_log(message),
+                    _ => message.clone(),
+                };
+                section_tree.push(node_label(&line, None, None));
+            }
+            for hint in &section.hints {
+                section_tree.push(node_label("Hint", Some(hint), None));
+            }
+            tree.push(section_tree);
+        }
+        tree.push(node_label("Checked at", Some(&self.checked_at), Some(" :")));
+        let mut text = format!("\n{tree}\n");
+        if let Some(devices) = &self.devices {
+            text.push_str(&render_device_table(devices, self.holders.as_ref()));
+        }
+        for diff in self
+            .drift_files
+            .iter()
+            .filter_map(|file| file.diff.as_ref())
+        {
+            text.push_str(diff);
+        }
+        text
+    }
+
+    pub fn render_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("Failed to serialize the check report")
+    }
+
+    pub fn render_yaml(&self) -> Result<String> {
+        serde_yaml::to_string(self).context("Failed to serialize the check report")
+    }
+}
+
+/// Colored `[OK]`/`[WARN]`/`[ERROR]` label for a section title.
+pub fn status_log(status: Status, title: &str) -> String {
+    match status {
+        Status::Ok => output::get_ok_log(output::LOG_LABEL_OK, title),
+        Status::Warning => output::get_warn_log(output::LOG_LABEL_WARN, title),
+        Status::Error => output::get_error_log(output::LOG_LABEL_ERROR, title),
+    }
+}
+
+fn daemon_section(launchd: &DaemonReport) -> Section {
+    let Some(plist_path) = &launchd.plist_path else {
+        return Section::error("No known chmod-bpf daemon configuration was found")
+            .with_hint("Run `sudo chmod-bpf install` to install the launch daemon.");
+    };
+    let mut section = Section::ok(format!("Found configuration at {plist_path}"));
+    if !launchd.program_arguments.is_empty() {
+        section
+            .messages
+            .push(format!("Runs: {}", launchd.program_arguments.join(" ")));
+    }
+    if !launchd.errors.is_empty() {
+        section.status = Status::Warning;
+        section
+            .messages
+            .splice(0..0, launchd.errors.iter().cloned());
+    }
+    match &launchd.status {
+        Some(status) => {
+            section
+                .messages
+                .push(format!("Loaded: {}", status.summary()));
+            if let Some(code) = status.last_exit_code.filter(|code| *code != 0) {
+                section.status = section.status.max(Status::Warning);
+                section
+                    .messages
+                    .insert(0, format!("The daemon last exited with code {code}"));
+                section = section.with_hint(
+                    "Check the daemon's log (see --stderr-path on install) or run `sudo chmod-bpf apply` to see the error.",
+                );
+            }
+        }
+        None if launchd.errors.is_empty() => {
+            section.status = Status::Error;
+            section.messages.insert(
+                0,
+                format!(
+                    "{} is not loaded in launchd",
+                    launchd.label.as_deref().unwrap_or(plist_path)
+                ),
+            );
+            section = section.with_hint(format!(
+                "Load it with `sudo launchctl bootstrap system {plist_path}`."
+            ));
+        }
+        None => {}
+    }
+    section
+}
+
+fn drift_section(files: &[FileDrift]) -> Section {
+    if files.is_empty() {
+        return Section::ok("No installed chmod-bpf assets to compare");
+    }
+    let drifted = files
+        .iter()
+        .filter(|file| file.state != DriftState::Matches)
+        .count();
+    let mut section = if drifted == 0 {
+        Section::ok("Installed assets match this version")
+    } else {
+        Section::new(
+            Status::Warning,
+            format!("{drifted} installed asset(s) differ from this version"),
+        )
+        .with_hint("Run `sudo chmod-bpf install` to reinstall; add `--diff` to see the changes.")
+    };
+    for file in files {
+        section
+            .messages
+            .push(format!("{}: {}", file.path, file.state));
+    }
+    section
+}
+
+fn security_section(findings: &[SecurityFinding]) -> Section {
+    let worst = findings.iter().map(|finding| finding.severity).max();
+    let mut section = match worst {
+        None => {
+            return Section::ok(
+                "No risky owners, modes, symlinks or files on the paths to the installed assets",
+            );
+        }
+        Some(Severity::Low) => Section::ok(format!("{} low-severity finding(s)", findings.len())),
+        Some(Severity::Medium) => Section::new(
+            Status::Warning,
+            format!(
+                "{} finding(s) on the paths to the installed assets",
+                findings.len()
+            ),
+        ),
+        Some(_) => Section::error(format!(
+            "The launch daemon runs from a path non-root users can change ({} finding(s))",
+            findings.len()
+        )),
+    };
+    for finding in findings {
+        section.messages.push(finding.to_string());
+    }
+    if worst > Some(Severity::Low) {
+        section = section.with_hint(
+            "Run `sudo chmod-bpf install` to restore the owners and modes, and remove anything chmod-bpf did not install.",
+        );
+    }
+    section
+}
+
+fn device_count_section(count: &DeviceCount) -> Section {
+    let limit = count
+        .kernel_max
+        .map_or("unknown".to_string(), |max| max.to_string());
+    let summary = format!(
+        "{} BPF device(s) exist; target {}, kernel limit {limit}",
+        count.existing, count.target
+    );
+    if count.target_reached() {
+        return Section::ok(summary);
+    }
+    Section::new(Status::Warning, format!("Pre-creation target not reached: {summary}"))
+        .with_hint(
+            "Run `sudo chmod-bpf apply`, or check that the launch daemon ran at boot. Capture tools fail once they run out of free devices.",
+        )
+}
+
+fn membership_section(membership: GroupMembership, user: &str, group: &str) -> Section {
+    match membership {
+        GroupMembership::Active => Section::ok(format!("{user} is an active member of {group}")),
+        GroupMembership::NotActiveInSession => Section::new(
+            Status::Warning,
+            format!("{user} was added to {group}, but this login session predates the change"),
+        )
+        .with_hint("Log out and back in (or reboot) so the new group membership takes effect."),
+        GroupMembership::SessionUnknown => Section::ok(format!(
+            "{user} is a member of {group}; the login session cannot be inspected under sudo"
+        ))
+        .with_hint(format!(
+            "Run `chmod-bpf check` without sudo to confirm this login session has {group}."
+        )),
+        GroupMembership::Absent => Section::error(format!("{user} is not a member of {group}"))
+            .with_hint(format!(
+                "Run `sudo chmod-bpf install`, or add the user with `sudo dseditgroup -o edit -a {user} -t user {group}`, then log in again."
+            )),
+        GroupMembership::GroupMissing => Section::error(format!("Group {group} does not exist"))
+            .with_hint("Run `sudo chmod-bpf install` to create the group."),
+    }
+}
+
+fn permission_section(devices: &[DeviceStatus], summary: &DeviceSummary) -> Section {
+    if devices.is_empty() {
+        return Section::error("No BPF device nodes were found under /dev");
+    }
+    if summary.all_accessible() {
+        let mut section = Section::ok("You have access to all detected BPF devices.");
+        section.messages.push(summary.to_string());
+        return section;
+    }
+    let mut section = Section::error(summary.to_string());
+    let failing: Vec<&DeviceStatus> = devices
+        .iter()
+        .filter(|device| device.verdict != DeviceVerdict::Accessible)
+        .collect();
+    for device in failing.iter().take(MAX_LISTED_DEVICES) {
+        section.messages.push(match (&device.error, &device.group) {
+            (Some(error), _) => format!("{}: {error}", device.path),
+            (None, Some(group)) => format!("{}: {} (group {group})", device.path, device.verdict),
+            (None, None) => format!("{}: {}", device.path, device.verdict),
+        });
+    }
+    if failing.len() > MAX_LISTED_DEVICES {
+        section.messages.push(format!(
+            "... and {} more (use --devices for the full table)",
+            failing.len() - MAX_LISTED_DEVICES
+        ));
+    }
+    section
+}
+
+/// Formats the devices as an aligned table, with the processes holding each
+/// device open when they could be listed.
+fn render_device_table(devices: &[DeviceStatus], holders: Option<&HolderReport>) -> String {
+    let rows: Vec<[String; 7]> = devices
+        .iter()
+        .map(|device| {
+            [
+                device.path.clone(),
+                device.kind.map_or("-".to_string(), |kind| kind.to_string()),
+                device.owner.clone().unwrap_or_else(|| "-".to_string()),
+                device.group.clone().unwrap_or_else(|| "-".to_string()),
+                device.mode.clone().unwrap_or_else(|| "-".to_string()),
+                device.verdict.to_string(),
+                holders
+                    .and_then(|holders| holders.describe(Path::new(&device.path)))
+                    .unwrap_or_else(|| "-".to_string()),
+            ]
+        })
+        .collect();
+    output::render_table(
+        [
+            "DEVICE", "TYPE", "OWNER", "GROUP", "MODE", "VERDICT", "HELD BY",
+        ],
+        rows,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{FileMetadata, MemoryBackend, MemoryDevice};
+    use crate::plan;
+    use crate::plist::LaunchdPlist;
+    use std::path::PathBuf;
+
+    /// Adds `count` devices owned by `gid` with the given mode.
+    fn add_devices(backend: &MemoryBackend, count: u32, gid: u32, mode: u32, accessible: bool) {
+        let mut state = backend.state.borrow_mut();
+        state
+            .sysctls
+            .insert(bpf::BPF_MAX_DEVICES_SYSCTL.to_string(), count.to_string());
+        for index in 0..count {
+            state.devices.insert(
+                PathBuf::from(format!("/dev/bpf{index}")),
+                MemoryDevice {
+                    metadata: FileMetadata { uid: 0, gid, mode },
+                    accessible,
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn check_passes_after_install() {
+        let backend = MemoryBackend::root("alice");
+        let config = Config::default();
+        plan::install_plan(&backend, &config, &LaunchdPlist::default())
+            .unwrap()
+            .execute(&backend)
+            .unwrap();
+        let gid = backend.group_gid(&config.group.name).unwrap();
+        add_devices(&backend, 4, gid, 0o020660, true);
+
+        let report = CheckReport::collect(&backend, &config, CheckOptions::default());
+        for (title, section) in report.sections() {
+            assert_eq!(
+                section.status,
+                Status::Ok,
+                "{title}: {:?}",
+                section.messages
+            );
+        }
+        assert_eq!(report.group_state, GroupMembership::Active);
+        assert_eq!(report.exit_code(true), EXIT_OK);
+    }
+
+    #[test]
+    fn check_before_install_reports_each_failing_section() {
+        let backend = MemoryBackend::root("alice");
+        add_devices(&backend, 4, 0, 0o020600, false);
+
+        let report = CheckReport::collect(&backend, &Config::default(), CheckOptions::default());
+        assert_eq!(report.permissions.status, Status::Error);
+        assert_eq!(
+            report.device_summary.problems[&DeviceVerdict::WrongGroup],
+            4
+        );
+        assert_eq!(report.group_state, GroupMembership::GroupMissing);
+        assert_eq!(report.daemon.status, Status::Error);
+        assert_eq!(report.exit_code(false), EXIT_PERMISSION_FAILURE);
+    }
+
+    #[test]
+    fn check_warns_when_the_session_predates_the_membership() {
+        let backend = MemoryBackend {
+            stale_session: true,
+            ..MemoryBackend::root("alice")
+        };
+        let config = Config::default();
+        plan::install_plan(&backend, &config, &LaunchdPlist::default())
+            .unwrap()
+            .execute(&backend)
+            .unwrap();
+        let gid = backend.group_gid(&config.group.name).unwrap();
+        add_devices(&backend, 4, gid, 0o020660, true);
+
+        let report = CheckReport::collect(&backend, &config, CheckOptions::default());
+        assert_eq!(report.group_state, GroupMembership::NotActiveInSession);
+        assert_eq!(report.membership.status, Status::Warning);
+        assert_eq!(report.exit_code(false), EXIT_OK);
+        assert_eq!(report.exit_code(true), EXIT_GROUP_FAILURE);
+    }
+
+    #[test]
+    fn unknown_session_passes_with_a_hint() {
+        let section = membership_section(GroupMembership::SessionUnknown, "alice", "access_bpf");
+        assert_eq!(section.status, Status::Ok);
+        assert_eq!(
+            section.messages,
+            ["alice is a member of access_bpf; the login session cannot be inspected under sudo"]
+        );
+        assert_eq!(
+            section.hints,
+            ["Run `chmod-bpf check` without sudo to confirm this login session has access_bpf."]
+        );
+    }
+
+    #[test]
+    fn device_count_warns_when_the_target_is_missed() {
+        let section = device_count_section(&DeviceCount::new(4, 256, Some(256)));
+        assert_eq!(section.status, Status::Warning);
+        assert_eq!(
+            section.messages,
+            [
+                "Pre-creation target not reached: 4 BPF device(s) exist; target 256, kernel limit 256"
+            ]
+        );
+        assert_eq!(section.hints.len(), 1);
+
+        let section = device_count_section(&DeviceCount::new(8, 256, Some(8)));
+        assert_eq!(section.status, Status::Ok);
+        assert_eq!(
+            section.messages,
+            ["8 BPF device(s) exist; target 8, kernel limit 8"]
+        );
+    }
+
+    #[test]
+    fn check_warns_when_the_kernel_limit_cannot_be_read() {
+        let backend = MemoryBackend::root("alice");
+        let config = Config::default();
+        add_devices(&backend, config.devices.count, 0, 0o020600, false);
+        backend.state.borrow_mut().sysctls.clear();
+
+        let report = CheckReport::collect(&backend, &config, CheckOptions::default());
+        assert_eq!(report.device_count.status, Status::Warning);
+        assert!(
+            report.device_count.messages[0].ends_with("kernel limit unknown"),
+            "{:?}",
+            report.device_count.messages
+        );
+        assert!(
+            report.device_count.messages[1].starts_with(
+                "Could not read the kernel limit: Unknown sysctl debug.bpf_maxdevices"
+            ),
+            "{:?}",
+            report.device_count.messages
+        );
+    }
+}
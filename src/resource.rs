@@ -1,6 +1,11 @@
 pub const CHMOD_BPF_PLIST_PATH: &str = "/Library/LaunchDaemons/com.foctal.chmod-bpf.plist";
-pub const CHMOD_BPF_PLIST: &str = include_str!("../resources/com.foctal.chmod-bpf.plist");
-pub const CHMOD_BPF_SCRIPT_DIR_PATH: &str = "/Library/Application Support/Foctal/chmod-bpf/";
-pub const CHMOD_BPF_SCRIPT_PATH: &str =
-    "/Library/Application Support/Foctal/chmod-bpf/chmod-bpf.sh";
-pub const CHMOD_BPF_SCRIPT: &str = include_str!("../scripts/chmod-bpf.sh");
+pub const CHMOD_BPF_SUPPORT_DIR_PATH: &str = "/Library/Application Support/Foctal/chmod-bpf";
+/// Copy of the chmod-bpf binary that the launch daemon runs with `apply`.
+pub const CHMOD_BPF_BINARY_PATH: &str = "/Library/Application Support/Foctal/chmod-bpf/chmod-bpf";
+/// Launch daemon installed by Wireshark's ChmodBPF package.
+pub const WIRESHARK_CHMODBPF_PLIST_PATH: &str =
+    "/Library/LaunchDaemons/org.wireshark.ChmodBPF.plist";
+/// Directory holding Wireshark's ChmodBPF script.
+pub const WIRESHARK_CHMODBPF_DIR_PATH: &str = "/Library/Application Support/Wireshark/ChmodBPF";
+/// Group Wireshark's ChmodBPF grants BPF access to.
+pub const WIRESHARK_CHMODBPF_GROUP: &str = "access_bpf";
@@ -0,0 +1,429 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    backend::{FileMetadata, SystemBackend},
+    config::Config,
+    manifest, permission, plan,
+};
+
+/// How much a finding weakens the root-run launch daemon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Low => "low",
+            Severity::Medium => "medium",
+            Severity::High => "high",
+            Severity::Critical => "critical",
+        })
+    }
+}
+
+/// A risky owner, mode, symlink or file on the path to an asset run as root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SecurityFinding {
+    pub severity: Severity,
+    pub path: String,
+    pub issue: String,
+}
+
+impl fmt::Display for SecurityFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.severity, self.path, self.issue)
+    }
+}
+
+/// Judges one path component by its `lstat` owner and mode; `group` names
+/// the owning group for messages and `asset` is set
+/// for the last component and clear for the directories above it. Anything a
+/// non-root user can change, or replace, lets them run code as root.
+pub fn assess(
+    path: &str,
+    metadata: &FileMetadata,
+    group: &str,
+    asset: bool,
+) -> Vec<SecurityFinding> {
+    let mut findings = Vec::new();
+    let mut push = |severity, issue: String| {
+        findings.push(SecurityFinding {
+            severity,
+            path: path.to_string(),
+            issue,
+        })
+    };
+    let mode = metadata.mode;
+    let file_type = mode & 0o170000;
+    let mode_string = permission::mode_string(mode);
+
+    if file_type == 0o120000 {
+        push(
+            if metadata.uid == 0 {
+                Severity::High
+            } else {
+                Severity::Critical
+            },
+            "is a symbolic link; whoever controls its target controls what launchd runs"
+                .to_string(),
+        );
+        return findings;
+    }
+    if metadata.uid != 0 {
+        push(
+            Severity::Critical,
+            format!("is owned by uid {} instead of root", metadata.uid),
+        );
+    }
+    let is_dir = file_type == 0o040000;
+    let sticky = is_dir && mode & 0o1000 != 0;
+    if mode & 0o002 != 0 {
+        // In a sticky directory others can add entries but not replace root's.
+        push(
+            if sticky {
+                Severity::Medium
+            } else {
+                Severity::Critical
+            },
+            format!("is writable by everyone ({mode_string})"),
+        );
+    }
+    if mode & 0o020 != 0 {
+        push(
+            if metadata.gid == 0 {
+                Severity::Low
+            } else {
+                Severity::High
+            },
+            format!("is writable by group {group} ({mode_string})"),
+        );
+    }
+    if asset && mode & 0o6000 != 0 {
+        push(
+            Severity::Medium,
+            format!("has the setuid or setgid bit set ({mode_string})"),
+        );
+    }
+    if !asset && !is_dir {
+        push(Severity::High, "is not a directory".to_string());
+    }
+    findings
+}
+
+/// Walks every component of `path` from `/` down, judging each with
+/// [`assess`]. Returns nothing when the asset is not installed.
+pub fn audit_path_chain(backend: &dyn SystemBackend, path: &Path) -> Result<Vec<SecurityFinding>> {
+    if backend.symlink_metadata(path)?.is_none() {
+        return Ok(Vec::new());
+    }
+    let mut components: Vec<&Path> = path.ancestors().collect();
+    components.reverse();
+    let mut findings = Vec::new();
+    for component in components {
+        let Some(metadata) = backend.symlink_metadata(component)? else {
+            continue;
+        };
+        let group = backend
+            .group_name_by_gid(metadata.gid)
+            .unwrap_or_else(|| metadata.gid.to_string());
+        findings.extend(assess(
+            &component.to_string_lossy(),
+            &metadata,
+            &group,
+            component == path,
+        ));
+    }
+    Ok(findings)
+}
+
+/// Audits the path chains of the installed binary and launch daemon plist,
+/// and the support directory for files install did not put there. Sorted by
+/// severity, most severe first.
+pub fn audit_installed_assets(
+    backend: &dyn SystemBackend,
+    config: &Config,
+) -> Result<Vec<SecurityFinding>> {
+    let plist_path = manifest::load(backend, &config.manifest_path())?
+        .map_or_else(manifest::default_plist_path, |manifest| manifest.plist_path);
+    let binary_path = PathBuf::from(config.binary_path());
+
+    let mut findings = audit_path_chain(backend, &binary_path)?;
+    for finding in audit_path_chain(backend, Path::new(&plist_path))? {
+        // The shared parents are already covered by the binary's chain.
+        if !findings.contains(&finding) {
+            findings.push(finding);
+        }
+    }
+
+    let support_dir = Path::new(&config.paths.support_dir);
+    if backend.symlink_metadata(support_dir)?.is_some() {
+        let expected = plan::support_entries(config);
+        for name in backend.list_dir(support_dir)? {
+            if !expected.contains(&name) {
+                findings.push(SecurityFinding {
+                    severity: Severity::Medium,
+                    path: support_dir.join(&name).to_string_lossy().into_owned(),
+                    issue: "was not installed by chmod-bpf".to_string(),
+                });
+            }
+        }
+    }
+    findings.sort_by_key(|finding| std::cmp::Reverse(finding.severity));
+    Ok(findings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use crate::plist::LaunchdPlist;
+
+    fn metadata(uid: u32, gid: u32, mode: u32) -> FileMetadata {
+        FileMetadata { uid, gid, mode }
+    }
+
+    #[test]
+    fn assess_judges_owner_mode_and_type() {
+        let cases = [
+            ("root file", metadata(0, 0, 0o100755), true, vec![]),
+            ("root dir", metadata(0, 0, 0o040755), false, vec![]),
+            (
+                "symlink owned by root",
+                metadata(0, 0, 0o120755),
+                false,
+                vec![(Severity::High, "is a symbolic link")],
+            ),
+            (
+                "symlink owned by a user",
+                metadata(501, 20, 0o120755),
+                true,
+                vec![(Severity::Critical, "is a symbolic link")],
+            ),
+            (
+                "owned by a user",
+                metadata(501, 0, 0o100755),
+                true,
+                vec![(Severity::Critical, "is owned by uid 501")],
+            ),
+            (
+                "world-writable dir",
+                metadata(0, 0, 0o040757),
+                false,
+                vec![(Severity::Critical, "is writable by everyone")],
+            ),
+            (
+                "sticky world-writable dir",
+                metadata(0, 0, 0o041757),
+                false,
+                vec![(Severity::Medium, "is writable by everyone")],
+            ),
+            (
+                "sticky bit on a file",
+                metadata(0, 0, 0o101757),
+                true,
+                vec![(Severity::Critical, "is writable by everyone")],
+            ),
+            (
+                "group-writable by wheel",
+                metadata(0, 0, 0o100775),
+                true,
+                vec![(Severity::Low, "is writable by group staff")],
+            ),
+            (
+                "group-writable by a user group",
+                metadata(0, 20, 0o100775),
+                true,
+                vec![(Severity::High, "is writable by group staff")],
+            ),
+            (
+                "setuid asset",
+                metadata(0, 0, 0o104755),
+                true,
+                vec![(Severity::Medium, "has the setuid or setgid bit set")],
+            ),
+            (
+                "setgid asset",
+                metadata(0, 0, 0o102755),
+                true,
+                vec![(Severity::Medium, "has the setuid or setgid bit set")],
+            ),
+            (
+                "file as a parent",
+                metadata(0, 0, 0o100755),
+                false,
+                vec![(Severity::High, "is not a directory")],
+            ),
+            (
+                "everything wrong",
+                metadata(501, 20, 0o100777),
+                true,
+                vec![
+                    (Severity::Critical, "is owned by uid 501"),
+                    (Severity::Critical, "is writable by everyone"),
+                    (Severity::High, "is writable by group staff"),
+                ],
+            ),
+        ];
+        for (name, metadata, asset, expected) in cases {
+            let findings = assess("/x", &metadata, "staff", asset);
+            assert_eq!(findings.len(), expected.len(), "{name}: {findings:?}");
+            for (finding, (severity, issue)) in findings.iter().zip(expected) {
+                assert_eq!(finding.severity, severity, "{name}: {finding}");
+                assert!(finding.issue.starts_with(issue), "{name}: {finding}");
+                assert_eq!(finding.path, "/x", "{name}");
+            }
+        }
+    }
+
+    #[test]
+    fn severities_order_from_low_to_critical() {
+        assert!(Severity::Low < Severity::Medium);
+        assert!(Severity::Medium < Severity::High);
+        assert!(Severity::High < Severity::Critical);
+        // Install only warns about findings above low.
+        let risky: Vec<_> = [
+            Severity::Low,
+            Severity::Medium,
+            Severity::High,
+            Severity::Critical,
+        ]
+        .into_iter()
+        .filter(|severity| *severity > Severity::Low)
+        .collect();
+        assert_eq!(
+            risky,
+            [Severity::Medium, Severity::High, Severity::Critical]
+        );
+    }
+
+    #[test]
+    fn path_chain_covers_every_component() {
+        let backend = MemoryBackend::root("alice");
+        backend
+            .state
+            .borrow_mut()
+            .files
+            .insert("/opt/bpf/bin/chmod-bpf".to_string(), Vec::new());
+        assert!(
+            audit_path_chain(&backend, Path::new("/opt/bpf/bin/chmod-bpf"))
+                .unwrap()
+                .is_empty()
+        );
+
+        {
+            let mut state = backend.state.borrow_mut();
+            state.groups.insert(
+                "staff".to_string(),
+                crate::backend::MemoryGroup {
+                    gid: 20,
+                    ..Default::default()
+                },
+            );
+            state
+                .metadata
+                .insert(PathBuf::from("/opt"), metadata(0, 0, 0o120755));
+            state
+                .metadata
+                .insert(PathBuf::from("/opt/bpf"), metadata(0, 20, 0o040775));
+            state
+                .metadata
+                .insert(PathBuf::from("/opt/bpf/bin"), metadata(501, 0, 0o040755));
+        }
+        let findings: Vec<_> = audit_path_chain(&backend, Path::new("/opt/bpf/bin/chmod-bpf"))
+            .unwrap()
+            .into_iter()
+            .map(|finding| (finding.severity, finding.path, finding.issue))
+            .collect();
+        assert_eq!(
+            findings,
+            [
+                (
+                    Severity::High,
+                    "/opt".to_string(),
+                    "is a symbolic link; whoever controls its target controls what launchd runs"
+                        .to_string()
+                ),
+                (
+                    Severity::High,
+                    "/opt/bpf".to_string(),
+                    "is writable by group staff (drwxrwxr-x)".to_string()
+                ),
+                (
+                    Severity::Critical,
+                    "/opt/bpf/bin".to_string(),
+                    "is owned by uid 501 instead of root".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn path_chain_of_a_missing_asset_is_empty() {
+        let backend = MemoryBackend::root("alice");
+        backend
+            .state
+            .borrow_mut()
+            .metadata
+            .insert(PathBuf::from("/tmp"), metadata(0, 0, 0o040777));
+        assert!(
+            audit_path_chain(&backend, Path::new("/tmp/chmod-bpf"))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn installed_assets_report_extra_files_most_severe_first() {
+        let backend = MemoryBackend::root("alice");
+        let config = Config::default();
+        plan::install_plan(&backend, &config, &LaunchdPlist::default())
+            .unwrap()
+            .execute(&backend)
+            .unwrap();
+        assert!(
+            audit_installed_assets(&backend, &config)
+                .unwrap()
+                .is_empty()
+        );
+
+        let support_dir = Path::new(&config.paths.support_dir);
+        let extra = support_dir.join("helper.sh");
+        {
+            let mut state = backend.state.borrow_mut();
+            state
+                .files
+                .insert(extra.to_string_lossy().into_owned(), Vec::new());
+            state.metadata.insert(
+                PathBuf::from(config.binary_path()),
+                metadata(0, 0, 0o100775),
+            );
+            state
+                .metadata
+                .insert(PathBuf::from("/Library"), metadata(0, 0, 0o040757));
+        }
+        let findings = audit_installed_assets(&backend, &config).unwrap();
+        let summary: Vec<_> = findings
+            .iter()
+            .map(|finding| (finding.severity, finding.path.as_str()))
+            .collect();
+        // `/Library` is shared by both chains but reported once.
+        assert_eq!(
+            summary,
+            [
+                (Severity::Critical, "/Library"),
+                (Severity::Medium, extra.to_str().unwrap()),
+                (Severity::Low, config.binary_path().as_str()),
+            ]
+        );
+        assert_eq!(findings[1].issue, "was not installed by chmod-bpf");
+    }
+}
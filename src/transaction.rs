@@ -0,0 +1,389 @@
+use anyhow::Result;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+use tracing::{info, warn};
+
+use crate::{backend::SystemBackend, plan::Action};
+
+/// How a completed step can be reverted.
+#[derive(Debug, Clone)]
+enum Undo {
+    /// Running this action restores the previous state.
+    Revert(Action),
+    /// The step left nothing behind that needs reverting.
+    NotNeeded,
+    /// The step cannot be reverted automatically.
+    Irreversible(&'static str),
+}
+
+#[derive(Debug, Clone)]
+pub enum RollbackOutcome {
+    Undone,
+    NotNeeded,
+    Failed(String),
+    Irreversible(&'static str),
+}
+
+/// Result of rolling back each completed step, most recent first.
+#[derive(Debug, Default)]
+pub struct RollbackReport {
+    pub entries: Vec<(Action, RollbackOutcome)>,
+}
+
+impl RollbackReport {
+    pub fn undone(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|(_, outcome)| matches!(outcome, RollbackOutcome::Undone))
+            .count()
+    }
+
+    /// Steps that are still in effect after the rollback.
+    pub fn remaining(&self) -> Vec<&(Action, RollbackOutcome)> {
+        self.entries
+            .iter()
+            .filter(|(_, outcome)| {
+                matches!(
+                    outcome,
+                    RollbackOutcome::Failed(_) | RollbackOutcome::Irreversible(_)
+                )
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for RollbackReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let remaining = self.remaining();
+        write!(f, "rolled back {} step(s)", self.undone())?;
+        if remaining.is_empty() {
+            return Ok(());
+        }
+        write!(f, "; {} step(s) could not be undone:", remaining.len())?;
+        for (action, outcome) in remaining {
+            match outcome {
+                RollbackOutcome::Failed(error) => write!(f, "\n  - {action}: {error}")?,
+                RollbackOutcome::Irreversible(reason) => write!(f, "\n  - {action}: {reason}")?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Applies actions while recording how to revert each of them.
+pub struct Transaction<'a> {
+    backend: &'a dyn SystemBackend,
+    completed: Vec<(Action, Undo)>,
+    created_groups: BTreeSet<String>,
+    created_paths: BTreeSet<String>,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn new(backend: &'a dyn SystemBackend) -> Self {
+        Self {
+            backend,
+            completed: Vec::new(),
+            created_groups: BTreeSet::new(),
+            created_paths: BTreeSet::new(),
+        }
+    }
+
+    /// Applies the action, recording its undo step only once it succeeded.
+    pub fn apply(&mut self, action: &Action) -> Result<()> {
+        let undo = self.undo_for(action)?;
+        action.apply(self.backend)?;
+        match action {
+            Action::CreateGroup { group, .. } => {
+                self.created_groups.insert(group.clone());
+            }
+            Action::CreateDir { path } | Action::WriteFile { path, .. }
+                if matches!(
+                    undo,
+                    Undo::Revert(Action::RemoveEmptyDir { .. } | Action::RemoveFile { .. })
+                ) =>
+            {
+                self.created_paths.insert(path.clone());
+            }
+            _ => {}
+        }
+        self.completed.push((action.clone(), undo));
+        Ok(())
+    }
+
+    /// Reverts every completed step in reverse order.
+    pub fn rollback(self) -> RollbackReport {
+        let mut report = RollbackReport::default();
+        for (action, undo) in self.completed.into_iter().rev() {
+            let outcome = match undo {
+                Undo::Revert(revert) => match revert.apply(self.backend) {
+                    Ok(()) => {
+                        info!("Rolled back: {action}");
+                        RollbackOutcome::Undone
+                    }
+                    Err(error) => {
+                        warn!(?error, "Failed to roll back: {action}");
+                        RollbackOutcome::Failed(format!("{error:#}"))
+                    }
+                },
+                Undo::NotNeeded => RollbackOutcome::NotNeeded,
+                Undo::Irreversible(reason) => {
+                    warn!("Cannot roll back: {action} ({reason})");
+                    RollbackOutcome::Irreversible(reason)
+                }
+            };
+            report.entries.push((action, outcome));
+        }
+        report
+    }
+
+    fn created_by_transaction(&self, path: &str) -> bool {
+        self.created_paths
+            .iter()
+            .any(|created| Path::new(path).starts_with(created))
+    }
+
+    /// Captures the state needed to revert the action before it runs.
+    fn undo_for(&self, action: &Action) -> Result<Undo> {
+        let backend = self.backend;
+        let undo = match action {
+            Action::CreateGroup { group, .. } => Undo::Revert(Action::DeleteGroup {
+                group: group.clone(),
+            }),
+            Action::AddGroupToGroup {
+                group,
+                target_group,
+            } => {
+                if self.created_groups.contains(target_group) {
+                    // Deleting the group also drops its members.
+                    Undo::NotNeeded
+                } else if backend.group_is_member(group, target_group)? {
+                    Undo::NotNeeded
+                } else {
+                    Undo::Revert(Action::RemoveGroupFromGroup {
+                        group: group.clone(),
+                        target_group: target_group.clone(),
+                    })
+                }
+            }
+            Action::AddUserToGroup { user, group } => {
+                if self.created_groups.contains(group) || backend.user_is_member(user, group) {
+                    Undo::NotNeeded
+                } else {
+                    Undo::Revert(Action::RemoveUserFromGroup {
+                        user: user.clone(),
+                        group: group.clone(),
+                    })
+                }
+            }
+            Action::RemoveGroupFromGroup {
+                group,
+                target_group,
+            } => Undo::Revert(Action::AddGroupToGroup {
+                group: group.clone(),
+                target_group: target_group.clone(),
+            }),
+            Action::RemoveUserFromGroup { user, group } => Undo::Revert(Action::AddUserToGroup {
+                user: user.clone(),
+                group: group.clone(),
+            }),
+            Action::CreateDir { path } => {
+                if backend.path_exists(path) {
+                    Undo::NotNeeded
+                } else {
+                    // Files written into it are removed first, so it is empty again.
+                    Undo::Revert(Action::RemoveEmptyDir { path: path.clone() })
+                }
+            }
+            Action::WriteFile { path, .. } => match backend.read_file(path)? {
+                Some(previous) => Undo::Revert(Action::write_file(path, &previous)),
+                None => Undo::Revert(Action::RemoveFile { path: path.clone() }),
+            },
+            Action::SetOwner { path, .. } | Action::SetMode { path, .. } => {
+                if self.created_by_transaction(path) {
+                    Undo::NotNeeded
+                } else {
+                    Undo::Irreversible("the previous ownership and mode were not recorded")
+                }
+            }
+            Action::BootoutDaemon { plist_path, .. } => {
+                if self.created_by_transaction(plist_path) {
+                    // Nothing could have been loaded from a plist written by this run.
+                    Undo::NotNeeded
+                } else {
+                    Undo::Irreversible("the previously loaded daemon is not reloaded")
+                }
+            }
+            Action::BootstrapDaemon { plist_path } => Undo::Revert(Action::BootoutDaemon {
+                plist_path: plist_path.clone(),
+                allow_failure: false,
+            }),
+            Action::RemoveEmptyDir { path } => {
+                if backend.path_exists(path) {
+                    Undo::Revert(Action::CreateDir { path: path.clone() })
+                } else {
+                    Undo::NotNeeded
+                }
+            }
+            Action::DeleteGroup { .. } => Undo::Irreversible("deleted groups are not recreated"),
+            Action::RemoveDir { .. } | Action::RemoveFile { .. } => {
+                Undo::Irreversible("removed files are not restored")
+            }
+        };
+        Ok(undo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{MemoryBackend, Step};
+
+    fn create_group(group: &str) -> Action {
+        Action::CreateGroup {
+            group: group.to_string(),
+            real_name: group.to_string(),
+            gid: 600,
+        }
+    }
+
+    fn set_mode(path: &str) -> Action {
+        Action::SetMode {
+            path: path.to_string(),
+            mode: "u=rw,go=r".to_string(),
+            recursive: false,
+        }
+    }
+
+    fn outcomes(report: &RollbackReport) -> Vec<(Action, String)> {
+        report
+            .entries
+            .iter()
+            .map(|(action, outcome)| (action.clone(), format!("{outcome:?}")))
+            .collect()
+    }
+
+    #[test]
+    fn rollback_reverts_the_most_recent_step_first() {
+        let backend = MemoryBackend::root("alice");
+        let before = backend.state.borrow().clone();
+        let actions = [
+            create_group("bpf"),
+            Action::CreateDir {
+                path: "/x/chmod-bpf".to_string(),
+            },
+            Action::write_file("/x/chmod-bpf/script", b"echo"),
+            Action::AddUserToGroup {
+                user: "alice".to_string(),
+                group: "bpf".to_string(),
+            },
+        ];
+        let mut transaction = Transaction::new(&backend);
+        for action in &actions {
+            transaction.apply(action).unwrap();
+        }
+
+        let report = transaction.rollback();
+        let rolled_back: Vec<_> = report
+            .entries
+            .iter()
+            .map(|(action, _)| action.clone())
+            .collect();
+        assert_eq!(
+            rolled_back,
+            actions.iter().rev().cloned().collect::<Vec<_>>()
+        );
+        // Membership in a group created by this run goes away with the group.
+        assert_eq!(report.undone(), 3);
+        assert!(report.remaining().is_empty());
+        assert_eq!(report.to_string(), "rolled back 3 step(s)");
+        assert_eq!(*backend.state.borrow(), before);
+    }
+
+    #[test]
+    fn rollback_reports_failed_and_irreversible_steps() {
+        let backend = MemoryBackend {
+            fail_at: Some(Step::DeleteGroup),
+            ..MemoryBackend::root("alice")
+        };
+        backend
+            .state
+            .borrow_mut()
+            .files
+            .insert("/etc/old".to_string(), b"old".to_vec());
+        let remove = Action::RemoveFile {
+            path: "/etc/old".to_string(),
+        };
+        let mut transaction = Transaction::new(&backend);
+        transaction.apply(&create_group("bpf")).unwrap();
+        transaction.apply(&remove).unwrap();
+
+        let report = transaction.rollback();
+        assert_eq!(
+            outcomes(&report),
+            [
+                (
+                    remove,
+                    "Irreversible(\"removed files are not restored\")".to_string()
+                ),
+                (
+                    create_group("bpf"),
+                    "Failed(\"Injected failure at DeleteGroup\")".to_string()
+                ),
+            ]
+        );
+        assert_eq!(report.undone(), 0);
+        assert_eq!(report.remaining().len(), 2);
+        let message = report.to_string();
+        assert!(
+            message.contains("2 step(s) could not be undone"),
+            "{message}"
+        );
+        assert!(
+            message.contains("removed files are not restored"),
+            "{message}"
+        );
+        assert!(
+            message.contains("Injected failure at DeleteGroup"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn only_paths_inside_created_directories_count_as_created() {
+        let backend = MemoryBackend::root("alice");
+        {
+            let mut state = backend.state.borrow_mut();
+            state.dirs.insert("/x".to_string());
+            state
+                .files
+                .insert("/x/chmod-bpf-other".to_string(), Vec::new());
+        }
+        let mut transaction = Transaction::new(&backend);
+        transaction
+            .apply(&Action::CreateDir {
+                path: "/x/chmod-bpf".to_string(),
+            })
+            .unwrap();
+        transaction
+            .apply(&Action::write_file("/x/chmod-bpf/script", b"echo"))
+            .unwrap();
+        transaction.apply(&set_mode("/x/chmod-bpf/script")).unwrap();
+        transaction.apply(&set_mode("/x/chmod-bpf-other")).unwrap();
+
+        let report = transaction.rollback();
+        let outcomes = outcomes(&report);
+        assert_eq!(
+            outcomes[0],
+            (
+                set_mode("/x/chmod-bpf-other"),
+                "Irreversible(\"the previous ownership and mode were not recorded\")".to_string()
+            )
+        );
+        assert_eq!(
+            outcomes[1],
+            (set_mode("/x/chmod-bpf/script"), "NotNeeded".to_string())
+        );
+    }
+}
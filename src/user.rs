@@ -1,18 +1,81 @@
 use anyhow::{Context, Result, anyhow};
+use serde::Serialize;
+use std::collections::BTreeSet;
 use std::env;
+use std::fmt;
 use std::process::Command;
-use std::str;
-use uzers::{User, get_current_username, get_effective_uid, get_group_by_name, get_user_by_name};
+use std::str::{self, FromStr};
+use uzers::{
+    User, get_current_username, get_effective_gid, get_effective_uid, get_group_by_gid,
+    get_group_by_name, get_user_by_name, get_user_by_uid,
+};
 
-use crate::command;
+use crate::{access, command};
 
 pub const MIN_GID: u32 = 100;
 
+/// Whether the operator can use the group's permissions right now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupMembership {
+    /// Member in the directory and in the current login session.
+    Active,
+    /// Member in the directory, but the login session predates it.
+    NotActiveInSession,
+    /// Member in the directory; under `sudo` the operator's login session
+    /// cannot be inspected.
+    SessionUnknown,
+    /// Not a member of the group.
+    Absent,
+    /// The group does not exist.
+    GroupMissing,
+}
+
+impl GroupMembership {
+    /// Combines directory membership with the session's supplementary
+    /// groups, when they are known.
+    pub fn from_states(configured: bool, in_session: Option<bool>) -> Self {
+        match (configured, in_session) {
+            (_, Some(true)) => GroupMembership::Active,
+            (true, Some(false)) => GroupMembership::NotActiveInSession,
+            (true, None) => GroupMembership::SessionUnknown,
+            (false, _) => GroupMembership::Absent,
+        }
+    }
+}
+
+/// Direct members of a group: user accounts and nested groups.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct GroupMembers {
+    pub users: Vec<String>,
+    pub groups: Vec<String>,
+}
+
+/// Check if the user account exists.
+pub fn user_exists(user_name: &str) -> bool {
+    get_user_by_name(user_name).is_some()
+}
+
 /// Check if the group exists.
 pub fn group_exists(group_name: &str) -> bool {
     get_group_by_name(group_name).is_some()
 }
 
+/// Get the gid of the group, if it exists.
+pub fn get_group_gid(group_name: &str) -> Option<u32> {
+    get_group_by_name(group_name).map(|group| group.gid())
+}
+
+/// Get the name of the group with the given gid, if it exists.
+pub fn get_group_name(gid: u32) -> Option<String> {
+    get_group_by_gid(gid).map(|group| group.name().to_string_lossy().into_owned())
+}
+
+/// Get the name of the user with the given uid, if it exists.
+pub fn get_user_name(uid: u32) -> Option<String> {
+    get_user_by_uid(uid).map(|user| user.name().to_string_lossy().into_owned())
+}
+
 pub fn get_original_user() -> Option<String> {
     env::var("SUDO_USER").ok()
 }
@@ -56,8 +119,71 @@ pub fn create_group(group_name: &str, group_real_name: &str, gid: u32) -> Result
     )
 }
 
-/// Get the first available GID starting from min_gid.
-pub fn get_free_gid(min_gid: u32) -> Result<u32> {
+/// Inclusive range of gids a new group may be created with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GidRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl FromStr for GidRange {
+    type Err = String;
+
+    /// Parses `a..b`, both ends included.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (start, end) = value
+            .split_once("..")
+            .ok_or_else(|| format!("expected START..END, got {value:?}"))?;
+        if end.starts_with('=') {
+            return Err(format!(
+                "expected START..END, got {value:?}; both ends are already included"
+            ));
+        }
+        let parse = |bound: &str| {
+            bound
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| format!("{bound:?} is not a valid gid"))
+        };
+        let range = Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        };
+        if range.start == 0 {
+            return Err("the gid range must not include 0".to_string());
+        }
+        if range.start > range.end {
+            return Err(format!("the gid range {value} is empty"));
+        }
+        Ok(range)
+    }
+}
+
+impl fmt::Display for GidRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+/// Gids from the output of `dscl . -list /Groups PrimaryGroupID`, one
+/// `<name> <gid>` pair per line. Negative gids such as `nobody`'s -2 and
+/// malformed lines are skipped.
+pub fn parse_group_gids(output: &str) -> Vec<u32> {
+    output
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .filter_map(|gid| gid.parse().ok())
+        .collect()
+}
+
+/// Lowest gid in `range` that no group uses.
+pub fn allocate_gid(used: &[u32], range: GidRange) -> Option<u32> {
+    let used: BTreeSet<u32> = used.iter().copied().collect();
+    (range.start..=range.end).find(|gid| !used.contains(gid))
+}
+
+/// Gids of every group in the local directory.
+pub fn list_group_gids() -> Result<Vec<u32>> {
     let output = Command::new("dscl")
         .arg(".")
         .arg("-list")
@@ -68,43 +194,30 @@ pub fn get_free_gid(min_gid: u32) -> Result<u32> {
 
     if !output.status.success() {
         return Err(anyhow!(
-            "dscl exited with status {} while searching for a free gid",
+            "dscl exited with status {} while listing group IDs",
             output.status
         ));
     }
 
     let output_str = str::from_utf8(&output.stdout).context("dscl output was not UTF-8")?;
-    let mut gids: Vec<u32> = output_str
-        .lines()
-        .filter_map(|line| line.split_whitespace().nth(1))
-        .filter_map(|gid| gid.parse::<u32>().ok())
-        .collect::<Vec<u32>>();
-    gids.sort_unstable();
-
-    let mut current_gid = min_gid;
-    for gid in gids {
-        if gid != current_gid && gid >= min_gid {
-            break;
-        }
-        current_gid += 1;
-    }
-
-    Ok(current_gid)
+    Ok(parse_group_gids(output_str))
 }
 
-/// Adds the current user to the specified group.
-pub fn add_current_user_to_group(group_name: &str) -> Result<()> {
-    let user =
-        get_real_current_user().ok_or_else(|| anyhow!("Unable to resolve the current user"))?;
-    let username = user.name().to_string_lossy().into_owned();
+/// Get the first available GID in `range`.
+pub fn get_free_gid(range: GidRange) -> Result<u32> {
+    allocate_gid(&list_group_gids()?, range)
+        .ok_or_else(|| anyhow!("No free gid in the range {range}"))
+}
 
+/// Adds the specified user to the specified group.
+pub fn add_user_to_group(username: &str, group_name: &str) -> Result<()> {
     let mut command = Command::new("dseditgroup");
     command
         .arg("-q")
         .arg("-o")
         .arg("edit")
         .arg("-a")
-        .arg(&username)
+        .arg(username)
         .arg("-t")
         .arg("user")
         .arg(group_name);
@@ -114,20 +227,93 @@ pub fn add_current_user_to_group(group_name: &str) -> Result<()> {
     )
 }
 
-/// Check if the current user is in the specified group.
-pub fn current_user_in_group(group_name: &str) -> bool {
-    if let Some(user_name) = get_current_username() {
-        if let Some(user) = get_user_by_name(&user_name) {
-            if let Some(groups) = user.groups() {
-                for group in groups {
-                    if group.name() == group_name {
-                        return true;
-                    }
-                }
+/// Check if the specified user is in the specified group.
+pub fn user_in_group(user_name: &str, group_name: &str) -> bool {
+    get_user_by_name(user_name)
+        .and_then(|user| user.groups())
+        .is_some_and(|groups| groups.iter().any(|group| group.name() == group_name))
+}
+
+/// Check if the specified group is nested in the target group.
+pub fn group_in_group(group: &str, target_group: &str) -> Result<bool> {
+    let group_uid = read_group_attribute(group, "GeneratedUID")?;
+    let nested = read_group_attribute(target_group, "NestedGroups")?;
+    Ok(group_uid
+        .first()
+        .is_some_and(|uid| nested.iter().any(|nested_uid| nested_uid == uid)))
+}
+
+/// Reads the users listed in the group record and resolves its nested
+/// groups, which dscl stores by GeneratedUID, to their names.
+pub fn group_members(group_name: &str) -> Result<GroupMembers> {
+    let mut users = read_group_attribute(group_name, "GroupMembership")?;
+    users.sort();
+    users.dedup();
+    let nested = read_group_attribute(group_name, "NestedGroups")?;
+    let mut groups = Vec::new();
+    if !nested.is_empty() {
+        let output = Command::new("dscl")
+            .arg(".")
+            .arg("-list")
+            .arg("/Groups")
+            .arg("GeneratedUID")
+            .output()
+            .context("Failed to list group GeneratedUIDs with dscl")?;
+        let listing = str::from_utf8(&output.stdout).context("dscl output was not UTF-8")?;
+        for line in listing.lines() {
+            let mut fields = line.split_whitespace();
+            if let (Some(name), Some(uid)) = (fields.next(), fields.next())
+                && nested.iter().any(|nested_uid| nested_uid == uid)
+            {
+                groups.push(name.to_string());
             }
         }
+        groups.sort();
     }
-    false
+    Ok(GroupMembers { users, groups })
+}
+
+/// Reads a multi-valued attribute of a group record from the local directory.
+fn read_group_attribute(group_name: &str, attribute: &str) -> Result<Vec<String>> {
+    let output = Command::new("dscl")
+        .arg(".")
+        .arg("-read")
+        .arg(format!("/Groups/{group_name}"))
+        .arg(attribute)
+        .output()
+        .with_context(|| format!("Failed to query dscl for {attribute} of {group_name}"))?;
+
+    // dscl exits non-zero when the attribute has no values.
+    let output_str = str::from_utf8(&output.stdout).context("dscl output was not UTF-8")?;
+    Ok(output_str
+        .split_whitespace()
+        .filter(|value| !value.ends_with(':'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Check whether the operator (`SUDO_USER` when set) belongs to the group in
+/// the directory and in the running login session.
+pub fn current_user_group_membership(group_name: &str) -> GroupMembership {
+    let Some(group) = get_group_by_name(group_name) else {
+        return GroupMembership::GroupMissing;
+    };
+    let Some(user) = get_real_current_user() else {
+        return GroupMembership::Absent;
+    };
+    let configured = user.primary_group_id() == group.gid()
+        || user
+            .groups()
+            .is_some_and(|groups| groups.iter().any(|member| member.gid() == group.gid()));
+    // Under sudo the process carries root's groups, so the operator's login
+    // session cannot be inspected.
+    let in_session = match get_original_user() {
+        Some(_) => None,
+        None => access::supplementary_groups()
+            .ok()
+            .map(|groups| groups.contains(&group.gid()) || get_effective_gid() == group.gid()),
+    };
+    GroupMembership::from_states(configured, in_session)
 }
 
 /// Adds the specified group to the specified group.
@@ -148,9 +334,148 @@ pub fn add_group_to_group(group: &str, target_group: &str) -> Result<()> {
     )
 }
 
+/// Removes the specified user from the specified group.
+pub fn remove_user_from_group(username: &str, group_name: &str) -> Result<()> {
+    let mut command = Command::new("dseditgroup");
+    command
+        .arg("-q")
+        .arg("-o")
+        .arg("edit")
+        .arg("-d")
+        .arg(username)
+        .arg("-t")
+        .arg("user")
+        .arg(group_name);
+    command::run(
+        &mut command,
+        &format!("remove user {username} from group {group_name}"),
+    )
+}
+
+/// Removes the specified group from the specified group.
+pub fn remove_group_from_group(group: &str, target_group: &str) -> Result<()> {
+    let mut command = Command::new("dseditgroup");
+    command
+        .arg("-q")
+        .arg("-o")
+        .arg("edit")
+        .arg("-d")
+        .arg(group)
+        .arg("-t")
+        .arg("group")
+        .arg(target_group);
+    command::run(
+        &mut command,
+        &format!("remove group {group} from group {target_group}"),
+    )
+}
+
 /// Deletes the specified group.
 pub fn delete_group(group_name: &str) -> Result<()> {
     let mut command = Command::new("dseditgroup");
     command.arg("-o").arg("delete").arg(group_name);
     command::run(&mut command, &format!("delete group {group_name}"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DSCL_OUTPUT: &str = "\
+_amavisd                                 83
+_appowner                                87
+admin                                    80
+nobody                                   -2
+nogroup                                  -1
+staff                                    20
+wheel                                    0
+access_bpf                               501
+com.apple.access_ssh                     399
+broken
+also broken                              x
+access_bpf_dup                           502
+";
+
+    #[test]
+    fn membership_combines_directory_and_session() {
+        let cases = [
+            // configured, in session, expected
+            (true, Some(true), GroupMembership::Active),
+            (true, Some(false), GroupMembership::NotActiveInSession),
+            (true, None, GroupMembership::SessionUnknown),
+            // e.g. the effective gid, without a directory record
+            (false, Some(true), GroupMembership::Active),
+            (false, Some(false), GroupMembership::Absent),
+            (false, None, GroupMembership::Absent),
+        ];
+        for (configured, in_session, expected) in cases {
+            assert_eq!(
+                GroupMembership::from_states(configured, in_session),
+                expected,
+                "{configured} {in_session:?}"
+            );
+        }
+        assert_eq!(
+            serde_json::to_string(&GroupMembership::SessionUnknown).unwrap(),
+            "\"session_unknown\""
+        );
+    }
+
+    fn range(start: u32, end: u32) -> GidRange {
+        GidRange { start, end }
+    }
+
+    #[test]
+    fn parse_group_gids_skips_negative_and_malformed_lines() {
+        assert_eq!(
+            parse_group_gids(DSCL_OUTPUT),
+            [83, 87, 80, 20, 0, 501, 399, 502]
+        );
+        assert_eq!(parse_group_gids(""), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn allocate_gid_finds_the_lowest_free_gid() {
+        let used = parse_group_gids(DSCL_OUTPUT);
+        let cases = [
+            // used gids, range, expected
+            (&used[..], range(501, 600), Some(503)),
+            // gids below the range do not matter
+            (&used[..], range(80, 90), Some(81)),
+            (&[][..], range(600, 699), Some(600)),
+            // the first gap is taken
+            (&[600, 601, 603, 605][..], range(600, 699), Some(602)),
+            (&[10, 20, 30][..], range(600, 699), Some(600)),
+            (&[699][..], range(699, 699), None),
+            (&[600, 601, 602][..], range(600, 602), None),
+            (&[600, 601, 602][..], range(600, 603), Some(603)),
+        ];
+        for (used, range, expected) in cases {
+            assert_eq!(allocate_gid(used, range), expected, "{used:?} in {range}");
+        }
+    }
+
+    #[test]
+    fn gid_range_parses_inclusive_ranges() {
+        assert_eq!("600..699".parse::<GidRange>(), Ok(range(600, 699)));
+        assert_eq!(" 700 .. 700 ".parse::<GidRange>(), Ok(range(700, 700)));
+        assert_eq!(range(600, 699).to_string(), "600..699");
+    }
+
+    #[test]
+    fn gid_range_rejects_invalid_ranges() {
+        let cases = [
+            ("0..5", "must not include 0"),
+            ("9..3", "is empty"),
+            ("600..=699", "both ends are already included"),
+            ("600", "expected START..END"),
+            ("a..9", "is not a valid gid"),
+            ("-1..9", "is not a valid gid"),
+            ("1..", "is not a valid gid"),
+        ];
+        for (value, message) in cases {
+            let error = value.parse::<GidRange>().unwrap_err();
+            assert!(error.contains(message), "{value}: {error}");
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use anyhow::{Context, Result};
+use chrono::Local;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::{backend::SystemBackend, bpf::DeviceVerdict, output::OutputFormat, permission};
+
+/// Directory holding the BPF device nodes.
+pub const DEVICE_DIR: &str = "/dev";
+
+/// Waits for the device directory to change.
+pub trait DeviceWatcher {
+    /// Blocks until something in the watched directory may have changed, or
+    /// until the re-check interval elapses.
+    fn wait(&mut self) -> Result<()>;
+    /// Short name of the mechanism, for logs.
+    fn name(&self) -> &'static str;
+}
+
+/// Re-checks on a fixed interval. Works everywhere, including devfs on macOS,
+/// which reports no attribute changes.
+pub struct PollingWatcher {
+    interval: Duration,
+}
+
+impl PollingWatcher {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval }
+    }
+}
+
+impl DeviceWatcher for PollingWatcher {
+    fn wait(&mut self) -> Result<()> {
+        std::thread::sleep(self.interval);
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "polling"
+    }
+}
+
+/// Wakes up on inotify events for the directory, and at least once per
+/// interval so nothing is missed if the event queue overflows.
+#[cfg(target_os = "linux")]
+pub struct InotifyWatcher {
+    fd: libc::c_int,
+    interval: Duration,
+}
+
+#[cfg(target_os = "linux")]
+impl InotifyWatcher {
+    pub fn new(dir: &Path, interval: Duration) -> Result<Self> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let c_dir = std::ffi::CString::new(dir.as_os_str().as_bytes())
+            .with_context(|| format!("{} contains a NUL byte", dir.display()))?;
+        // SAFETY: plain syscall without pointers.
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            anyhow::bail!("inotify_init1 failed: {}", std::io::Error::last_os_error());
+        }
+        let mask = libc::IN_ATTRIB
+            | libc::IN_CREATE
+            | libc::IN_DELETE
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO;
+        // SAFETY: the path is NUL-terminated and the descriptor is ours.
+        if unsafe { libc::inotify_add_watch(fd, c_dir.as_ptr(), mask) } < 0 {
+            let error = std::io::Error::last_os_error();
+            // SAFETY: the descriptor is ours and not used afterwards.
+            unsafe { libc::close(fd) };
+            anyhow::bail!("Failed to watch {}: {error}", dir.display());
+        }
+        Ok(Self { fd, interval })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl DeviceWatcher for InotifyWatcher {
+    fn wait(&mut self) -> Result<()> {
+        let mut poll_fd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = self.interval.as_millis().min(i32::MAX as u128) as libc::c_int;
+        // SAFETY: one valid pollfd is passed.
+        if unsafe { libc::poll(&mut poll_fd, 1, timeout) } < 0 {
+            let error = std::io::Error::last_os_error();
+            if error.kind() != std::io::ErrorKind::Interrupted {
+                anyhow::bail!("poll on inotify failed: {error}");
+            }
+        }
+        // Only the wake-up matters, so the queued events are drained unread.
+        let mut buffer = [0u8; 4096];
+        // SAFETY: the buffer length is passed and the descriptor is non-blocking.
+        while unsafe { libc::read(self.fd, buffer.as_mut_ptr().cast(), buffer.len()) } > 0 {}
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "inotify"
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for InotifyWatcher {
+    fn drop(&mut self) {
+        // SAFETY: the descriptor is owned by this watcher.
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Picks inotify on Linux and falls back to polling elsewhere or when
+/// inotify is unavailable.
+pub fn device_watcher(dir: &Path, interval: Duration) -> Box<dyn DeviceWatcher> {
+    #[cfg(target_os = "linux")]
+    match InotifyWatcher::new(dir, interval) {
+        Ok(watcher) => return Box::new(watcher),
+        Err(error) => warn!(?error, "inotify is unavailable; falling back to polling"),
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = dir;
+    Box::new(PollingWatcher::new(interval))
+}
+
+/// Group and mode of one device, and whether they are what `apply` sets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceState {
+    pub group: String,
+    pub mode: String,
+    /// `WrongGroup` or `WrongMode` when the device drifted, `None` when it
+    /// conforms.
+    pub problem: Option<DeviceVerdict>,
+}
+
+/// Whether a device drifted, was brought back in line, or disappeared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Drifted,
+    Restored,
+    Removed,
+}
+
+/// A device changing from or back to the expected group and mode. For a
+/// removed device, the group and mode are the last ones seen.
+#[derive(Debug, Clone, Serialize)]
+pub struct DriftEvent {
+    pub event: EventKind,
+    /// RFC 3339 time the change was seen.
+    pub time: String,
+    pub path: String,
+    pub group: String,
+    pub mode: String,
+    pub expected_group: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub problem: Option<DeviceVerdict>,
+}
+
+impl DriftEvent {
+    pub fn render_text(&self) -> String {
+        match self.event {
+            EventKind::Drifted => format!(
+                "{} drifted: {} (group {}, mode {}; expected group {} with read/write)",
+                self.path,
+                self.problem
+                    .map_or("changed".to_string(), |problem| problem.to_string()),
+                self.group,
+                self.mode,
+                self.expected_group
+            ),
+            EventKind::Restored => format!(
+                "{} restored: group {}, mode {}",
+                self.path, self.group, self.mode
+            ),
+            EventKind::Removed => format!(
+                "{} removed (last seen with group {}, mode {})",
+                self.path, self.group, self.mode
+            ),
+        }
+    }
+}
+
+/// Reads the group and mode of every BPF device and compares them with the
+/// expected group and `g+rw`.
+pub fn snapshot(backend: &dyn SystemBackend, group: &str) -> Result<BTreeMap<String, DeviceState>> {
+    let gid = backend.group_gid(group);
+    let mut devices = BTreeMap::new();
+    for path in backend.bpf_device_paths()? {
+        // A device can disappear between listing and inspection.
+        let Ok(metadata) = backend.file_metadata(&path) else {
+            continue;
+        };
+        let problem = if gid != Some(metadata.gid) {
+            Some(DeviceVerdict::WrongGroup)
+        } else if metadata.mode & 0o060 != 0o060 {
+            Some(DeviceVerdict::WrongMode)
+        } else {
+            None
+        };
+        devices.insert(
+            path.to_string_lossy().into_owned(),
+            DeviceState {
+                group: backend
+                    .group_name_by_gid(metadata.gid)
+                    .unwrap_or_else(|| metadata.gid.to_string()),
+                mode: permission::mode_string(metadata.mode),
+                problem,
+            },
+        );
+    }
+    Ok(devices)
+}
+
+/// Events for devices whose conformance changed between two snapshots, and
+/// for devices that disappeared. New devices are compared with a conforming
+/// state, so a device that appears with the wrong group is reported once. A
+/// device going from one problem to another, e.g. from the wrong group to
+/// the wrong mode, is reported as drifting again.
+pub fn diff_snapshots(
+    previous: &BTreeMap<String, DeviceState>,
+    current: &BTreeMap<String, DeviceState>,
+    expected_group: &str,
+) -> Vec<DriftEvent> {
+    let time = Local::now().to_rfc3339();
+    let event = |event, path: &String, state: &DeviceState| DriftEvent {
+        event,
+        time: time.clone(),
+        path: path.clone(),
+        group: state.group.clone(),
+        mode: state.mode.clone(),
+        expected_group: expected_group.to_string(),
+        problem: state.problem,
+    };
+    let changed = current.iter().filter_map(|(path, state)| {
+        let was = previous.get(path).and_then(|previous| previous.problem);
+        if was == state.problem {
+            return None;
+        }
+        let kind = match state.problem {
+            Some(_) => EventKind::Drifted,
+            None => EventKind::Restored,
+        };
+        Some(event(kind, path, state))
+    });
+    let removed = previous
+        .iter()
+        .filter(|(path, _)| !current.contains_key(*path))
+        .map(|(path, state)| event(EventKind::Removed, path, state));
+    changed.chain(removed).collect()
+}
+
+/// Re-evaluates the devices each time the watcher wakes up and reports every
+/// device that drifts from, or returns to, the expected group and mode. Runs
+/// until interrupted.
+pub fn watch_devices(
+    backend: &dyn SystemBackend,
+    watcher: &mut dyn DeviceWatcher,
+    group: &str,
+    format: OutputFormat,
+) -> Result<()> {
+    info!(
+        "Watching {DEVICE_DIR} for BPF permission changes ({})",
+        watcher.name()
+    );
+    let mut previous = BTreeMap::new();
+    loop {
+        let current = snapshot(backend, group)?;
+        for event in diff_snapshots(&previous, &current, group) {
+            emit(&event, format)?;
+        }
+        previous = current;
+        watcher.wait()?;
+    }
+}
+
+fn emit(event: &DriftEvent, format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Text => match event.event {
+            EventKind::Drifted => warn!("{}", event.render_text()),
+            EventKind::Restored | EventKind::Removed => info!("{}", event.render_text()),
+        },
+        // One object per line so the stream can be piped to a log shipper.
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string(event).context("Failed to serialize the drift event")?
+        ),
+        OutputFormat::Yaml => print!(
+            "---\n{}",
+            serde_yaml::to_string(event).context("Failed to serialize the drift event")?
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(group: &str, mode: &str, problem: Option<DeviceVerdict>) -> DeviceState {
+        DeviceState {
+            group: group.to_string(),
+            mode: mode.to_string(),
+            problem,
+        }
+    }
+
+    fn snapshot_of(devices: &[(&str, DeviceState)]) -> BTreeMap<String, DeviceState> {
+        devices
+            .iter()
+            .map(|(path, state)| (path.to_string(), state.clone()))
+            .collect()
+    }
+
+    fn events(
+        previous: &BTreeMap<String, DeviceState>,
+        current: &BTreeMap<String, DeviceState>,
+    ) -> Vec<(EventKind, String, Option<DeviceVerdict>)> {
+        diff_snapshots(previous, current, "access_bpf")
+            .into_iter()
+            .map(|event| (event.event, event.path, event.problem))
+            .collect()
+    }
+
+    #[test]
+    fn diff_snapshots_reports_each_change() {
+        let ok = state("access_bpf", "crw-rw----", None);
+        let wrong_group = state("wheel", "crw-rw----", Some(DeviceVerdict::WrongGroup));
+        let wrong_mode = state("access_bpf", "crw-------", Some(DeviceVerdict::WrongMode));
+
+        let previous = snapshot_of(&[
+            ("/dev/bpf0", ok.clone()),
+            ("/dev/bpf1", ok.clone()),
+            ("/dev/bpf2", wrong_group.clone()),
+            ("/dev/bpf3", wrong_group.clone()),
+            ("/dev/bpf4", wrong_mode.clone()),
+            ("/dev/bpf5", ok.clone()),
+        ]);
+        let current = snapshot_of(&[
+            ("/dev/bpf0", ok.clone()),
+            ("/dev/bpf1", wrong_mode.clone()),
+            ("/dev/bpf2", wrong_mode.clone()),
+            ("/dev/bpf3", ok.clone()),
+            ("/dev/bpf4", wrong_mode.clone()),
+            ("/dev/bpf6", wrong_group.clone()),
+            ("/dev/bpf7", ok.clone()),
+        ]);
+        assert_eq!(
+            events(&previous, &current),
+            [
+                (
+                    EventKind::Drifted,
+                    "/dev/bpf1".to_string(),
+                    Some(DeviceVerdict::WrongMode)
+                ),
+                (
+                    EventKind::Drifted,
+                    "/dev/bpf2".to_string(),
+                    Some(DeviceVerdict::WrongMode)
+                ),
+                (EventKind::Restored, "/dev/bpf3".to_string(), None),
+                (
+                    EventKind::Drifted,
+                    "/dev/bpf6".to_string(),
+                    Some(DeviceVerdict::WrongGroup)
+                ),
+                (EventKind::Removed, "/dev/bpf5".to_string(), None),
+            ]
+        );
+        assert!(events(&current, &current).is_empty());
+    }
+
+    #[test]
+    fn diff_snapshots_reports_wrong_devices_on_the_first_pass() {
+        let current = snapshot_of(&[
+            ("/dev/bpf0", state("access_bpf", "crw-rw----", None)),
+            (
+                "/dev/bpf1",
+                state("wheel", "crw-------", Some(DeviceVerdict::WrongGroup)),
+            ),
+        ]);
+        assert_eq!(
+            events(&BTreeMap::new(), &current),
+            [(
+                EventKind::Drifted,
+                "/dev/bpf1".to_string(),
+                Some(DeviceVerdict::WrongGroup)
+            )]
+        );
+    }
+
+    #[test]
+    fn removed_event_keeps_the_last_seen_state() {
+        let previous = snapshot_of(&[(
+            "/dev/bpf9",
+            state("wheel", "crw-------", Some(DeviceVerdict::WrongGroup)),
+        )]);
+        let events = diff_snapshots(&previous, &BTreeMap::new(), "access_bpf");
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].render_text(),
+            "/dev/bpf9 removed (last seen with group wheel, mode crw-------)"
+        );
+    }
+}